@@ -0,0 +1,18 @@
+//! Generates the gRPC service from `proto/rt_ping.proto` when the `grpc`
+//! feature is on.  Needs `protoc` on `PATH` or in `PROTOC`.
+
+fn main() {
+    println!("cargo:rerun-if-changed=proto/rt_ping.proto");
+    #[cfg(feature = "grpc")]
+    {
+        let found = std::env::var_os("PROTOC").is_some()
+            || std::process::Command::new("protoc")
+                .arg("--version")
+                .output()
+                .is_ok();
+        if !found {
+            panic!("the grpc feature needs protoc: install it or set PROTOC=/path/to/protoc");
+        }
+        tonic_build::compile_protos("proto/rt_ping.proto").expect("compiling proto/rt_ping.proto");
+    }
+}
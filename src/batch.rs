@@ -0,0 +1,622 @@
+//! Many hashes, one beacon round.
+//!
+//! The hashes of a batch become the leaves of a Merkle tree (the IETF
+//! Roughtime tree, SHA-512 truncated to 32 bytes); the root is stamped
+//! once and each submitter gets an `Inclusion` from its hash to that root.
+//! A batch proof is therefore the inclusion path plus the root's
+//! `proof::Proof`.
+//!
+//! `Batcher` does this for a service: submitters from any thread hand it
+//! hashes and get a `Ticket`; a batch is sealed at `max_items` or when
+//! its oldest hash has waited `max_wait`, stamped with one beacon round,
+//! and every ticket completes with its own inclusion.  The queue is
+//! bounded, so a slow beacon round pushes back on submitters.
+
+use crate::{client::TimestampClient, wire::Version, TimestampError, TimestampResponse};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Path from one hash to the batch root.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Inclusion {
+    pub index: u32,
+    /// Concatenated sibling hashes, leaf level first (hex).
+    pub path: String,
+    /// The stamped root (hex).
+    pub root: String,
+}
+
+impl Inclusion {
+    /// Whether `hash` is leaf `index` of the tree with this root.
+    pub fn verify(&self, hash: &[u8]) -> bool {
+        match (hex::decode(&self.path), hex::decode(&self.root)) {
+            (Ok(path), Ok(root)) => {
+                Version::Ietf
+                    .merkle()
+                    .root_from_paths(self.index as usize, hash, &path)
+                    == root
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Merkle root over `hashes` (non-empty) and every leaf's inclusion.
+pub fn merkle(hashes: &[[u8; 32]]) -> ([u8; 32], Vec<Inclusion>) {
+    let mut tree = Version::Ietf.merkle();
+    for h in hashes {
+        tree.push_leaf(h);
+    }
+    let root = tree.compute_root();
+    let root_hex = hex::encode(&root);
+    let items = (0..hashes.len())
+        .map(|i| Inclusion {
+            index: i as u32,
+            path: hex::encode(tree.get_paths(i)),
+            root: root_hex.clone(),
+        })
+        .collect();
+    let mut r = [0u8; 32];
+    r.copy_from_slice(&root[..32]);
+    (r, items)
+}
+
+/// Stamp every hash with one round: the root's response, and one
+/// inclusion per hash, in order.
+pub fn stamp_batch(
+    client: &TimestampClient,
+    hashes: &[[u8; 32]],
+) -> Result<(TimestampResponse, Vec<Inclusion>), TimestampError> {
+    if hashes.is_empty() {
+        return Err(TimestampError::Config("empty batch".into()));
+    }
+    let (root, items) = merkle(hashes);
+    Ok((client.timestamp(root)?, items))
+}
+
+// -------------------------------------------------------------------------
+// Batcher
+
+/// Per-batch reports kept for `Batcher::metrics`.
+const RECENT: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct BatchConfig {
+    /// Seal a batch at this many hashes…
+    pub max_items: usize,
+    /// …or this long after its oldest hash (submitters may ask for less).
+    pub max_wait: Duration,
+    /// Hashes waiting behind the batch being filled; `submit` blocks and
+    /// `try_submit` fails beyond this.
+    pub queue: usize,
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self {
+            max_items: 256,
+            max_wait: Duration::from_millis(50),
+            queue: 4096,
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum BatchError {
+    #[error("batch queue full ({0} hashes waiting)")]
+    Full(usize),
+    #[error("batcher stopped")]
+    Closed,
+    #[error("no stamp within {0:?}")]
+    Timeout(Duration),
+    #[error("batch failed: {0}")]
+    Failed(Arc<TimestampError>),
+}
+
+/// One submitter's result: the shared stamp over the root and its own
+/// path to it.
+#[derive(Debug, Clone)]
+pub struct Stamped {
+    pub response: Arc<TimestampResponse>,
+    pub inclusion: Inclusion,
+}
+
+/// Why a batch was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub enum Sealed {
+    Full,
+    Deadline,
+    /// The batcher was dropped with hashes still queued.
+    Shutdown,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BatchReport {
+    pub size: usize,
+    pub sealed: Sealed,
+    /// Oldest hash's time in the queue before the seal.
+    pub max_wait: Duration,
+    pub mean_wait: Duration,
+    /// The beacon round itself.
+    pub stamp_time: Duration,
+    pub ok: bool,
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct BatchMetrics {
+    pub batches: u64,
+    pub failed: u64,
+    pub items: u64,
+    /// `try_submit` calls turned away by a full queue.
+    pub rejected: u64,
+    /// Waiting right now.
+    pub queued: usize,
+    pub max_size: usize,
+    pub max_wait: Duration,
+    /// Newest last.
+    pub recent: Vec<BatchReport>,
+}
+
+type Reply = Box<dyn FnOnce(Result<Stamped, BatchError>) + Send>;
+
+struct Job {
+    hash: [u8; 32],
+    queued_at: Instant,
+    deadline: Instant,
+    reply: Reply,
+}
+
+#[derive(Default)]
+struct Stats {
+    metrics: BatchMetrics,
+    recent: VecDeque<BatchReport>,
+}
+
+/// Pending result of a submission.
+pub struct Ticket(mpsc::Receiver<Result<Stamped, BatchError>>);
+
+impl Ticket {
+    pub fn wait(self) -> Result<Stamped, BatchError> {
+        self.0.recv().unwrap_or(Err(BatchError::Closed))
+    }
+
+    pub fn wait_timeout(self, timeout: Duration) -> Result<Stamped, BatchError> {
+        match self.0.recv_timeout(timeout) {
+            Ok(r) => r,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(BatchError::Timeout(timeout)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(BatchError::Closed),
+        }
+    }
+}
+
+/// Collects hashes from any number of threads and stamps them a batch at
+/// a time: one beacon round per batch, never more than one in flight.
+/// Dropping it stamps what is still queued, then stops.
+pub struct Batcher {
+    tx: Option<mpsc::SyncSender<Job>>,
+    cfg: BatchConfig,
+    queued: Arc<AtomicUsize>,
+    stats: Arc<Mutex<Stats>>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl Batcher {
+    pub fn new(client: TimestampClient, cfg: BatchConfig) -> Self {
+        let (tx, rx) = mpsc::sync_channel(cfg.queue.max(1));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let stats = Arc::new(Mutex::new(Stats::default()));
+        let worker = {
+            let (max_items, queued, stats) = (cfg.max_items.max(1), queued.clone(), stats.clone());
+            thread::spawn(move || run(&client, rx, max_items, &queued, &stats))
+        };
+        Self {
+            tx: Some(tx),
+            cfg,
+            queued,
+            stats,
+            worker: Some(worker),
+        }
+    }
+
+    pub fn config(&self) -> &BatchConfig {
+        &self.cfg
+    }
+
+    /// Queue `hash`, blocking while the queue is full.
+    pub fn submit(&self, hash: [u8; 32]) -> Result<Ticket, BatchError> {
+        let (tx, rx) = mpsc::channel();
+        self.submit_then(hash, self.cfg.max_wait, move |r| {
+            let _ = tx.send(r);
+        })?;
+        Ok(Ticket(rx))
+    }
+
+    /// Queue `hash` or fail at once with `Full`.
+    pub fn try_submit(&self, hash: [u8; 32]) -> Result<Ticket, BatchError> {
+        let (tx, rx) = mpsc::channel();
+        let job = self.job(hash, self.cfg.max_wait, move |r| {
+            let _ = tx.send(r);
+        });
+        let sender = self.tx.as_ref().ok_or(BatchError::Closed)?;
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match sender.try_send(job) {
+            Ok(()) => Ok(Ticket(rx)),
+            Err(e) => {
+                let waiting = self.queued.fetch_sub(1, Ordering::SeqCst) - 1;
+                match e {
+                    mpsc::TrySendError::Full(_) => {
+                        self.stats.lock().unwrap().metrics.rejected += 1;
+                        Err(BatchError::Full(waiting))
+                    }
+                    mpsc::TrySendError::Disconnected(_) => Err(BatchError::Closed),
+                }
+            }
+        }
+    }
+
+    /// Queue `hash`, blocking while the queue is full; its batch closes
+    /// within `max_wait` (capped by the configured one) and `reply` runs
+    /// on the batcher's thread with the result.
+    pub fn submit_then(
+        &self,
+        hash: [u8; 32],
+        max_wait: Duration,
+        reply: impl FnOnce(Result<Stamped, BatchError>) + Send + 'static,
+    ) -> Result<(), BatchError> {
+        let job = self.job(hash, max_wait, reply);
+        let sender = self.tx.as_ref().ok_or(BatchError::Closed)?;
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        sender.send(job).map_err(|_| {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            BatchError::Closed
+        })
+    }
+
+    pub fn metrics(&self) -> BatchMetrics {
+        let st = self.stats.lock().unwrap();
+        BatchMetrics {
+            queued: self.queued.load(Ordering::SeqCst),
+            recent: st.recent.iter().cloned().collect(),
+            ..st.metrics.clone()
+        }
+    }
+
+    fn job(
+        &self,
+        hash: [u8; 32],
+        max_wait: Duration,
+        reply: impl FnOnce(Result<Stamped, BatchError>) + Send + 'static,
+    ) -> Job {
+        let now = Instant::now();
+        Job {
+            hash,
+            queued_at: now,
+            deadline: now + max_wait.min(self.cfg.max_wait),
+            reply: Box::new(reply),
+        }
+    }
+}
+
+impl Drop for Batcher {
+    fn drop(&mut self) {
+        self.tx = None;
+        if let Some(w) = self.worker.take() {
+            let _ = w.join();
+        }
+    }
+}
+
+fn run(
+    client: &TimestampClient,
+    rx: mpsc::Receiver<Job>,
+    max_items: usize,
+    queued: &AtomicUsize,
+    stats: &Mutex<Stats>,
+) {
+    while let Ok(first) = rx.recv() {
+        let mut close_at = first.deadline;
+        let mut jobs = vec![first];
+        let sealed = loop {
+            if jobs.len() >= max_items {
+                break Sealed::Full;
+            }
+            let left = close_at.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break Sealed::Deadline;
+            }
+            match rx.recv_timeout(left) {
+                Ok(j) => {
+                    close_at = close_at.min(j.deadline);
+                    jobs.push(j);
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => break Sealed::Deadline,
+                Err(mpsc::RecvTimeoutError::Disconnected) => break Sealed::Shutdown,
+            }
+        };
+        queued.fetch_sub(jobs.len(), Ordering::SeqCst);
+        stamp_jobs(client, jobs, sealed, stats);
+    }
+}
+
+fn stamp_jobs(client: &TimestampClient, jobs: Vec<Job>, sealed: Sealed, stats: &Mutex<Stats>) {
+    let start = Instant::now();
+    let waits: Vec<Duration> = jobs.iter().map(|j| start - j.queued_at).collect();
+    let hashes: Vec<[u8; 32]> = jobs.iter().map(|j| j.hash).collect();
+    let result = stamp_batch(client, &hashes);
+    let report = BatchReport {
+        size: jobs.len(),
+        sealed,
+        max_wait: waits.iter().copied().max().unwrap_or_default(),
+        mean_wait: waits.iter().sum::<Duration>() / jobs.len() as u32,
+        stamp_time: start.elapsed(),
+        ok: result.is_ok(),
+    };
+    {
+        let mut st = stats.lock().unwrap();
+        let m = &mut st.metrics;
+        m.batches += 1;
+        m.failed += !report.ok as u64;
+        m.items += report.size as u64;
+        m.max_size = m.max_size.max(report.size);
+        m.max_wait = m.max_wait.max(report.max_wait);
+        if st.recent.len() == RECENT {
+            st.recent.pop_front();
+        }
+        st.recent.push_back(report);
+    }
+    match result {
+        Ok((resp, items)) => {
+            let resp = Arc::new(resp);
+            for (job, inclusion) in jobs.into_iter().zip(items) {
+                (job.reply)(Ok(Stamped {
+                    response: resp.clone(),
+                    inclusion,
+                }));
+            }
+        }
+        Err(e) => {
+            let e = Arc::new(e);
+            for job in jobs {
+                (job.reply)(Err(BatchError::Failed(e.clone())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        source::{MockSource, TimeSource},
+        BeaconMeta, ProbeOptions,
+    };
+
+    /// A mock beacon that records every hash it stamps and holds each
+    /// query until released (or until the release side is dropped).
+    struct Beacon {
+        mock: MockSource,
+        asked: Mutex<Vec<[u8; 32]>>,
+        entered: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl TimeSource for Beacon {
+        fn name(&self) -> &str {
+            self.mock.name()
+        }
+
+        fn authenticated(&self) -> bool {
+            true
+        }
+
+        fn remote(&self) -> bool {
+            false
+        }
+
+        fn query(
+            &self,
+            hash: &[u8; 32],
+            opts: &ProbeOptions,
+        ) -> Result<BeaconMeta, TimestampError> {
+            self.asked.lock().unwrap().push(*hash);
+            let _ = self.entered.lock().unwrap().send(());
+            let _ = self.release.lock().unwrap().recv();
+            self.mock.query(hash, opts)
+        }
+    }
+
+    struct Setup {
+        beacon: Arc<Beacon>,
+        batcher: Batcher,
+        entered: mpsc::Receiver<()>,
+        release: mpsc::Sender<()>,
+    }
+
+    fn setup(fail: bool, cfg: BatchConfig) -> Setup {
+        let (entered_tx, entered) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let beacon = Arc::new(Beacon {
+            mock: MockSource {
+                rtt: Duration::ZERO,
+                fail,
+                ..MockSource::new("a", 1_000)
+            },
+            asked: Mutex::new(Vec::new()),
+            entered: Mutex::new(entered_tx),
+            release: Mutex::new(release_rx),
+        });
+        let client = TimestampClient::builder()
+            .source(beacon.clone())
+            .build()
+            .unwrap();
+        Setup {
+            beacon,
+            batcher: Batcher::new(client, cfg),
+            entered,
+            release,
+        }
+    }
+
+    /// As `setup`, with queries answered at once.
+    fn unheld(cfg: BatchConfig) -> (Arc<Beacon>, Batcher) {
+        let s = setup(false, cfg);
+        (s.beacon, s.batcher)
+    }
+
+    const LONG: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn a_full_batch_is_sealed_before_its_deadline() {
+        let (beacon, batcher) = unheld(BatchConfig {
+            max_items: 3,
+            max_wait: LONG,
+            ..Default::default()
+        });
+        let tickets: Vec<_> = (1..=3u8)
+            .map(|i| batcher.submit([i; 32]).unwrap())
+            .collect();
+        for t in tickets {
+            t.wait_timeout(Duration::from_secs(2)).unwrap();
+        }
+        assert_eq!(beacon.asked.lock().unwrap().len(), 1);
+        let m = batcher.metrics();
+        assert_eq!((m.batches, m.items, m.max_size, m.failed), (1, 3, 3, 0));
+        assert_eq!(m.recent[0].sealed, Sealed::Full);
+        assert!(m.recent[0].ok);
+    }
+
+    #[test]
+    fn a_batch_is_sealed_when_its_oldest_hash_has_waited_long_enough() {
+        let (beacon, batcher) = unheld(BatchConfig {
+            max_items: 100,
+            max_wait: Duration::from_millis(30),
+            ..Default::default()
+        });
+        let a = batcher.submit([1; 32]).unwrap();
+        let b = batcher.submit([2; 32]).unwrap();
+        a.wait().unwrap();
+        b.wait().unwrap();
+        let report = batcher.metrics().recent[0].clone();
+        assert_eq!((report.size, report.sealed), (2, Sealed::Deadline));
+        assert!(report.max_wait >= Duration::from_millis(30), "{report:?}");
+        assert!(report.mean_wait <= report.max_wait);
+        assert_eq!(batcher.metrics().max_wait, report.max_wait);
+
+        // a submitter in a hurry closes the batch sooner
+        let (tx, rx) = mpsc::channel();
+        let submitted = Instant::now();
+        batcher
+            .submit_then([3; 32], Duration::from_millis(1), move |r| {
+                let _ = tx.send(r);
+            })
+            .unwrap();
+        rx.recv().unwrap().unwrap();
+        assert!(submitted.elapsed() < Duration::from_millis(30));
+        assert_eq!(beacon.asked.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn every_submitter_gets_a_path_to_the_stamped_root() {
+        let (beacon, batcher) = unheld(BatchConfig {
+            max_items: 5,
+            max_wait: LONG,
+            ..Default::default()
+        });
+        let hashes: Vec<[u8; 32]> = (0..5u8).map(|i| [i * 11; 32]).collect();
+        let tickets: Vec<_> = hashes.iter().map(|h| batcher.submit(*h).unwrap()).collect();
+        let stamped: Vec<Stamped> = tickets.into_iter().map(|t| t.wait().unwrap()).collect();
+
+        let root = beacon.asked.lock().unwrap()[0];
+        for (i, (s, h)) in stamped.iter().zip(&hashes).enumerate() {
+            assert!(Arc::ptr_eq(&s.response, &stamped[0].response));
+            assert_eq!(s.inclusion.index, i as u32);
+            assert_eq!(s.inclusion.root, hex::encode(root));
+            assert_eq!(s.response.input_hash, s.inclusion.root);
+            assert!(s.inclusion.verify(h));
+            assert!(!s.inclusion.verify(&hashes[(i + 1) % 5]));
+        }
+        assert_eq!(merkle(&hashes).0, root);
+    }
+
+    #[test]
+    fn a_full_queue_turns_try_submit_away() {
+        let s = setup(
+            false,
+            BatchConfig {
+                max_items: 1,
+                max_wait: LONG,
+                queue: 1,
+            },
+        );
+        // the first batch is being stamped; one more hash fits behind it
+        let first = s.batcher.submit([1; 32]).unwrap();
+        s.entered.recv().unwrap();
+        let second = s.batcher.try_submit([2; 32]).unwrap();
+        assert!(matches!(
+            s.batcher.try_submit([3; 32]),
+            Err(BatchError::Full(1))
+        ));
+        let m = s.batcher.metrics();
+        assert_eq!((m.rejected, m.queued), (1, 1));
+
+        drop(s.release);
+        first.wait().unwrap();
+        second.wait().unwrap();
+        assert_eq!(s.batcher.metrics().items, 2);
+    }
+
+    #[test]
+    fn a_failed_round_fails_every_ticket() {
+        let s = setup(
+            true,
+            BatchConfig {
+                max_items: 2,
+                max_wait: LONG,
+                ..Default::default()
+            },
+        );
+        drop(s.release);
+        let a = s.batcher.submit([1; 32]).unwrap();
+        let b = s.batcher.submit([2; 32]).unwrap();
+        for t in [a, b] {
+            assert!(matches!(t.wait(), Err(BatchError::Failed(_))));
+        }
+        let m = s.batcher.metrics();
+        assert_eq!((m.batches, m.failed, m.items), (1, 1, 2));
+        assert!(!m.recent[0].ok);
+    }
+
+    #[test]
+    fn dropping_the_batcher_stamps_what_is_queued() {
+        let (beacon, batcher) = unheld(BatchConfig {
+            max_items: 100,
+            max_wait: LONG,
+            ..Default::default()
+        });
+        let ticket = batcher.submit([9; 32]).unwrap();
+        let stats = batcher.stats.clone();
+        drop(batcher);
+        assert!(ticket.wait().unwrap().inclusion.verify(&[9; 32]));
+        assert_eq!(beacon.asked.lock().unwrap().len(), 1);
+        assert_eq!(stats.lock().unwrap().recent[0].sealed, Sealed::Shutdown);
+    }
+
+    #[test]
+    fn an_empty_batch_is_refused() {
+        let client = TimestampClient::builder()
+            .source(Arc::new(MockSource::new("a", 0)))
+            .build()
+            .unwrap();
+        assert!(matches!(
+            stamp_batch(&client, &[]),
+            Err(TimestampError::Config(_))
+        ));
+    }
+}
@@ -0,0 +1,395 @@
+//! Uncertainty budgets: keep sampling until the bound is tight enough.
+//!
+//! `get_timestamp_within` takes a target bound and a deadline.  Each round
+//! re-samples the beacons that have answered best so far (a lower-RTT
+//! sample of the same server has a narrower interval) and brings in one
+//! more server; only the tightest sample per server is kept.  The quorum
+//! is the `need` servers with the smallest bounds, so a slow beacon never
+//! widens the result — it ends up in `cross_checks`.  Servers are tried in
+//! order of their tracked median RTT.  When the deadline passes first the
+//! call fails with `TimestampError::Budget`, carrying the best bound seen.
+//!
+//! Re-sampling is paced so a tight target cannot hammer the beacons: the
+//! pause between rounds starts at the slowest RTT of the first round and
+//! doubles each round, no server is queried more than
+//! `MAX_SAMPLES_PER_SERVER` times per call, and every query goes through
+//! `opts.rate_limit` like any other.
+
+use crate::{
+    aggregate, run_query, source::TimeSource, BeaconMeta, ProbeOptions, QuorumPolicy,
+    TimestampError, TimestampResponse,
+};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Shortest pause between rounds, so fast failures don't turn into a busy
+/// loop.
+const MIN_ROUND_GAP: Duration = Duration::from_millis(20);
+/// Queries per server per call, first sample included.
+pub const MAX_SAMPLES_PER_SERVER: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UncertaintyBudget {
+    /// Largest acceptable `Metadata::combined_uncert_us`.
+    pub target: Duration,
+    /// Give up after this long, measured from the call.
+    pub deadline: Duration,
+}
+
+impl UncertaintyBudget {
+    pub fn new(target: Duration, deadline: Duration) -> Self {
+        Self { target, deadline }
+    }
+}
+
+/// One timestamp whose bound is within `budget.target`.
+///
+/// The quorum is `opts.min_quorum`, else two beacons (or one, if only one
+/// is eligible).  `sources` are all candidates; list preferred ones first
+/// when RTTs are still unknown.
+pub fn get_timestamp_within(
+    hash: [u8; 32],
+    sources: &[Arc<dyn TimeSource>],
+    opts: &ProbeOptions,
+    budget: UncertaintyBudget,
+) -> Result<TimestampResponse, TimestampError> {
+    let until = Instant::now() + budget.deadline;
+    let target_us = budget.target.as_micros() as u64;
+
+    let counts = |s: &Arc<dyn TimeSource>| s.authenticated() || opts.quorum == QuorumPolicy::Any;
+    let mut eligible: Vec<Arc<dyn TimeSource>> = sources
+        .iter()
+        .filter(|s| counts(s))
+        .filter(|s| opts.admits(s.name()))
+        .cloned()
+        .collect();
+    if eligible.is_empty() {
+        eligible = sources.iter().filter(|s| counts(s)).cloned().collect();
+    }
+    if eligible.is_empty() {
+        return Err(TimestampError::NoProbes);
+    }
+    // known-fast servers first; unknown ones keep the caller's order
+    let median_rtt = |s: &Arc<dyn TimeSource>| {
+        opts.health
+            .as_ref()
+            .and_then(|h| h.rtt_quantile(s.name(), 0.5))
+            .unwrap_or(Duration::MAX)
+    };
+    eligible.sort_by_key(|s| median_rtt(s));
+    let need = opts.min_quorum.unwrap_or(2).clamp(1, eligible.len());
+
+    // tightest sample per server, and servers that failed in this call
+    let mut best: HashMap<String, BeaconMeta> = HashMap::new();
+    let mut sampled: HashMap<String, u32> = HashMap::new();
+    let mut failed: Vec<String> = Vec::new();
+    let mut cross_checks: Vec<BeaconMeta> = Vec::new();
+    let mut best_bound: Option<u64> = None;
+    let mut last_err = None;
+    let mut round = 0;
+    let mut gap = MIN_ROUND_GAP;
+
+    loop {
+        let remaining = until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        let usable = eligible
+            .iter()
+            .filter(|s| !failed.iter().any(|f| f == s.name()))
+            .count();
+        if usable < need {
+            // too many servers failed: the budget is not the problem
+            return Err(match last_err {
+                Some(e) if usable == 0 => e,
+                _ => TimestampError::Quorum { got: usable, need },
+            });
+        }
+        // the current quorum (re-sampled) plus one server not heard yet
+        let mut ranked: Vec<&Arc<dyn TimeSource>> = eligible
+            .iter()
+            .filter(|s| !failed.iter().any(|f| f == s.name()))
+            .filter(|s| {
+                sampled
+                    .get(s.name())
+                    .is_none_or(|n| *n < MAX_SAMPLES_PER_SERVER)
+            })
+            .collect();
+        if ranked.is_empty() {
+            break; // every server sampled to the cap
+        }
+        ranked.sort_by_key(|s| best.get(s.name()).map_or(i128::MAX, |b| b.uncert_us));
+        let fresh = ranked.iter().position(|s| !best.contains_key(s.name()));
+        let mut batch: Vec<Arc<dyn TimeSource>> =
+            ranked.iter().take(need).map(|s| (*s).clone()).collect();
+        if let Some(i) = fresh.filter(|i| *i >= need) {
+            batch.push(ranked[i].clone());
+        }
+        if round == 0 && opts.quorum == QuorumPolicy::AuthenticatedOnly {
+            // SNTP answers are still worth reporting, once
+            batch.extend(sources.iter().filter(|s| !s.authenticated()).cloned());
+        }
+
+        opts.check_rate(&batch)?;
+        let mut o = opts.clone();
+        o.timeout = o.timeout.min(remaining);
+        let results: Vec<_> = thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|s| {
+                    let o = &o;
+                    scope.spawn(move || run_query(&**s, &hash, o))
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+        let mut slowest = MIN_ROUND_GAP;
+        for (s, r) in batch.iter().zip(results) {
+            *sampled.entry(s.name().to_string()).or_default() += 1;
+            match r.map_err(|_| TimestampError::Join)? {
+                Ok(b) if !counts(s) => cross_checks.push(b),
+                Ok(b) => {
+                    slowest = slowest.max(Duration::from_secs_f64(b.rtt_ms / 1e3));
+                    let better = best
+                        .get(s.name())
+                        .is_none_or(|prev| b.uncert_us < prev.uncert_us);
+                    if better {
+                        best.insert(s.name().to_string(), b);
+                    }
+                }
+                Err(e) => {
+                    failed.push(s.name().to_string());
+                    last_err = Some(e);
+                }
+            }
+        }
+        round += 1;
+
+        if best.len() >= need {
+            let resp = assemble(hash, &best, &cross_checks, need, opts);
+            let bound = resp.metadata.combined_uncert_us();
+            best_bound = Some(best_bound.map_or(bound, |b| b.min(bound)));
+            if bound <= target_us {
+                if let Some(health) = &opts.health {
+                    health.observe(&resp);
+                }
+                return Ok(resp);
+            }
+        }
+        gap = if round == 1 { slowest } else { gap * 2 };
+        if until.saturating_duration_since(Instant::now()) <= gap {
+            break; // no time for another paced round
+        }
+        thread::sleep(gap);
+    }
+
+    Err(TimestampError::Budget {
+        best_us: best_bound,
+        target_us,
+    })
+}
+
+/// Response over the `need` tightest samples; the rest become cross-checks.
+fn assemble(
+    hash: [u8; 32],
+    best: &HashMap<String, BeaconMeta>,
+    cross_checks: &[BeaconMeta],
+    need: usize,
+    opts: &ProbeOptions,
+) -> TimestampResponse {
+    let mut samples: Vec<BeaconMeta> = best.values().cloned().collect();
+    samples.sort_by_key(|b| b.uncert_us);
+    let rest = samples.split_off(need.min(samples.len()));
+    let mut checks = cross_checks.to_vec();
+    checks.extend(rest);
+    aggregate(hash, samples, checks, opts.aggregation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MockSource;
+    use std::sync::Mutex;
+
+    /// Answers with `uncerts_us[n]` on its n-th query (the last one
+    /// repeating) and a reported RTT of `rtt`, without waiting for it.
+    struct Sampler {
+        name: String,
+        uncerts_us: Vec<i128>,
+        rtt: Duration,
+        fail: bool,
+        queries: Mutex<Vec<Instant>>,
+    }
+
+    impl Sampler {
+        fn new(name: &str, uncerts_us: &[i128], rtt_ms: u64) -> Arc<Self> {
+            Arc::new(Self {
+                name: name.into(),
+                uncerts_us: uncerts_us.to_vec(),
+                rtt: Duration::from_millis(rtt_ms),
+                fail: false,
+                queries: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn count(&self) -> usize {
+            self.queries.lock().unwrap().len()
+        }
+    }
+
+    impl TimeSource for Sampler {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn authenticated(&self) -> bool {
+            true
+        }
+
+        fn query(
+            &self,
+            hash: &[u8; 32],
+            opts: &ProbeOptions,
+        ) -> Result<BeaconMeta, TimestampError> {
+            let n = {
+                let mut q = self.queries.lock().unwrap();
+                q.push(Instant::now());
+                q.len() - 1
+            };
+            let mut mock = MockSource::new(&self.name, 0);
+            mock.rtt = Duration::ZERO;
+            mock.fail = self.fail;
+            let mut m = mock.query(hash, opts)?;
+            m.uncert_us = self.uncerts_us[n.min(self.uncerts_us.len() - 1)];
+            m.rtt_ms = self.rtt.as_secs_f64() * 1e3;
+            Ok(m)
+        }
+    }
+
+    fn opts() -> ProbeOptions {
+        ProbeOptions {
+            rate_limit: None,
+            ..Default::default()
+        }
+    }
+
+    fn ms(v: u64) -> Duration {
+        Duration::from_millis(v)
+    }
+
+    #[test]
+    fn sampling_stops_once_the_target_is_met() {
+        let a = Sampler::new("a", &[5_000, 3_000, 800], 1);
+        let b = Sampler::new("b", &[4_000, 900], 1);
+        let sources: Vec<Arc<dyn TimeSource>> = vec![a.clone(), b.clone()];
+        let budget = UncertaintyBudget::new(ms(1), ms(5_000));
+        let resp = get_timestamp_within([1; 32], &sources, &opts(), budget).unwrap();
+        assert_eq!(resp.metadata.combined_uncert_us(), 900);
+        assert_eq!((a.count(), b.count()), (3, 3));
+    }
+
+    #[test]
+    fn only_the_tightest_servers_form_the_quorum() {
+        let a = Sampler::new("a", &[300], 1);
+        let b = Sampler::new("b", &[20_000], 1);
+        let c = Sampler::new("c", &[500], 1);
+        let sources: Vec<Arc<dyn TimeSource>> = vec![a, b, c];
+        let o = ProbeOptions {
+            min_quorum: Some(2),
+            ..opts()
+        };
+        let budget = UncertaintyBudget::new(ms(1), ms(5_000));
+        // first round: a, b and the one fresh server beyond them, c
+        let resp = get_timestamp_within([2; 32], &sources, &o, budget).unwrap();
+        let hosts: Vec<_> = resp
+            .metadata
+            .beacons
+            .iter()
+            .map(|b| b.host.as_str())
+            .collect();
+        assert_eq!(hosts, ["a", "c"]);
+        assert_eq!(resp.metadata.cross_checks[0].host, "b");
+    }
+
+    #[test]
+    fn rounds_are_paced_by_the_first_rtt_and_then_doubled() {
+        let a = Sampler::new("a", &[5_000, 5_000, 5_000, 100], 30);
+        let sources: Vec<Arc<dyn TimeSource>> = vec![a.clone()];
+        let budget = UncertaintyBudget::new(Duration::from_micros(100), ms(5_000));
+        get_timestamp_within([3; 32], &sources, &opts(), budget).unwrap();
+        let at = a.queries.lock().unwrap().clone();
+        assert_eq!(at.len(), 4);
+        let gaps: Vec<_> = at.windows(2).map(|w| w[1] - w[0]).collect();
+        for (gap, want) in gaps.iter().zip([30, 60, 120]) {
+            assert!(*gap >= ms(want) && *gap < ms(want + 50), "{gaps:?}");
+        }
+    }
+
+    #[test]
+    fn each_server_is_sampled_at_most_the_cap() {
+        let a = Sampler::new("a", &[5_000], 1);
+        let sources: Vec<Arc<dyn TimeSource>> = vec![a.clone()];
+        let budget = UncertaintyBudget::new(Duration::from_micros(1), ms(20_000));
+        let started = Instant::now();
+        let err = get_timestamp_within([4; 32], &sources, &opts(), budget).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                TimestampError::Budget {
+                    best_us: Some(5_000),
+                    target_us: 1
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(a.count(), MAX_SAMPLES_PER_SERVER as usize);
+        assert!(started.elapsed() < ms(10_000));
+    }
+
+    #[test]
+    fn the_deadline_ends_sampling_with_the_best_bound_seen() {
+        let a = Sampler::new("a", &[5_000, 2_000], 1);
+        let sources: Vec<Arc<dyn TimeSource>> = vec![a.clone()];
+        let budget = UncertaintyBudget::new(Duration::from_micros(1), ms(100));
+        let started = Instant::now();
+        let err = get_timestamp_within([5; 32], &sources, &opts(), budget).unwrap_err();
+        assert!(
+            matches!(
+                err,
+                TimestampError::Budget {
+                    best_us: Some(2_000),
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert!(
+            started.elapsed() < ms(100),
+            "no round started past the deadline"
+        );
+        assert!(a.count() < MAX_SAMPLES_PER_SERVER as usize);
+    }
+
+    #[test]
+    fn failed_servers_are_not_a_budget_problem() {
+        let broken = |name: &str| {
+            let mut s = Sampler::new(name, &[0], 1);
+            Arc::get_mut(&mut s).unwrap().fail = true;
+            s as Arc<dyn TimeSource>
+        };
+        let ok: Arc<dyn TimeSource> = Sampler::new("ok", &[500], 1);
+        let budget = UncertaintyBudget::new(ms(1), ms(1_000));
+
+        let err = get_timestamp_within([6; 32], &[ok, broken("x")], &opts(), budget).unwrap_err();
+        assert!(
+            matches!(err, TimestampError::Quorum { got: 1, need: 2 }),
+            "{err}"
+        );
+        let err = get_timestamp_within([7; 32], &[broken("y")], &opts(), budget).unwrap_err();
+        assert!(matches!(err, TimestampError::Io(_)), "{err}");
+    }
+}
@@ -0,0 +1,297 @@
+//! chrony `refclock SOCK` feed.
+//!
+//! chronyd owns the Unix datagram socket named in its config, e.g.
+//!
+//! ```text
+//! refclock SOCK /var/run/chrony.rt_ping.sock refid RTIM
+//! ```
+//!
+//! and reads fixed-size `struct sock_sample` records from it (see
+//! `refclock_sock.c`).  We send one record per beacon round.
+
+use crate::{client::TimestampClient, fresh_nonce, log, TimestampError, TimestampResponse};
+use std::{
+    io, mem,
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+pub const DEFAULT_SOCK: &str = "/var/run/chrony.rt_ping.sock";
+pub const SOCK_MAGIC: libc::c_int = 0x534f_434b;
+
+/// Leap indicator in the chrony sense.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeapStatus {
+    Normal = 0,
+    Insert = 1,
+    Delete = 2,
+}
+
+/// Wire layout chrony expects – native endianness and alignment.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawSockSample {
+    tv: libc::timeval,
+    offset: f64,
+    pulse: libc::c_int,
+    leap: libc::c_int,
+    _pad: libc::c_int,
+    magic: libc::c_int,
+}
+
+/// One measurement: at system time `time` the true time was `offset_s`
+/// seconds ahead (same sign as `offset_us`).
+#[derive(Debug, Clone, Copy)]
+pub struct SockSample {
+    pub time: SystemTime,
+    pub offset_s: f64,
+    pub leap: LeapStatus,
+}
+
+impl SockSample {
+    pub fn from_response(resp: &TimestampResponse) -> Self {
+        Self {
+            time: SystemTime::UNIX_EPOCH + Duration::from_micros(resp.local_timestamp),
+            offset_s: resp.offset_us() as f64 / 1e6,
+            leap: LeapStatus::Normal, // Roughtime carries no leap warning
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let since = self.time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+        let raw = RawSockSample {
+            tv: libc::timeval {
+                tv_sec: since.as_secs() as libc::time_t,
+                tv_usec: since.subsec_micros() as libc::suseconds_t,
+            },
+            offset: self.offset_s,
+            pulse: 0,
+            leap: self.leap as libc::c_int,
+            _pad: 0,
+            magic: SOCK_MAGIC,
+        };
+        let mut out = vec![0u8; mem::size_of::<RawSockSample>()];
+        unsafe {
+            std::ptr::write_unaligned(out.as_mut_ptr() as *mut RawSockSample, raw);
+        }
+        out
+    }
+
+    /// Inverse of `encode`, for stand-in listeners.
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() != mem::size_of::<RawSockSample>() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad sock_sample size"));
+        }
+        let raw = unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const RawSockSample) };
+        if raw.magic != SOCK_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bad sock_sample magic"));
+        }
+        let leap = match raw.leap {
+            1 => LeapStatus::Insert,
+            2 => LeapStatus::Delete,
+            _ => LeapStatus::Normal,
+        };
+        Ok(Self {
+            time: SystemTime::UNIX_EPOCH
+                + Duration::new(raw.tv.tv_sec as u64, raw.tv.tv_usec as u32 * 1_000),
+            offset_s: raw.offset,
+            leap,
+        })
+    }
+}
+
+/// Sending side of chrony's SOCK refclock.
+pub struct ChronySock {
+    sock: UnixDatagram,
+    path: PathBuf,
+}
+
+impl ChronySock {
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            sock: UnixDatagram::unbound()?,
+            path: path.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn send(&self, sample: &SockSample) -> io::Result<()> {
+        self.sock.send_to(&sample.encode(), &self.path).map(|_| ())
+    }
+}
+
+/// Query `client`'s beacons every `every` and feed chrony forever.
+/// Failed rounds are reported and skipped; chrony copes with gaps.
+pub fn run(
+    path: impl AsRef<Path>,
+    client: &TimestampClient,
+    every: Duration,
+) -> Result<(), TimestampError> {
+    let out = ChronySock::new(path)?;
+    loop {
+        let round = fresh_nonce()
+            .map_err(TimestampError::from)
+            .and_then(|n| client.timestamp(n));
+        match round {
+            Ok(resp) => {
+                let sample = SockSample::from_response(&resp);
+                log::info!(
+                    "chrony      : offset {:+.6} s  (drift {} µs)",
+                    sample.offset_s, resp.metadata.drift_us
+                );
+                if let Err(e) = out.send(&sample) {
+                    log::error!("chrony      : send failed: {e}");
+                }
+            }
+            Err(e) => log::error!("chrony      : beacon round failed: {e}"),
+        }
+        thread::sleep(every);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ntp::{self, NtpPacket},
+        source::SntpSource,
+        ProbeOptions, QuorumPolicy,
+    };
+    use std::{
+        net::UdpSocket,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    /// SNTP stand-in on 127.0.0.1: ignores the first `drop` requests and
+    /// answers the rest `ahead` of our clock.  Returns its address and a
+    /// count of the requests it has seen.
+    fn fake_beacon(drop: usize, ahead: Duration) -> (String, Arc<AtomicUsize>) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap().to_string();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = sock.recv_from(&mut buf) {
+                if counter.fetch_add(1, Ordering::SeqCst) < drop {
+                    continue;
+                }
+                let Ok(req) = NtpPacket::decode(&buf[..len]) else { continue };
+                let ts = ntp::to_ntp_ts(SystemTime::now() + ahead);
+                let resp = NtpPacket {
+                    version: req.version,
+                    mode: ntp::MODE_SERVER,
+                    stratum: 1,
+                    orig_ts: req.tx_ts,
+                    rx_ts: ts,
+                    tx_ts: ts,
+                    ..Default::default()
+                };
+                let _ = sock.send_to(&resp.encode(), peer);
+            }
+        });
+        (addr, seen)
+    }
+
+    fn listener(name: &str) -> (UnixDatagram, PathBuf) {
+        let path = std::env::temp_dir().join(format!("rt_ping-{name}-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        (UnixDatagram::bind(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn sample_round_trips_through_the_socket() {
+        let (chronyd, path) = listener("sample");
+        let sample = SockSample {
+            time: SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456),
+            offset_s: -0.012_5,
+            leap: LeapStatus::Insert,
+        };
+        ChronySock::new(&path).unwrap().send(&sample).unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = chronyd.recv(&mut buf).unwrap();
+        let got = SockSample::decode(&buf[..len]).unwrap();
+        assert_eq!(got.time, sample.time);
+        assert_eq!(got.offset_s, sample.offset_s);
+        assert_eq!(got.leap, LeapStatus::Insert);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn a_lost_request_is_retried_and_the_round_reaches_chrony() {
+        let (beacon, seen) = fake_beacon(1, Duration::from_millis(250));
+        let client = TimestampClient::builder()
+            .sntp(beacon)
+            .quorum(QuorumPolicy::Any)
+            .timeout(Duration::from_millis(200))
+            .retries(1)
+            .build()
+            .unwrap();
+        let resp = client.timestamp([0; 32]).unwrap();
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+
+        let (chronyd, path) = listener("round");
+        ChronySock::new(&path)
+            .unwrap()
+            .send(&SockSample::from_response(&resp))
+            .unwrap();
+        let mut buf = [0u8; 64];
+        let len = chronyd.recv(&mut buf).unwrap();
+        let got = SockSample::decode(&buf[..len]).unwrap();
+        assert!((got.offset_s - 0.25).abs() < 0.05, "offset {}", got.offset_s);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn run_feeds_every_round_to_the_sock_refclock() {
+        let (beacon, seen) = fake_beacon(0, Duration::from_millis(400));
+        let client = TimestampClient::builder()
+            .sntp(beacon)
+            .quorum(QuorumPolicy::Any)
+            .timeout(Duration::from_millis(500))
+            .build()
+            .unwrap();
+        let (chronyd, path) = listener("run");
+        chronyd.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let target = path.clone();
+        thread::spawn(move || run(target, &client, Duration::from_millis(20)));
+
+        let mut buf = [0u8; 64];
+        for _ in 0..2 {
+            let len = chronyd.recv(&mut buf).unwrap();
+            assert_eq!(len, std::mem::size_of::<RawSockSample>());
+            let got = SockSample::decode(&buf[..len]).unwrap();
+            assert!((got.offset_s - 0.4).abs() < 0.05, "offset {}", got.offset_s);
+            assert_eq!(got.leap, LeapStatus::Normal);
+            let lag = SystemTime::now().duration_since(got.time).unwrap_or_default();
+            assert!(lag < Duration::from_secs(1), "sample time {lag:?} old");
+        }
+        assert!(seen.load(Ordering::SeqCst) >= 2);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn a_silent_beacon_times_out_after_every_retry() {
+        let (beacon, seen) = fake_beacon(usize::MAX, Duration::ZERO);
+        let opts = ProbeOptions {
+            timeout: Duration::from_millis(100),
+            retries: 2,
+            rate_limit: None,
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let err = crate::run_query(&SntpSource::new(beacon), &[0; 32], &opts).unwrap_err();
+        assert!(
+            matches!(&err, TimestampError::Io(e) if e.kind() == io::ErrorKind::TimedOut),
+            "{err}"
+        );
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,444 @@
+//! `TimestampClient`: the beacon set and probe options, configured once
+//! and reused.
+//!
+//! ```no_run
+//! # use rt_ping::client::TimestampClient;
+//! # use std::time::Duration;
+//! let client = TimestampClient::builder()
+//!     .roughtime("roughtime.cloudflare.com:2002", None)
+//!     .roughtime_ietf("roughtime.cloudflare.com:2003", None)
+//!     .timeout(Duration::from_secs(1))
+//!     .retries(2)
+//!     .build()?;
+//! let resp = client.timestamp([42; 32])?;
+//! # Ok::<(), rt_ping::TimestampError>(())
+//! ```
+//!
+//! The client is `Send + Sync`; share it behind an `Arc` and call
+//! `timestamp` from any thread.
+
+use crate::{
+    budget::{get_timestamp_within, UncertaintyBudget},
+    clock::{Clock, SystemClock},
+    drift::DriftEstimator,
+    get_timestamp_from,
+    guard::ClockGuard,
+    health::{HealthConfig, HealthTracker},
+    hedge::{get_timestamp_hedged, HedgePolicy},
+    monotonic::MonotonicIssuer,
+    multipath::{MultiPathSource, PathSpec},
+    net::IpPreference,
+    ratelimit::RateLimiter,
+    rto::RtoEstimator,
+    source::{RoughtimeSource, SntpSource, TimeSource},
+    Aggregation, NonceDerivation, ProbeOptions, QuorumPolicy, TimestampError, TimestampResponse,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[derive(Clone)]
+pub struct TimestampClient {
+    sources: Vec<Arc<dyn TimeSource>>,
+    backups: Vec<Arc<dyn TimeSource>>,
+    hedge: HedgePolicy,
+    guard: Option<Arc<ClockGuard>>,
+    monotonic: Option<Arc<MonotonicIssuer>>,
+    drift: Arc<Mutex<DriftEstimator>>,
+    opts: ProbeOptions,
+}
+
+impl TimestampClient {
+    pub fn builder() -> TimestampClientBuilder {
+        TimestampClientBuilder::default()
+    }
+
+    /// One round against every configured source, hedged with the
+    /// backups when there are any; then checked by the guard and made
+    /// monotonic, if configured.
+    pub fn timestamp(&self, hash: [u8; 32]) -> Result<TimestampResponse, TimestampError> {
+        let res = if self.backups.is_empty() {
+            get_timestamp_from(hash, &self.sources, &self.opts)
+        } else {
+            get_timestamp_hedged(hash, &self.sources, &self.backups, &self.opts, &self.hedge)
+        };
+        self.finish(res)
+    }
+
+    /// Keep sampling (primaries, then backups) until the bound is within
+    /// `budget.target`; see `budget`.
+    pub fn timestamp_within(
+        &self,
+        hash: [u8; 32],
+        budget: UncertaintyBudget,
+    ) -> Result<TimestampResponse, TimestampError> {
+        let all: Vec<_> = self.sources.iter().chain(&self.backups).cloned().collect();
+        self.finish(get_timestamp_within(hash, &all, &self.opts, budget))
+    }
+
+    pub fn sources(&self) -> &[Arc<dyn TimeSource>] {
+        &self.sources
+    }
+
+    pub fn options(&self) -> &ProbeOptions {
+        &self.opts
+    }
+
+    pub fn guard(&self) -> Option<&ClockGuard> {
+        self.guard.as_deref()
+    }
+
+    pub fn monotonic(&self) -> Option<&MonotonicIssuer> {
+        self.monotonic.as_deref()
+    }
+
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.opts.rate_limit.as_deref()
+    }
+
+    /// Frequency estimate of the local clock, fed by the daemons' periodic
+    /// rounds (see `corrected::CorrectedClock::refresh`).
+    pub fn drift(&self) -> &Mutex<DriftEstimator> {
+        &self.drift
+    }
+
+    /// Guard check, then the monotonic issuer: refused stamps never
+    /// advance the high-water mark.
+    fn finish(
+        &self,
+        res: Result<TimestampResponse, TimestampError>,
+    ) -> Result<TimestampResponse, TimestampError> {
+        let mut resp = match &self.guard {
+            Some(g) => g.admit(res)?,
+            None => res?,
+        };
+        if let Some(m) = &self.monotonic {
+            m.stamp(&mut resp)?;
+        }
+        Ok(resp)
+    }
+
+    /// Per-server health, shared by every call on this client.
+    pub fn health(&self) -> &HealthTracker {
+        self.opts.health.as_deref().expect("set by build()")
+    }
+}
+
+#[derive(Default)]
+pub struct TimestampClientBuilder {
+    sources: Vec<Arc<dyn TimeSource>>,
+    backups: Vec<Arc<dyn TimeSource>>,
+    hedge: HedgePolicy,
+    guard: Option<Arc<ClockGuard>>,
+    monotonic: Option<Arc<MonotonicIssuer>>,
+    drift: Option<DriftEstimator>,
+    opts: ProbeOptions,
+    paths: Vec<PathSpec>,
+}
+
+impl TimestampClientBuilder {
+    /// Classic (Google) Roughtime beacon; with `public_key` its replies'
+    /// signatures are checked.
+    pub fn roughtime(mut self, host: impl Into<String>, public_key: Option<Vec<u8>>) -> Self {
+        let mut src = RoughtimeSource::classic(host);
+        src.public_key = public_key;
+        self.sources.push(Arc::new(src));
+        self
+    }
+
+    /// IETF-draft Roughtime beacon.
+    pub fn roughtime_ietf(mut self, host: impl Into<String>, public_key: Option<Vec<u8>>) -> Self {
+        let mut src = RoughtimeSource::ietf(host);
+        src.public_key = public_key;
+        self.sources.push(Arc::new(src));
+        self
+    }
+
+    /// Unauthenticated SNTP cross-check.
+    pub fn sntp(mut self, host: impl Into<String>) -> Self {
+        self.sources.push(Arc::new(SntpSource::new(host)));
+        self
+    }
+
+    pub fn source(mut self, source: Arc<dyn TimeSource>) -> Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Queried only when the primaries are slow (see `hedge`).
+    pub fn backup(mut self, source: Arc<dyn TimeSource>) -> Self {
+        self.backups.push(source);
+        self
+    }
+
+    pub fn hedge_policy(mut self, policy: HedgePolicy) -> Self {
+        self.hedge = policy;
+        self
+    }
+
+    /// Refuse unsafe stamps (see `guard`).
+    pub fn guard(mut self, guard: Arc<ClockGuard>) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// Make every stamp strictly greater than the last (see `monotonic`).
+    pub fn monotonic(mut self, issuer: Arc<MonotonicIssuer>) -> Self {
+        self.monotonic = Some(issuer);
+        self
+    }
+
+    /// Start from this drift estimate (e.g. `DriftEstimator::open` on a
+    /// drift file); by default each client fits its own from scratch.
+    pub fn drift(mut self, drift: DriftEstimator) -> Self {
+        self.drift = Some(drift);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.opts.timeout = timeout;
+        self
+    }
+
+    /// Largest reply accepted, bytes (default 4096).
+    pub fn max_reply(mut self, bytes: usize) -> Self {
+        self.opts.max_reply = bytes;
+        self
+    }
+
+    /// Per-server timeouts from RTT history (see `rto`).
+    pub fn adaptive_timeouts(mut self, rto: Arc<RtoEstimator>) -> Self {
+        self.opts.rto = Some(rto);
+        self
+    }
+
+    /// Per-server query budgets (see `ratelimit`); `None` lifts them.
+    /// Without this the process-wide `ratelimit::shared()` applies.
+    pub fn rate_limit(mut self, limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.opts.rate_limit = limiter;
+        self
+    }
+
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.opts.retries = retries;
+        self
+    }
+
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.opts.bind = Some(addr);
+        self
+    }
+
+    /// Pin probes to a network interface (Linux).
+    pub fn interface(mut self, name: impl Into<String>) -> Self {
+        self.opts.interface = Some(name.into());
+        self
+    }
+
+    pub fn ip_preference(mut self, pref: IpPreference) -> Self {
+        self.opts.ip = pref;
+        self
+    }
+
+    /// Query every source over each of `paths` (see `multipath`).
+    pub fn multipath(mut self, paths: Vec<PathSpec>) -> Self {
+        self.paths = paths;
+        self
+    }
+
+    pub fn quorum(mut self, policy: QuorumPolicy) -> Self {
+        self.opts.quorum = policy;
+        self
+    }
+
+    /// Tolerate failed sources as long as `n` beacons answer.
+    pub fn min_quorum(mut self, n: usize) -> Self {
+        self.opts.min_quorum = Some(n);
+        self
+    }
+
+    pub fn aggregation(mut self, policy: Aggregation) -> Self {
+        self.opts.aggregation = policy;
+        self
+    }
+
+    pub fn nonce(mut self, derivation: NonceDerivation) -> Self {
+        self.opts.nonce = derivation;
+        self
+    }
+
+    pub fn kernel_timestamps(mut self, on: bool) -> Self {
+        self.opts.kernel_timestamps = on;
+        self
+    }
+
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.opts.clock = Some(clock);
+        self
+    }
+
+    /// Share a tracker between clients; by default each gets its own.
+    pub fn health(mut self, tracker: Arc<HealthTracker>) -> Self {
+        self.opts.health = Some(tracker);
+        self
+    }
+
+    pub fn build(self) -> Result<TimestampClient, TimestampError> {
+        if self.sources.is_empty() {
+            return Err(TimestampError::Config("no time sources configured".into()));
+        }
+        if self.opts.timeout.is_zero() {
+            return Err(TimestampError::Config("timeout must be non-zero".into()));
+        }
+        if let Some(n) = self.opts.min_quorum {
+            // backups answer in the same round when hedging kicks in
+            let total = self.sources.len() + self.backups.len();
+            if n == 0 || n > total {
+                return Err(TimestampError::Config(format!(
+                    "min_quorum {n} outside 1..={total}"
+                )));
+            }
+        }
+        let paths = self.paths;
+        let wrap = |list: Vec<Arc<dyn TimeSource>>| -> Vec<Arc<dyn TimeSource>> {
+            if paths.len() < 2 {
+                return list;
+            }
+            list.into_iter()
+                .map(|s| Arc::new(MultiPathSource::new(s, paths.clone())) as Arc<dyn TimeSource>)
+                .collect()
+        };
+        let (sources, backups) = (wrap(self.sources), wrap(self.backups));
+        let mut opts = self.opts;
+        if opts.health.is_none() {
+            let clock = opts.clock.clone().unwrap_or_else(|| Arc::new(SystemClock));
+            opts.health = Some(Arc::new(HealthTracker::with_clock(
+                HealthConfig::default(),
+                clock,
+            )));
+        }
+        Ok(TimestampClient {
+            sources,
+            backups,
+            hedge: self.hedge,
+            guard: self.guard,
+            monotonic: self.monotonic,
+            drift: Arc::new(Mutex::new(self.drift.unwrap_or_default())),
+            opts,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MockSource;
+
+    fn mock(name: &str) -> Arc<dyn TimeSource> {
+        Arc::new(MockSource::new(name, 0))
+    }
+
+    fn config_error(b: TimestampClientBuilder) -> String {
+        match b.build() {
+            Err(TimestampError::Config(msg)) => msg,
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => panic!("built"),
+        }
+    }
+
+    #[test]
+    fn a_client_needs_a_primary_source() {
+        let msg = config_error(TimestampClient::builder());
+        assert!(msg.contains("no time sources"), "{msg}");
+        // backups alone are not enough
+        let msg = config_error(TimestampClient::builder().backup(mock("b")));
+        assert!(msg.contains("no time sources"), "{msg}");
+    }
+
+    #[test]
+    fn a_zero_timeout_is_rejected() {
+        let msg = config_error(
+            TimestampClient::builder()
+                .source(mock("a"))
+                .timeout(Duration::ZERO),
+        );
+        assert!(msg.contains("timeout"), "{msg}");
+    }
+
+    #[test]
+    fn min_quorum_must_fit_the_sources_and_backups() {
+        let two = || {
+            TimestampClient::builder()
+                .source(mock("a"))
+                .source(mock("b"))
+        };
+        let msg = config_error(two().min_quorum(0));
+        assert!(msg.contains("min_quorum 0 outside 1..=2"), "{msg}");
+        let msg = config_error(two().min_quorum(3));
+        assert!(msg.contains("min_quorum 3 outside 1..=2"), "{msg}");
+
+        let client = two().backup(mock("c")).min_quorum(3).build().unwrap();
+        assert_eq!(client.options().min_quorum, Some(3));
+        let msg = config_error(two().backup(mock("c")).min_quorum(4));
+        assert!(msg.contains("outside 1..=3"), "{msg}");
+    }
+
+    #[test]
+    fn build_fills_in_the_defaults() {
+        let client = TimestampClient::builder()
+            .source(mock("a"))
+            .build()
+            .unwrap();
+        assert_eq!(client.sources().len(), 1);
+        assert!(client.options().health.is_some());
+        assert!(client.rate_limiter().is_some());
+        assert!(client.guard().is_none() && client.monotonic().is_none());
+        assert_eq!(client.drift().lock().unwrap().num_samples(), 0);
+
+        let unlimited = TimestampClient::builder()
+            .source(mock("a"))
+            .rate_limit(None)
+            .build()
+            .unwrap();
+        assert!(unlimited.rate_limiter().is_none());
+    }
+
+    #[test]
+    fn multipath_wraps_every_source_once_there_are_two_paths() {
+        let paths = vec![
+            PathSpec::parse("v4").unwrap(),
+            PathSpec::parse("v6").unwrap(),
+        ];
+        let paths_seen = |paths: Vec<PathSpec>| {
+            let client = TimestampClient::builder()
+                .source(mock("a"))
+                .multipath(paths)
+                .rate_limit(None)
+                .build()
+                .unwrap();
+            let resp = client.timestamp([0; 32]).unwrap();
+            resp.metadata.beacons[0].paths.len()
+        };
+        assert_eq!(
+            paths_seen(paths[..1].to_vec()),
+            0,
+            "one path is not wrapped"
+        );
+        assert_eq!(paths_seen(paths), 2);
+    }
+
+    #[test]
+    fn a_built_client_answers_from_its_sources() {
+        let client = TimestampClient::builder()
+            .source(Arc::new(MockSource::new("a", 1_000)))
+            .source(Arc::new(MockSource::new("b", 3_000)))
+            .quorum(QuorumPolicy::Any)
+            .build()
+            .unwrap();
+        let resp = client.timestamp([1; 32]).unwrap();
+        assert_eq!(resp.metadata.beacons.len(), 2);
+        assert_eq!(resp.offset_us(), 2_000);
+    }
+}
@@ -0,0 +1,75 @@
+//! Wall and monotonic time behind a trait, so probe timing can be driven
+//! by a simulated clock.
+
+use std::{
+    fmt::Debug,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+pub trait Clock: Send + Sync + Debug {
+    /// Wall-clock reading (what `SystemTime::now()` would say).
+    fn wall(&self) -> SystemTime;
+    /// Monotonic reading (what `Instant::now()` would say).
+    fn mono(&self) -> Instant;
+    /// Let `d` pass on this clock.
+    fn sleep(&self, d: Duration) {
+        thread::sleep(d)
+    }
+}
+
+/// The real clocks.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn mono(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Manually advanced clock: both readings move only on `advance`.
+#[derive(Debug)]
+pub struct SimClock {
+    wall0: SystemTime,
+    mono0: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl SimClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            wall0: start,
+            mono0: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, d: Duration) {
+        *self.elapsed.lock().unwrap() += d;
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for SimClock {
+    fn wall(&self) -> SystemTime {
+        self.wall0 + self.elapsed()
+    }
+
+    fn mono(&self) -> Instant {
+        self.mono0 + self.elapsed()
+    }
+
+    /// Advances the clock instead of blocking.
+    fn sleep(&self, d: Duration) {
+        self.advance(d)
+    }
+}
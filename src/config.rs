@@ -0,0 +1,909 @@
+//! Operator configuration: a TOML file, environment overrides and CLI flags.
+//!
+//! Precedence, lowest first: built-in defaults < file < environment < CLI.
+//! Every setting has one dotted key; the environment variable is
+//! `RT_PING_` + the key upper-cased with `.` → `_`, the flag is `--` + the
+//! key with `.`/`_` → `-`:
+//!
+//! | key                     | env                             | flag                      |
+//! |-------------------------|---------------------------------|---------------------------|
+//! | `servers`               | `RT_PING_SERVERS`               | `--servers`               |
+//! | `backups`               | `RT_PING_BACKUPS`               | `--backups`               |
+//! | `timeout_ms`            | `RT_PING_TIMEOUT_MS`            | `--timeout-ms`            |
+//! | `retries`               | `RT_PING_RETRIES`               | `--retries`               |
+//! | `bind`                  | `RT_PING_BIND`                  | `--bind`                  |
+//! | `interface`             | `RT_PING_INTERFACE`             | `--interface`             |
+//! | `ip`                    | `RT_PING_IP`                    | `--ip`                    |
+//! | `paths`                 | `RT_PING_PATHS`                 | `--paths`                 |
+//! | `policy.quorum`         | `RT_PING_POLICY_QUORUM`         | `--policy-quorum`         |
+//! | `policy.min_quorum`     | `RT_PING_POLICY_MIN_QUORUM`     | `--policy-min-quorum`     |
+//! | `policy.aggregation`    | `RT_PING_POLICY_AGGREGATION`    | `--policy-aggregation`    |
+//! | `policy.nonce`          | `RT_PING_POLICY_NONCE`          | `--policy-nonce`          |
+//! | `rto.enabled`           | `RT_PING_RTO_ENABLED`           | `--rto-enabled`           |
+//! | `rto.floor_ms`          | `RT_PING_RTO_FLOOR_MS`          | `--rto-floor-ms`          |
+//! | `rto.ceiling_ms`        | `RT_PING_RTO_CEILING_MS`        | `--rto-ceiling-ms`        |
+//! | `rto.file`              | `RT_PING_RTO_FILE`              | `--rto-file`              |
+//! | `monotonic.file`        | `RT_PING_MONOTONIC_FILE`        | `--monotonic-file`        |
+//! | `monotonic.reserve_ms`  | `RT_PING_MONOTONIC_RESERVE_MS`  | `--monotonic-reserve-ms`  |
+//! | `drift.file`            | `RT_PING_DRIFT_FILE`            | `--drift-file`            |
+//! | `rate_limit.enabled`    | `RT_PING_RATE_LIMIT_ENABLED`    | `--rate-limit-enabled`    |
+//! | `rate_limit.burst`      | `RT_PING_RATE_LIMIT_BURST`      | `--rate-limit-burst`      |
+//! | `rate_limit.per_minute` | `RT_PING_RATE_LIMIT_PER_MINUTE` | `--rate-limit-per-minute` |
+//! | `rate_limit.policy`     | `RT_PING_RATE_LIMIT_POLICY`     | `--rate-limit-policy`     |
+//! | `output.format`         | `RT_PING_OUTPUT_FORMAT`         | `--output-format`         |
+//! | `log.level`             | `RT_PING_LOG_LEVEL`             | `--log-level`             |
+//!
+//! Env and CLI `servers` / `backups` are comma-separated source specs (see
+//! `source::from_spec`) and replace the file's list; public keys and
+//! per-server rate limits can only be given in the file.  Backups are only
+//! queried when the servers are slow (see `hedge`):
+//!
+//! ```toml
+//! timeout_ms = 2000
+//! retries    = 1
+//!
+//! [[servers]]
+//! spec       = "roughtime://roughtime.cloudflare.com:2002"
+//! public_key = "803eb78528f749c4bec2e39e1abb9b5e5ab7e4dd5ce4b6f2fd2f93ecc3538f1a"
+//!
+//! [[servers]]
+//! spec = "sntp://pool.ntp.org:123"
+//!
+//! [policy]
+//! quorum      = "authenticated-only"
+//! aggregation = "median"
+//!
+//! [rto]
+//! enabled = true
+//! file    = "/var/lib/rt_ping/rtt"
+//!
+//! [drift]
+//! file = "/var/lib/rt_ping/drift"
+//!
+//! [rate_limit]
+//! burst      = 4
+//! per_minute = 30
+//! policy     = "failover"
+//!
+//! [output]
+//! format = "json"
+//! ```
+//!
+//! The file path comes from `--config` or `RT_PING_CONFIG`.
+//!
+//! `[rate_limit]` defaults to `ratelimit::Rate::default()` (burst 8, then
+//! 60 per minute per server) with policy `wait`.  The server subcommands
+//! stamp through the same limiter, so size it for the service.
+
+use crate::{
+    client::TimestampClient,
+    drift::DriftEstimator,
+    log::LogLevel,
+    monotonic::{MonotonicIssuer, DEFAULT_RESERVE},
+    multipath::PathSpec,
+    net::IpPreference,
+    ratelimit::{OverLimit, Rate, RateLimiter},
+    rto::{RtoConfig, RtoEstimator},
+    source::{self, RoughtimeSource, TimeSource},
+    Aggregation, NonceDerivation, QuorumPolicy, TimestampError, DEFAULT_HOSTS,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap, fs, io, net::SocketAddr, path::Path, str::FromStr, sync::Arc,
+    time::Duration,
+};
+
+pub const ENV_PREFIX: &str = "RT_PING_";
+pub const CONFIG_ENV: &str = "RT_PING_CONFIG";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("{path}: {source}")]
+    Read { path: String, source: io::Error },
+    /// TOML syntax / type errors; the message carries the key and line.
+    #[error("{path}: {message}")]
+    Parse { path: String, message: String },
+    /// `origin` is the file key, env variable or flag that was rejected.
+    #[error("{origin}: {message}")]
+    Invalid { origin: String, message: String },
+}
+
+impl From<ConfigError> for TimestampError {
+    fn from(e: ConfigError) -> Self {
+        TimestampError::Config(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    /// `{:?}` of the response.
+    #[default]
+    Debug,
+    /// Human-readable, one field per line.
+    Text,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerEntry {
+    pub spec: String,
+    /// Hex long-term key (Roughtime only).
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// This server's own `[rate_limit]` values.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub per_minute: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub quorum: QuorumPolicy,
+    pub min_quorum: Option<usize>,
+    pub aggregation: Aggregation,
+    pub nonce: NonceDerivation,
+}
+
+/// Adaptive timeouts (see `rto`); `timeout_ms` applies until a server
+/// has history.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RtoSection {
+    pub enabled: bool,
+    pub floor_ms: u64,
+    pub ceiling_ms: u64,
+    /// RTT history kept between runs.
+    pub file: Option<String>,
+}
+
+impl Default for RtoSection {
+    fn default() -> Self {
+        let d = RtoConfig::default();
+        Self {
+            enabled: false,
+            floor_ms: d.floor.as_millis() as u64,
+            ceiling_ms: d.ceiling.as_millis() as u64,
+            file: None,
+        }
+    }
+}
+
+/// Strictly increasing stamps (see `monotonic`), on when `file` is set.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MonotonicSection {
+    /// High-water mark, fsync'd.
+    pub file: Option<String>,
+    pub reserve_ms: u64,
+}
+
+impl Default for MonotonicSection {
+    fn default() -> Self {
+        Self {
+            file: None,
+            reserve_ms: DEFAULT_RESERVE.as_millis() as u64,
+        }
+    }
+}
+
+/// Local clock frequency (see `drift`), carried across restarts when
+/// `file` is set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DriftSection {
+    /// chrony-style drift file: `<freq_ppm> <skew_ppm>`.
+    pub file: Option<String>,
+}
+
+/// Per-server query budgets (see `ratelimit`); servers may override
+/// `burst` and `per_minute` in their own entry.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitSection {
+    pub enabled: bool,
+    pub burst: u32,
+    pub per_minute: u32,
+    pub policy: OverLimit,
+}
+
+impl Default for RateLimitSection {
+    fn default() -> Self {
+        let d = Rate::default();
+        Self {
+            enabled: true,
+            burst: d.burst,
+            per_minute: (d.per_sec * 60.0) as u32,
+            policy: OverLimit::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+}
+
+/// Daemon log lines on stderr (see `log`): `quiet`, `error`, `info`
+/// (default) or `debug`, which also dumps this configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: LogLevel,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub servers: Vec<ServerEntry>,
+    pub backups: Vec<ServerEntry>,
+    pub timeout_ms: u64,
+    pub retries: u32,
+    pub bind: Option<String>,
+    /// Network interface to pin probes to (Linux).
+    pub interface: Option<String>,
+    pub ip: IpPreference,
+    /// Multi-path probing: `v4`, `v6`, `if:<name>`, `bind:<addr>`.
+    pub paths: Vec<String>,
+    pub policy: PolicyConfig,
+    pub rto: RtoSection,
+    pub monotonic: MonotonicSection,
+    pub drift: DriftSection,
+    pub rate_limit: RateLimitSection,
+    pub output: OutputConfig,
+    pub log: LogConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            servers: DEFAULT_HOSTS
+                .iter()
+                .map(|h| ServerEntry {
+                    spec: format!("roughtime://{h}"),
+                    public_key: None,
+                    burst: None,
+                    per_minute: None,
+                })
+                .collect(),
+            backups: Vec::new(),
+            timeout_ms: 3_000,
+            retries: 0,
+            bind: None,
+            interface: None,
+            ip: IpPreference::default(),
+            paths: Vec::new(),
+            policy: PolicyConfig::default(),
+            rto: RtoSection::default(),
+            monotonic: MonotonicSection::default(),
+            drift: DriftSection::default(),
+            rate_limit: RateLimitSection::default(),
+            output: OutputConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+/// Keys settable from env / CLI, in table order.
+pub const KEYS: [&str; 25] = [
+    "servers",
+    "backups",
+    "timeout_ms",
+    "retries",
+    "bind",
+    "interface",
+    "ip",
+    "paths",
+    "policy.quorum",
+    "policy.min_quorum",
+    "policy.aggregation",
+    "policy.nonce",
+    "rto.enabled",
+    "rto.floor_ms",
+    "rto.ceiling_ms",
+    "rto.file",
+    "monotonic.file",
+    "monotonic.reserve_ms",
+    "drift.file",
+    "rate_limit.enabled",
+    "rate_limit.burst",
+    "rate_limit.per_minute",
+    "rate_limit.policy",
+    "output.format",
+    "log.level",
+];
+
+impl Config {
+    pub fn from_toml(text: &str, path: &str) -> Result<Self, ConfigError> {
+        let cfg: Config = toml::from_str(text).map_err(|e| ConfigError::Parse {
+            path: path.to_string(),
+            message: e.to_string(),
+        })?;
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let shown = path.as_ref().display().to_string();
+        let text = fs::read_to_string(&path).map_err(|source| ConfigError::Read {
+            path: shown.clone(),
+            source,
+        })?;
+        Self::from_toml(&text, &shown)
+    }
+
+    /// Defaults < `path` (if any) < the process environment < `flags`
+    /// (`(key, value)` pairs, already stripped of `--`).
+    pub fn load(path: Option<&Path>, flags: &[(String, String)]) -> Result<Self, ConfigError> {
+        Self::load_from(path, std::env::vars().collect(), flags)
+    }
+
+    /// As `load`, with the environment given as `vars`.
+    pub fn load_from(
+        path: Option<&Path>,
+        vars: Vec<(String, String)>,
+        flags: &[(String, String)],
+    ) -> Result<Self, ConfigError> {
+        let env_path = vars.iter().find(|(k, _)| k == CONFIG_ENV).map(|(_, v)| v);
+        let mut cfg = match path.or(env_path.map(Path::new)) {
+            Some(p) => Self::from_file(p)?,
+            None => Self::default(),
+        };
+        cfg.apply_env(vars)?;
+        for (flag, value) in flags {
+            let key = flag_key(flag).ok_or_else(|| ConfigError::Invalid {
+                origin: format!("--{flag}"),
+                message: "unknown option".into(),
+            })?;
+            cfg.set(key, value, &format!("--{flag}"))?;
+        }
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Apply `RT_PING_*` variables from `vars`; unknown `RT_PING_` names
+    /// are rejected so typos don't go unnoticed.
+    pub fn apply_env(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<(), ConfigError> {
+        for (name, value) in vars {
+            let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            if name == CONFIG_ENV {
+                continue;
+            }
+            let key = KEYS
+                .iter()
+                .find(|k| k.replace('.', "_").eq_ignore_ascii_case(rest))
+                .ok_or_else(|| ConfigError::Invalid {
+                    origin: name.clone(),
+                    message: "unknown variable".into(),
+                })?;
+            self.set(key, &value, &name)?;
+        }
+        Ok(())
+    }
+
+    /// Set one dotted `key` from a string; `origin` names it in errors.
+    pub fn set(&mut self, key: &str, value: &str, origin: &str) -> Result<(), ConfigError> {
+        let invalid = |message: String| ConfigError::Invalid {
+            origin: origin.to_string(),
+            message,
+        };
+        match key {
+            "servers" => self.servers = spec_list(value),
+            "backups" => self.backups = spec_list(value),
+            "timeout_ms" => self.timeout_ms = parse(value).map_err(invalid)?,
+            "retries" => self.retries = parse(value).map_err(invalid)?,
+            "bind" => self.bind = Some(value.to_string()),
+            "interface" => self.interface = Some(value.to_string()),
+            "ip" => self.ip = enum_value(value).map_err(invalid)?,
+            "paths" => {
+                self.paths = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+            "policy.quorum" => self.policy.quorum = enum_value(value).map_err(invalid)?,
+            "policy.min_quorum" => self.policy.min_quorum = Some(parse(value).map_err(invalid)?),
+            "policy.aggregation" => self.policy.aggregation = enum_value(value).map_err(invalid)?,
+            "policy.nonce" => self.policy.nonce = enum_value(value).map_err(invalid)?,
+            "rto.enabled" => self.rto.enabled = parse(value).map_err(invalid)?,
+            "rto.floor_ms" => self.rto.floor_ms = parse(value).map_err(invalid)?,
+            "rto.ceiling_ms" => self.rto.ceiling_ms = parse(value).map_err(invalid)?,
+            "rto.file" => self.rto.file = Some(value.to_string()),
+            "monotonic.file" => self.monotonic.file = Some(value.to_string()),
+            "monotonic.reserve_ms" => self.monotonic.reserve_ms = parse(value).map_err(invalid)?,
+            "drift.file" => self.drift.file = Some(value.to_string()),
+            "rate_limit.enabled" => self.rate_limit.enabled = parse(value).map_err(invalid)?,
+            "rate_limit.burst" => self.rate_limit.burst = parse(value).map_err(invalid)?,
+            "rate_limit.per_minute" => {
+                self.rate_limit.per_minute = parse(value).map_err(invalid)?
+            }
+            "rate_limit.policy" => self.rate_limit.policy = enum_value(value).map_err(invalid)?,
+            "output.format" => self.output.format = enum_value(value).map_err(invalid)?,
+            "log.level" => self.log.level = enum_value(value).map_err(invalid)?,
+            _ => return Err(invalid(format!("unknown key {key:?}"))),
+        }
+        Ok(())
+    }
+
+    /// Checks serde can't express; errors name the offending key.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |origin: String, message: String| ConfigError::Invalid { origin, message };
+        if self.servers.is_empty() {
+            return Err(invalid(
+                "servers".into(),
+                "at least one server is required".into(),
+            ));
+        }
+        for (list, s, i) in self
+            .servers
+            .iter()
+            .enumerate()
+            .map(|(i, s)| ("servers", s, i))
+            .chain(
+                self.backups
+                    .iter()
+                    .enumerate()
+                    .map(|(i, s)| ("backups", s, i)),
+            )
+        {
+            source::from_spec(&s.spec)
+                .map_err(|e| invalid(format!("{list}[{i}].spec"), e.to_string()))?;
+            if s.burst == Some(0) {
+                return Err(invalid(
+                    format!("{list}[{i}].burst"),
+                    "must be non-zero".into(),
+                ));
+            }
+            if let Some(pk) = &s.public_key {
+                let origin = format!("{list}[{i}].public_key");
+                let bytes = hex::decode(pk).map_err(|e| invalid(origin.clone(), e.to_string()))?;
+                if bytes.len() != 32 {
+                    return Err(invalid(
+                        origin,
+                        format!("expected 32 bytes, got {}", bytes.len()),
+                    ));
+                }
+                if roughtime_source(&s.spec).is_none() {
+                    return Err(invalid(
+                        origin,
+                        "keys only apply to Roughtime servers".into(),
+                    ));
+                }
+            }
+        }
+        if self.timeout_ms == 0 {
+            return Err(invalid("timeout_ms".into(), "must be non-zero".into()));
+        }
+        if self.rto.ceiling_ms < self.rto.floor_ms {
+            return Err(invalid(
+                "rto.ceiling_ms".into(),
+                format!("below rto.floor_ms ({})", self.rto.floor_ms),
+            ));
+        }
+        if self.rate_limit.burst == 0 {
+            return Err(invalid(
+                "rate_limit.burst".into(),
+                "must be non-zero".into(),
+            ));
+        }
+        if let Some(bind) = &self.bind {
+            bind.parse::<SocketAddr>()
+                .map_err(|e| invalid("bind".into(), format!("{bind:?}: {e}")))?;
+        }
+        for (i, p) in self.paths.iter().enumerate() {
+            PathSpec::parse(p).map_err(|e| invalid(format!("paths[{i}]"), e.to_string()))?;
+        }
+        if let Some(n) = self.policy.min_quorum {
+            let total = self.servers.len() + self.backups.len();
+            if n == 0 || n > total {
+                return Err(invalid(
+                    "policy.min_quorum".into(),
+                    format!("{n} outside 1..={total}"),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Long-term keys of the keyed Roughtime servers and backups, by host.
+    pub fn public_keys(&self) -> HashMap<String, Vec<u8>> {
+        self.servers
+            .iter()
+            .chain(&self.backups)
+            .filter_map(ServerEntry::host_key)
+            .collect()
+    }
+
+    /// A client with these servers and policies.
+    pub fn client(&self) -> Result<TimestampClient, ConfigError> {
+        self.validate()?;
+        let mut b = TimestampClient::builder()
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .retries(self.retries)
+            .quorum(self.policy.quorum)
+            .aggregation(self.policy.aggregation)
+            .nonce(self.policy.nonce)
+            .ip_preference(self.ip);
+        if let Some(bind) = &self.bind {
+            // validated above
+            b = b.bind(bind.parse().expect("validated bind"));
+        }
+        if let Some(dev) = &self.interface {
+            b = b.interface(dev);
+        }
+        if !self.paths.is_empty() {
+            let paths = self
+                .paths
+                .iter()
+                .map(|p| PathSpec::parse(p).expect("validated path"));
+            b = b.multipath(paths.collect());
+        }
+        if let Some(n) = self.policy.min_quorum {
+            b = b.min_quorum(n);
+        }
+        if self.rto.enabled {
+            let cfg = RtoConfig {
+                floor: Duration::from_millis(self.rto.floor_ms),
+                ceiling: Duration::from_millis(self.rto.ceiling_ms),
+            };
+            let rto = match &self.rto.file {
+                Some(path) => RtoEstimator::open(path, cfg).map_err(|e| ConfigError::Invalid {
+                    origin: "rto.file".into(),
+                    message: format!("{path}: {e}"),
+                })?,
+                None => RtoEstimator::new(cfg),
+            };
+            b = b.adaptive_timeouts(Arc::new(rto));
+        }
+        if let Some(path) = &self.monotonic.file {
+            let reserve = Duration::from_millis(self.monotonic.reserve_ms);
+            let issuer =
+                MonotonicIssuer::open(path, reserve).map_err(|e| ConfigError::Invalid {
+                    origin: "monotonic.file".into(),
+                    message: format!("{path}: {e}"),
+                })?;
+            b = b.monotonic(Arc::new(issuer));
+        }
+        if let Some(path) = &self.drift.file {
+            let drift = DriftEstimator::open(path).map_err(|e| ConfigError::Invalid {
+                origin: "drift.file".into(),
+                message: format!("{path}: {e}"),
+            })?;
+            b = b.drift(drift);
+        }
+        if !self.rate_limit.enabled {
+            b = b.rate_limit(None);
+        } else {
+            let rl = &self.rate_limit;
+            let mut limiter =
+                RateLimiter::new(Rate::per_minute(rl.per_minute, rl.burst), rl.policy);
+            for s in self.servers.iter().chain(&self.backups) {
+                if s.burst.is_some() || s.per_minute.is_some() {
+                    let rate = Rate::per_minute(
+                        s.per_minute.unwrap_or(rl.per_minute),
+                        s.burst.unwrap_or(rl.burst),
+                    );
+                    limiter = limiter.server(s.source().name(), rate);
+                }
+            }
+            b = b.rate_limit(Some(Arc::new(limiter)));
+        }
+        for s in &self.servers {
+            b = b.source(s.source());
+        }
+        for s in &self.backups {
+            b = b.backup(s.source());
+        }
+        b.build().map_err(|e| ConfigError::Invalid {
+            origin: "servers".into(),
+            message: e.to_string(),
+        })
+    }
+}
+
+impl ServerEntry {
+    /// `(host, key)` for Roughtime entries with a valid key.
+    fn host_key(&self) -> Option<(String, Vec<u8>)> {
+        let key = hex::decode(self.public_key.as_ref()?).ok()?;
+        let src = roughtime_source(&self.spec)?;
+        Some((src.host, key))
+    }
+
+    /// The configured source; call on validated entries only.
+    fn source(&self) -> Arc<dyn TimeSource> {
+        match (&self.public_key, roughtime_source(&self.spec)) {
+            (Some(pk), Some(mut src)) => {
+                src.public_key = hex::decode(pk).ok();
+                Arc::new(src)
+            }
+            _ => source::from_spec(&self.spec).expect("validated spec"),
+        }
+    }
+}
+
+/// Comma-separated specs, without keys.
+fn spec_list(value: &str) -> Vec<ServerEntry> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|s| ServerEntry {
+            spec: s.to_string(),
+            public_key: None,
+            burst: None,
+            per_minute: None,
+        })
+        .collect()
+}
+
+/// Map a CLI flag (without `--`) to its key.
+pub fn flag_key(flag: &str) -> Option<&'static str> {
+    KEYS.iter()
+        .copied()
+        .find(|k| k.replace(['.', '_'], "-") == flag)
+}
+
+/// `spec` as a Roughtime source, if it names one.
+fn roughtime_source(spec: &str) -> Option<RoughtimeSource> {
+    match spec.split_once("://") {
+        Some(("roughtime", host)) => Some(RoughtimeSource::classic(host)),
+        Some(("roughtime-ietf", host)) => Some(RoughtimeSource::ietf(host)),
+        None if spec != "local" => Some(RoughtimeSource::classic(spec)),
+        _ => None,
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value.trim().parse().map_err(|e| format!("{value:?}: {e}"))
+}
+
+/// Kebab-case enum from a bare string, via the same serde names as the file.
+fn enum_value<T: for<'de> Deserialize<'de>>(value: &str) -> Result<T, String> {
+    T::deserialize(serde::de::value::StrDeserializer::<serde::de::value::Error>::new(value.trim()))
+        .map_err(|e| format!("{value:?}: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A config file in the temp dir, removed on drop.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, text: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("rt_ping-config-{name}-{}.toml", std::process::id()));
+            fs::write(&path, text).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn vars(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn origin(e: ConfigError) -> String {
+        match e {
+            ConfigError::Invalid { origin, .. } => origin,
+            other => panic!("expected an invalid setting, got {other}"),
+        }
+    }
+
+    const FILE: &str = r#"
+timeout_ms = 2000
+retries    = 1
+
+[[servers]]
+spec = "roughtime://a.example:2002"
+
+[[servers]]
+spec = "roughtime://b.example:2002"
+
+[output]
+format = "json"
+"#;
+
+    #[test]
+    fn defaults_without_file_env_or_flags() {
+        let cfg = Config::load_from(None, Vec::new(), &[]).unwrap();
+        assert_eq!(cfg, Config::default());
+        assert_eq!(cfg.servers.len(), DEFAULT_HOSTS.len());
+        assert_eq!(cfg.log.level, LogLevel::Info);
+    }
+
+    #[test]
+    fn file_then_env_then_flags() {
+        let file = TempFile::new("layers", FILE);
+        let env = vars(&[
+            ("RT_PING_TIMEOUT_MS", "1500"),
+            ("RT_PING_RETRIES", "2"),
+            ("PATH", "/bin"), // not ours
+        ]);
+        let flags = vars(&[("retries", "3"), ("log-level", "quiet")]);
+        let cfg = Config::load_from(Some(&file.0), env, &flags).unwrap();
+        assert_eq!(cfg.servers.len(), 2, "from the file");
+        assert_eq!(cfg.output.format, OutputFormat::Json, "from the file");
+        assert_eq!(cfg.timeout_ms, 1500, "env beats the file");
+        assert_eq!(cfg.retries, 3, "flags beat env");
+        assert_eq!(cfg.log.level, LogLevel::Quiet);
+        assert_eq!(cfg.rate_limit, RateLimitSection::default(), "untouched");
+    }
+
+    #[test]
+    fn the_file_may_come_from_the_environment() {
+        let file = TempFile::new("env-path", FILE);
+        let shown = file.0.display().to_string();
+        let cfg = Config::load_from(None, vars(&[(CONFIG_ENV, &shown)]), &[]).unwrap();
+        assert_eq!(cfg.timeout_ms, 2000);
+
+        // an explicit path wins over RT_PING_CONFIG
+        let other = TempFile::new("explicit", "timeout_ms = 700\n");
+        let cfg = Config::load_from(Some(&other.0), vars(&[(CONFIG_ENV, &shown)]), &[]).unwrap();
+        assert_eq!(cfg.timeout_ms, 700);
+    }
+
+    #[test]
+    fn env_and_flag_server_lists_replace_the_files() {
+        let file = TempFile::new("lists", FILE);
+        let env = vars(&[(
+            "RT_PING_SERVERS",
+            "sntp://c.example:123, roughtime-ietf://d.example:2003",
+        )]);
+        let cfg = Config::load_from(Some(&file.0), env.clone(), &[]).unwrap();
+        let specs: Vec<_> = cfg.servers.iter().map(|s| s.spec.as_str()).collect();
+        assert_eq!(
+            specs,
+            ["sntp://c.example:123", "roughtime-ietf://d.example:2003"]
+        );
+
+        let flags = vars(&[("servers", "e.example:2002")]);
+        let cfg = Config::load_from(Some(&file.0), env, &flags).unwrap();
+        assert_eq!(cfg.servers.len(), 1);
+        assert_eq!(cfg.servers[0].spec, "e.example:2002");
+    }
+
+    #[test]
+    fn every_key_has_an_env_variable_and_a_flag() {
+        for key in KEYS {
+            assert_eq!(flag_key(&key.replace(['.', '_'], "-")), Some(key));
+            let name = format!("{ENV_PREFIX}{}", key.replace('.', "_").to_uppercase());
+            let mut cfg = Config::default();
+            // the value may be rejected, but never as an unknown variable
+            if let Err(ConfigError::Invalid { origin, message }) =
+                cfg.apply_env(vars(&[(&name, "")]))
+            {
+                assert_eq!(origin, name);
+                assert_ne!(message, "unknown variable");
+            }
+        }
+    }
+
+    #[test]
+    fn file_errors_name_the_key() {
+        let err = Config::from_toml("timeout_ms = \"soon\"\n", "rt.toml").unwrap_err();
+        assert!(matches!(&err, ConfigError::Parse { .. }));
+        assert!(err.to_string().starts_with("rt.toml: "), "{err}");
+        assert!(err.to_string().contains("timeout_ms"), "{err}");
+
+        let err = Config::from_toml("[policy]\nquorom = \"any\"\n", "rt.toml").unwrap_err();
+        assert!(err.to_string().contains("quorom"), "{err}");
+
+        let err = Config::from_file("/nonexistent/rt_ping.toml").unwrap_err();
+        assert!(matches!(err, ConfigError::Read { .. }));
+    }
+
+    #[test]
+    fn env_and_flag_errors_name_the_variable_or_flag() {
+        let err = Config::load_from(None, vars(&[("RT_PING_TIMEOUT", "5")]), &[]).unwrap_err();
+        assert_eq!(origin(err), "RT_PING_TIMEOUT");
+        let err = Config::load_from(None, vars(&[("RT_PING_RETRIES", "many")]), &[]).unwrap_err();
+        assert_eq!(origin(err), "RT_PING_RETRIES");
+
+        let err = Config::load_from(None, Vec::new(), &vars(&[("bogus", "1")])).unwrap_err();
+        assert_eq!(origin(err), "--bogus");
+        let err =
+            Config::load_from(None, Vec::new(), &vars(&[("policy-quorum", "most")])).unwrap_err();
+        assert_eq!(origin(err), "--policy-quorum");
+    }
+
+    #[test]
+    fn validation_errors_name_the_setting() {
+        let key = "803eb78528f749c4bec2e39e1abb9b5e5ab7e4dd5ce4b6f2fd2f93ecc3538f1a";
+        let cases = [
+            ("servers = []\n".to_string(), "servers"),
+            (
+                "[[servers]]\nspec = \"a.example:2002\"\n[[servers]]\nspec = \"gps://x:1\"\n"
+                    .to_string(),
+                "servers[1].spec",
+            ),
+            (
+                "[[backups]]\nspec = \"b.example\"\n".to_string(),
+                "backups[0].spec",
+            ),
+            (
+                format!("[[servers]]\nspec = \"sntp://a.example:123\"\npublic_key = \"{key}\"\n"),
+                "servers[0].public_key",
+            ),
+            (
+                "[[servers]]\nspec = \"a.example:2002\"\npublic_key = \"abcd\"\n".to_string(),
+                "servers[0].public_key",
+            ),
+            (
+                "[[servers]]\nspec = \"a.example:2002\"\nburst = 0\n".to_string(),
+                "servers[0].burst",
+            ),
+            ("timeout_ms = 0\n".to_string(), "timeout_ms"),
+            (
+                "[rto]\nfloor_ms = 500\nceiling_ms = 100\n".to_string(),
+                "rto.ceiling_ms",
+            ),
+            ("[rate_limit]\nburst = 0\n".to_string(), "rate_limit.burst"),
+            ("bind = \"nowhere\"\n".to_string(), "bind"),
+            (
+                "paths = [\"v4\", \"carrier-pigeon\"]\n".to_string(),
+                "paths[1]",
+            ),
+            (
+                "[policy]\nmin_quorum = 3\n".to_string(),
+                "policy.min_quorum",
+            ),
+        ];
+        for (text, want) in cases {
+            let err = Config::from_toml(&text, "rt.toml").unwrap_err();
+            assert_eq!(origin(err), want, "{text}");
+        }
+    }
+
+    #[test]
+    fn min_quorum_counts_backups() {
+        let text = "[policy]\nmin_quorum = 3\n[[backups]]\nspec = \"c.example:2002\"\n";
+        let cfg = Config::from_toml(text, "rt.toml").unwrap();
+        assert_eq!(cfg.client().unwrap().options().min_quorum, Some(3));
+    }
+
+    #[test]
+    fn per_server_limits_and_keys_reach_the_client() {
+        let key = "803eb78528f749c4bec2e39e1abb9b5e5ab7e4dd5ce4b6f2fd2f93ecc3538f1a";
+        let text = format!(
+            "[[servers]]\nspec = \"a.example:2002\"\npublic_key = \"{key}\"\nburst = 2\n\
+             [[servers]]\nspec = \"sntp://b.example:123\"\n"
+        );
+        let cfg = Config::from_toml(&text, "rt.toml").unwrap();
+        assert_eq!(
+            cfg.public_keys()["a.example:2002"],
+            hex::decode(key).unwrap()
+        );
+        assert_eq!(cfg.public_keys().len(), 1);
+        let client = cfg.client().unwrap();
+        assert_eq!(client.sources().len(), 2);
+        assert!(client.rate_limiter().is_some());
+
+        let mut off = cfg.clone();
+        off.set("rate_limit.enabled", "false", "test").unwrap();
+        assert!(off.client().unwrap().rate_limiter().is_none());
+    }
+}
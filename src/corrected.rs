@@ -0,0 +1,159 @@
+//! Roughtime-corrected clock shared by the server modes.
+//!
+//! A background thread queries the beacons every `poll` and stores the
+//! latest offset estimate; readers get local time shifted by that offset,
+//! carried forward at the drift rate fitted over past rounds, together
+//! with a bound that grows with the estimate's age.
+
+use crate::{
+    client::TimestampClient, drift::DriftEstimator, fresh_nonce, log, TimestampError,
+    TimestampResponse,
+};
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Worst-case frequency error assumed between refreshes (NTP's PHI, 15 ppm).
+pub const PHI_PPM: u64 = 15;
+
+/// Latest beacon round, reduced to what a server needs.
+#[derive(Debug, Clone, Copy)]
+pub struct Estimate {
+    pub offset_us: i128,
+    pub uncert_us: u64,
+    pub min_rtt_us: u64,
+    pub at: Instant,
+    /// Offset gained per second after `at` (`drift::DriftEstimator`).
+    pub freq_ppm: f64,
+    /// Uncertainty of `freq_ppm`; widens the bound as the estimate ages.
+    pub skew_ppm: f64,
+}
+
+impl Estimate {
+    /// The round alone, without a drift rate.
+    pub fn from_response(resp: &TimestampResponse) -> Self {
+        Self {
+            offset_us: resp.offset_us(),
+            uncert_us: resp.metadata.combined_uncert_us(),
+            min_rtt_us: resp
+                .metadata
+                .beacons
+                .iter()
+                .map(|b| (b.rtt_ms * 1e3) as u64)
+                .min()
+                .unwrap_or(0),
+            at: Instant::now(),
+            freq_ppm: 0.0,
+            skew_ppm: 0.0,
+        }
+    }
+
+    /// Extrapolate with `drift`'s frequency.
+    pub fn with_drift(mut self, drift: &DriftEstimator) -> Self {
+        self.freq_ppm = drift.freq_ppm();
+        self.skew_ppm = drift.skew_ppm();
+        self
+    }
+}
+
+/// One corrected reading.
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub time: SystemTime,
+    pub uncert_us: u64,
+    pub age: Duration,
+    pub stale: bool,
+    pub min_rtt_us: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorrectedClock {
+    latest: Arc<RwLock<Option<Estimate>>>,
+    max_age: Duration,
+}
+
+impl CorrectedClock {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            latest: Arc::new(RwLock::new(None)),
+            max_age,
+        }
+    }
+
+    pub fn update(&self, est: Estimate) {
+        *self.latest.write().unwrap() = Some(est);
+    }
+
+    /// Take `resp` as the estimate, carried forward at `client`'s drift
+    /// rate.  For stamps served on request; periodic rounds use `refresh`.
+    pub fn observe(&self, resp: &TimestampResponse, client: &TimestampClient) {
+        let drift = client.drift().lock().unwrap();
+        self.update(Estimate::from_response(resp).with_drift(&drift));
+    }
+
+    /// As `observe`, for a periodic round: `resp` also feeds `client`'s
+    /// drift estimate, which is then saved to its drift file (if any).
+    /// The estimate is updated even when saving fails.
+    pub fn refresh(&self, resp: &TimestampResponse, client: &TimestampClient) -> io::Result<()> {
+        let mut drift = client.drift().lock().unwrap();
+        drift.add_response(resp);
+        self.update(Estimate::from_response(resp).with_drift(&drift));
+        drift.save()
+    }
+
+    pub fn estimate(&self) -> Option<Estimate> {
+        *self.latest.read().unwrap()
+    }
+
+    /// Corrected reading of `local`; `None` before the first beacon round.
+    pub fn at(&self, local: SystemTime) -> Option<Reading> {
+        Some(self.reading(&self.estimate()?, local))
+    }
+
+    /// Reading of `local` from a given estimate, so that several readings
+    /// can come from one snapshot even while the refresher updates.
+    pub fn reading(&self, est: &Estimate, local: SystemTime) -> Reading {
+        let age = est.at.elapsed();
+        let offset_us = est.offset_us as f64 + est.freq_ppm * age.as_secs_f64();
+        let mag = Duration::from_micros(offset_us.abs() as u64);
+        let time = if offset_us >= 0.0 { local + mag } else { local - mag };
+        let growth_ppm = PHI_PPM as f64 + est.skew_ppm;
+        Reading {
+            time,
+            uncert_us: est.uncert_us + (growth_ppm * age.as_secs_f64()).ceil() as u64,
+            age,
+            stale: age > self.max_age,
+            min_rtt_us: est.min_rtt_us,
+        }
+    }
+
+    pub fn now(&self) -> Option<Reading> {
+        self.at(SystemTime::now())
+    }
+
+    /// Keep the estimate fresh from `client`'s beacons every `poll`.
+    pub fn spawn_refresher(
+        &self,
+        client: TimestampClient,
+        poll: Duration,
+    ) -> thread::JoinHandle<()> {
+        let clock = self.clone();
+        thread::spawn(move || loop {
+            let round = fresh_nonce()
+                .map_err(TimestampError::from)
+                .and_then(|n| client.timestamp(n));
+            match round {
+                Ok(resp) => {
+                    if let Err(e) = clock.refresh(&resp, &client) {
+                        log::error!("refresh     : drift file not saved: {e}");
+                    }
+                }
+                Err(e) => log::error!("refresh     : beacon round failed: {e}"),
+            }
+            thread::sleep(poll);
+        })
+    }
+}
@@ -0,0 +1,283 @@
+//! Local oscillator drift-rate estimation with a chrony-style drift file.
+//!
+//! Each `get_timestamp` call yields one (local time, offset) pair.  A
+//! least-squares line through the recent pairs gives the frequency error of
+//! our clock in ppm (µs of offset gained per second), which lets us
+//! extrapolate corrected time between beacon queries.  The estimate survives
+//! restarts through a drift file holding `<freq_ppm> <skew_ppm>`.
+
+use crate::TimestampResponse;
+use std::{
+    collections::VecDeque,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Fewer samples than this and we keep using the frequency from the file.
+const MIN_FIT_SAMPLES: usize = 3;
+const DEFAULT_MAX_SAMPLES: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    local_us: u64,
+    offset_us: f64,
+}
+
+/// Tracks offset over time and fits its slope.
+///
+/// Sign convention follows `offset_us` (server − local): a positive
+/// `freq_ppm` means the local clock runs slow and falls further behind.
+#[derive(Debug, Clone)]
+pub struct DriftEstimator {
+    samples: VecDeque<Sample>,
+    max_samples: usize,
+    freq_ppm: f64,
+    skew_ppm: f64,
+    /// Centroid of the last fit, (local µs, offset µs): the fitted line
+    /// passes through it with slope `freq_ppm`.
+    centroid: Option<(f64, f64)>,
+    path: Option<PathBuf>,
+}
+
+impl Default for DriftEstimator {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_SAMPLES)
+    }
+}
+
+impl DriftEstimator {
+    pub fn new(max_samples: usize) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(max_samples),
+            max_samples: max_samples.max(MIN_FIT_SAMPLES),
+            freq_ppm: 0.0,
+            skew_ppm: 0.0,
+            centroid: None,
+            path: None,
+        }
+    }
+
+    /// Bind to a drift file, seeding the estimate from it when it exists.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut est = Self::default();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                let (freq, skew) = parse_drift_file(&text)?;
+                est.freq_ppm = freq;
+                est.skew_ppm = skew;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        est.path = Some(path);
+        Ok(est)
+    }
+
+    /// Write the current estimate to the drift file: temp file, fsync,
+    /// rename, fsync the directory.
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        let mut f = File::create(&tmp)?;
+        writeln!(f, "{:.6} {:.6}", self.freq_ppm, self.skew_ppm)?;
+        f.sync_all()?;
+        drop(f);
+        fs::rename(&tmp, path)?;
+        #[cfg(unix)]
+        {
+            let dir = match path.parent() {
+                Some(d) if !d.as_os_str().is_empty() => d,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Feed one measurement: our clock read `local`, the servers said we
+    /// were `offset_us` off.
+    pub fn add_sample(&mut self, local: SystemTime, offset_us: i128) {
+        let local_us = local
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        if self.samples.len() == self.max_samples {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Sample { local_us, offset_us: offset_us as f64 });
+        self.refit();
+    }
+
+    /// Feed a whole response (its measured anchored − local difference).
+    pub fn add_response(&mut self, resp: &TimestampResponse) {
+        let local = SystemTime::UNIX_EPOCH + Duration::from_micros(resp.local_timestamp);
+        self.add_sample(local, resp.offset_us());
+    }
+
+    pub fn freq_ppm(&self) -> f64 {
+        self.freq_ppm
+    }
+
+    /// Standard error of the slope, ppm.
+    pub fn skew_ppm(&self) -> f64 {
+        self.skew_ppm
+    }
+
+    pub fn num_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Offset expected at local time `t` on the fitted line, so one noisy
+    /// sample moves it by 1/n only.  Until there is a fit: the last
+    /// measured offset plus drift at the file's rate.  `None` until the
+    /// first sample.
+    pub fn offset_at(&self, t: SystemTime) -> Option<f64> {
+        let last = self.samples.back()?;
+        let (x0, y0) = self.centroid.unwrap_or((last.local_us as f64, last.offset_us));
+        let t_us = t
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_micros() as f64)
+            .unwrap_or(0.0);
+        Some(y0 + self.freq_ppm * (t_us - x0) / 1e6)
+    }
+
+    /// Growth of the extrapolation error at `t`, µs (skew × elapsed).
+    pub fn extrapolation_error_us(&self, t: SystemTime) -> Option<f64> {
+        let last = self.samples.back()?;
+        let last_t = SystemTime::UNIX_EPOCH + Duration::from_micros(last.local_us);
+        let dt_s = t.duration_since(last_t).unwrap_or_default().as_secs_f64();
+        Some(self.skew_ppm * dt_s)
+    }
+
+    /// Local time `t` corrected by the extrapolated offset.
+    pub fn corrected(&self, t: SystemTime) -> Option<SystemTime> {
+        let off = self.offset_at(t)?;
+        let mag = Duration::from_micros(off.abs() as u64);
+        Some(if off >= 0.0 { t + mag } else { t - mag })
+    }
+
+    pub fn corrected_now(&self) -> Option<SystemTime> {
+        self.corrected(SystemTime::now())
+    }
+
+    fn refit(&mut self) {
+        let n = self.samples.len();
+        if n < MIN_FIT_SAMPLES {
+            return;
+        }
+        // centre on the first sample to keep the sums well-conditioned
+        let t0 = self.samples[0].local_us as f64;
+        let xs: Vec<f64> = self.samples.iter().map(|s| (s.local_us as f64 - t0) / 1e6).collect();
+        let ys: Vec<f64> = self.samples.iter().map(|s| s.offset_us).collect();
+        let nf = n as f64;
+        let mx = xs.iter().sum::<f64>() / nf;
+        let my = ys.iter().sum::<f64>() / nf;
+        let sxx: f64 = xs.iter().map(|x| (x - mx).powi(2)).sum();
+        if sxx <= f64::EPSILON {
+            return; // all samples at the same instant
+        }
+        let sxy: f64 = xs.iter().zip(&ys).map(|(x, y)| (x - mx) * (y - my)).sum();
+        let slope = sxy / sxx;
+        let resid: f64 = xs
+            .iter()
+            .zip(&ys)
+            .map(|(x, y)| (y - (my + slope * (x - mx))).powi(2))
+            .sum();
+        self.freq_ppm = slope;
+        self.centroid = Some((t0 + mx * 1e6, my));
+        self.skew_ppm = (resid / (nf - 2.0).max(1.0) / sxx).sqrt();
+    }
+}
+
+fn parse_drift_file(text: &str) -> io::Result<(f64, f64)> {
+    let bad = || io::Error::new(io::ErrorKind::InvalidData, format!("bad drift file: {text:?}"));
+    let mut it = text.split_whitespace();
+    let freq = it.next().and_then(|v| v.parse().ok()).ok_or_else(bad)?;
+    let skew = match it.next() {
+        Some(v) => v.parse().map_err(|_| bad())?,
+        None => 0.0,
+    };
+    Ok((freq, skew))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + secs)
+    }
+
+    /// 250 µs at the start, gaining 2 ppm (2 µs per second).
+    fn on_line(secs: u64) -> i128 {
+        250 + 2 * secs as i128
+    }
+
+    #[test]
+    fn fit_recovers_the_frequency_of_a_clean_line() {
+        let mut d = DriftEstimator::default();
+        for s in (0..=600).step_by(60) {
+            d.add_sample(at(s), on_line(s));
+        }
+        assert!((d.freq_ppm() - 2.0).abs() < 1e-9, "{}", d.freq_ppm());
+        assert!(d.skew_ppm() < 1e-9);
+        assert!((d.offset_at(at(1_000)).unwrap() - on_line(1_000) as f64).abs() < 1e-6);
+    }
+
+    #[test]
+    fn one_noisy_sample_does_not_carry_the_prediction() {
+        let mut d = DriftEstimator::default();
+        for s in (0..600).step_by(60) {
+            d.add_sample(at(s), on_line(s));
+        }
+        d.add_sample(at(600), on_line(600) + 1_100);
+        let predicted = d.offset_at(at(600)).unwrap();
+        let miss = predicted - on_line(600) as f64;
+        // the last raw sample would be 1100 µs out; the line moves far less
+        assert!(miss > 0.0 && miss < 500.0, "{miss}");
+        assert!(d.skew_ppm() > 0.0);
+    }
+
+    #[test]
+    fn before_a_fit_the_file_rate_extrapolates_from_the_last_sample() {
+        let mut d = DriftEstimator { freq_ppm: -5.0, ..Default::default() };
+        assert_eq!(d.offset_at(at(0)), None);
+        d.add_sample(at(0), 1_000);
+        d.add_sample(at(100), 400);
+        assert_eq!(d.freq_ppm(), -5.0);
+        assert_eq!(d.offset_at(at(200)), Some(400.0 - 500.0));
+    }
+
+    #[test]
+    fn drift_file_round_trips() {
+        let path = std::env::temp_dir().join(format!("rt_ping-drift-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut d = DriftEstimator::open(&path).unwrap();
+        assert_eq!(d.freq_ppm(), 0.0);
+        for s in [0, 10, 20, 31] {
+            d.add_sample(at(s), on_line(s));
+        }
+        d.save().unwrap();
+
+        let again = DriftEstimator::open(&path).unwrap();
+        assert!((again.freq_ppm() - d.freq_ppm()).abs() < 1e-6);
+        assert!((again.skew_ppm() - d.skew_ppm()).abs() < 1e-6);
+        assert_eq!(again.num_samples(), 0);
+        assert!(!path.with_extension("tmp").exists());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn drift_file_parsing() {
+        assert_eq!(parse_drift_file("-12.5 0.25\n").unwrap(), (-12.5, 0.25));
+        assert_eq!(parse_drift_file("3.0").unwrap(), (3.0, 0.0));
+        assert!(parse_drift_file("").is_err());
+        assert!(parse_drift_file("fast 1").is_err());
+        assert!(parse_drift_file("1.0 wobbly").is_err());
+    }
+}
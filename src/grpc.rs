@@ -0,0 +1,785 @@
+//! gRPC front end (feature `grpc`), the typed sibling of `http`.
+//!
+//! Service `rt_ping.v1.Timestamper` from `proto/rt_ping.proto`:
+//! `Timestamp`, `BatchTimestamp` (one beacon round over a Merkle root,
+//! see `batch`), `Verify`, `GetTime`, and `StreamTimestamps`.  Unary
+//! `Timestamp` calls and every stream's hashes go into one shared
+//! `batch::Batcher`; each hash is answered with its inclusion once its
+//! batch has been stamped.  At most
+//! `stream_window` hashes per stream are outstanding; beyond that the
+//! stream is not read.
+//!
+//! Beacon rounds are blocking, so they run on tokio's blocking pool.
+
+use crate::{
+    batch::{self, BatchConfig, BatchError, Batcher, Inclusion, Stamped},
+    client::TimestampClient,
+    corrected::CorrectedClock,
+    fresh_nonce, log, multipath,
+    proof::{self, Proof},
+    shutdown::ShutdownHandle,
+    source::Evidence,
+    wire::Version,
+    BeaconKind, BeaconMeta, Metadata, TimestampBasis, TimestampError, TimestampResponse,
+    TimingSource,
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Generated messages and service traits.
+pub mod pb {
+    tonic::include_proto!("rt_ping.v1");
+}
+
+use pb::timestamper_server::{Timestamper, TimestamperServer};
+
+/// Shutdown poll interval, as in `http`.
+const SHUTDOWN_POLL: Duration = Duration::from_millis(25);
+
+#[derive(Debug, Clone)]
+pub struct GrpcConfig {
+    pub bind: String,
+    /// Hashes per `BatchTimestamp` call.
+    pub max_batch: usize,
+    /// Shared by `Timestamp` and all `StreamTimestamps` calls.
+    pub batch: BatchConfig,
+    /// Unanswered hashes per stream.
+    pub stream_window: usize,
+    /// Decoded request size limit, bytes.
+    pub max_message: usize,
+    /// Background refresh of the `GetTime` estimate.
+    pub poll: Duration,
+    pub max_age: Duration,
+    /// Long-term keys by host, for `Verify`; a proof from a beacon
+    /// without one is not valid.
+    pub keys: HashMap<String, Vec<u8>>,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:50051".into(),
+            max_batch: 4096,
+            batch: BatchConfig::default(),
+            stream_window: 1024,
+            max_message: 4 * 1024 * 1024,
+            poll: Duration::from_secs(64),
+            max_age: Duration::from_secs(1024),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+struct Shared {
+    cfg: GrpcConfig,
+    client: TimestampClient,
+    clock: CorrectedClock,
+    batcher: Batcher,
+}
+
+#[derive(Clone)]
+struct Service(Arc<Shared>);
+
+/// Serve on `cfg.bind` until `stop` is shut down; in-flight calls finish
+/// first.  Builds its own tokio runtime, so callers stay synchronous.
+pub fn serve(
+    cfg: GrpcConfig,
+    client: TimestampClient,
+    stop: ShutdownHandle,
+) -> Result<(), TimestampError> {
+    let addr: SocketAddr = cfg
+        .bind
+        .parse()
+        .map_err(|e| TimestampError::Config(format!("grpc bind {:?}: {e}", cfg.bind)))?;
+    let max_message = cfg.max_message;
+    let shared = Arc::new(Shared {
+        clock: CorrectedClock::new(cfg.max_age),
+        batcher: Batcher::new(client.clone(), cfg.batch.clone()),
+        cfg,
+        client,
+    });
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    rt.block_on(async move {
+        tokio::spawn(refresh(shared.clone(), stop.clone()));
+        let svc = TimestamperServer::new(Service(shared)).max_decoding_message_size(max_message);
+        log::info!("grpc        : listening on {addr}");
+        tonic::transport::Server::builder()
+            .add_service(svc)
+            .serve_with_shutdown(addr, async move {
+                while !stop.is_shutdown() {
+                    tokio::time::sleep(SHUTDOWN_POLL).await;
+                }
+            })
+            .await
+            .map_err(|e| TimestampError::Io(io::Error::other(e.to_string())))
+    })
+}
+
+/// Keeps `GetTime` fresh between stamps; exits on shutdown.
+async fn refresh(shared: Arc<Shared>, stop: ShutdownHandle) {
+    while !stop.is_shutdown() {
+        let s = shared.clone();
+        let round = tokio::task::spawn_blocking(move || {
+            let resp = s.client.timestamp(fresh_nonce()?)?;
+            s.clock.refresh(&resp, &s.client)?;
+            Ok::<_, TimestampError>(())
+        })
+        .await;
+        if let Ok(Err(e)) = round {
+            log::error!("grpc        : refresh failed: {e}");
+        }
+        let until = tokio::time::Instant::now() + shared.cfg.poll;
+        while !stop.is_shutdown() && tokio::time::Instant::now() < until {
+            tokio::time::sleep(SHUTDOWN_POLL).await;
+        }
+    }
+}
+
+// -------------------------------------------------------------------------
+// Service
+
+#[tonic::async_trait]
+impl Timestamper for Service {
+    async fn timestamp(
+        &self,
+        req: Request<pb::TimestampRequest>,
+    ) -> Result<Response<pb::TimestampReply>, Status> {
+        let hash = to_hash(&req.into_inner().hash).ok_or_else(bad_hash)?;
+        let (tx, rx) = oneshot::channel();
+        let s = self.0.clone();
+        // blocks while the batcher's queue is full
+        tokio::task::spawn_blocking(move || {
+            s.batcher.submit_then(hash, s.cfg.batch.max_wait, move |r| {
+                let _ = tx.send(r);
+            })
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(batch_status)?;
+        let Stamped {
+            response,
+            inclusion,
+        } = rx
+            .await
+            .unwrap_or(Err(BatchError::Closed))
+            .map_err(batch_status)?;
+        self.0.clock.observe(&response, &self.0.client);
+        Ok(Response::new(pb::TimestampReply {
+            proof: Some((&Proof::from_response(&response)).into()),
+            response: Some((&*response).into()),
+            inclusion: Some((&inclusion).into()),
+        }))
+    }
+
+    async fn batch_timestamp(
+        &self,
+        req: Request<pb::BatchTimestampRequest>,
+    ) -> Result<Response<pb::BatchTimestampReply>, Status> {
+        let hashes = req
+            .into_inner()
+            .hashes
+            .iter()
+            .map(|h| to_hash(h))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(bad_hash)?;
+        if hashes.len() > self.0.cfg.max_batch {
+            return Err(Status::invalid_argument(format!(
+                "{} hashes, at most {} per batch",
+                hashes.len(),
+                self.0.cfg.max_batch
+            )));
+        }
+        let shared = self.0.clone();
+        let (resp, items) = blocking(move || batch::stamp_batch(&shared.client, &hashes)).await?;
+        self.0.clock.observe(&resp, &self.0.client);
+        Ok(Response::new(pb::BatchTimestampReply {
+            proof: Some((&Proof::from_response(&resp)).into()),
+            response: Some((&resp).into()),
+            items: items.iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn verify(
+        &self,
+        req: Request<pb::VerifyRequest>,
+    ) -> Result<Response<pb::VerifyReply>, Status> {
+        let req = req.into_inner();
+        let Some(proof) = req.proof else {
+            return Err(Status::invalid_argument("no proof"));
+        };
+        let reply = match check(&req.hash, &proof, req.inclusion.as_ref())
+            .and_then(|p| p.verify(&self.0.cfg.keys))
+        {
+            Ok(beacons) => pb::VerifyReply {
+                valid: true,
+                error: String::new(),
+                beacons: beacons.iter().map(Into::into).collect(),
+            },
+            Err(e) => pb::VerifyReply {
+                valid: false,
+                error: e.to_string(),
+                beacons: Vec::new(),
+            },
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn get_time(
+        &self,
+        _: Request<pb::GetTimeRequest>,
+    ) -> Result<Response<pb::GetTimeReply>, Status> {
+        let Some(r) = self.0.clock.now() else {
+            return Err(Status::unavailable("no beacon round yet"));
+        };
+        Ok(Response::new(pb::GetTimeReply {
+            time_us: micros(r.time),
+            local_us: micros(SystemTime::now()),
+            uncert_us: r.uncert_us,
+            age_ms: r.age.as_millis() as u64,
+            stale: r.stale,
+        }))
+    }
+
+    type StreamTimestampsStream = ReceiverStream<Result<pb::StreamReply, Status>>;
+
+    async fn stream_timestamps(
+        &self,
+        req: Request<Streaming<pb::StreamRequest>>,
+    ) -> Result<Response<Self::StreamTimestampsStream>, Status> {
+        let inbound = req.into_inner();
+        // bounded by the window: a slow reader stops us reading more hashes
+        let (tx, rx) = mpsc::channel(self.0.cfg.stream_window.max(1));
+        tokio::spawn(stream_batches(self.0.clone(), inbound, tx));
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+type Outbound = mpsc::Sender<Result<pb::StreamReply, Status>>;
+
+/// A finished hash, holding its place in the stream's window until sent.
+type Done = (
+    u64,
+    [u8; 32],
+    Result<Stamped, BatchError>,
+    OwnedSemaphorePermit,
+);
+
+/// Submit every hash to the batcher; answers go out as batches finish.
+async fn stream_batches(
+    shared: Arc<Shared>,
+    mut inbound: Streaming<pb::StreamRequest>,
+    tx: Outbound,
+) {
+    let window = Arc::new(Semaphore::new(shared.cfg.stream_window.max(1)));
+    let (done_tx, done_rx) = mpsc::unbounded_channel::<Done>();
+    let forward = tokio::spawn(forward(shared.clone(), done_rx, tx.clone()));
+    loop {
+        let m = match inbound.message().await {
+            Ok(Some(m)) => m,
+            Ok(None) => break,
+            Err(s) => {
+                let _ = tx.send(Err(s)).await;
+                break;
+            }
+        };
+        let Ok(permit) = window.clone().acquire_owned().await else {
+            break;
+        };
+        let Some(hash) = to_hash(&m.hash) else {
+            let reply = pb::StreamReply {
+                id: m.id,
+                hash: m.hash,
+                error: bad_hash().message().to_string(),
+                ..Default::default()
+            };
+            if tx.send(Ok(reply)).await.is_err() {
+                break;
+            }
+            continue;
+        };
+        let (s, done, id) = (shared.clone(), done_tx.clone(), m.id);
+        // blocks while the batcher's queue is full
+        let submitted = tokio::task::spawn_blocking(move || {
+            s.batcher.submit_then(hash, s.cfg.batch.max_wait, move |r| {
+                let _ = done.send((id, hash, r, permit));
+            })
+        })
+        .await;
+        if !matches!(submitted, Ok(Ok(()))) {
+            let _ = tx.send(Err(Status::unavailable("batcher stopped"))).await;
+            break;
+        }
+    }
+    drop(done_tx);
+    let _ = forward.await;
+}
+
+/// Turn finished hashes into replies, converting each batch's response
+/// once.
+async fn forward(shared: Arc<Shared>, mut done: mpsc::UnboundedReceiver<Done>, tx: Outbound) {
+    let mut last: Option<(Arc<TimestampResponse>, pb::TimestampResponse, pb::Proof)> = None;
+    while let Some((id, hash, result, _permit)) = done.recv().await {
+        let reply = match result {
+            Ok(Stamped {
+                response,
+                inclusion,
+            }) => {
+                if !last
+                    .as_ref()
+                    .is_some_and(|(r, ..)| Arc::ptr_eq(r, &response))
+                {
+                    shared.clock.observe(&response, &shared.client);
+                    let converted = (&*response).into();
+                    let proof = (&Proof::from_response(&response)).into();
+                    last = Some((response, converted, proof));
+                }
+                let (_, converted, proof) = last.as_ref().unwrap();
+                pb::StreamReply {
+                    id,
+                    hash: hash.to_vec(),
+                    inclusion: Some((&inclusion).into()),
+                    response: Some(converted.clone()),
+                    proof: Some(proof.clone()),
+                    error: String::new(),
+                }
+            }
+            Err(e) => pb::StreamReply {
+                id,
+                hash: hash.to_vec(),
+                error: e.to_string(),
+                ..Default::default()
+            },
+        };
+        if tx.send(Ok(reply)).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Run a beacon round on the blocking pool.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, TimestampError> + Send + 'static,
+) -> Result<T, Status> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(status)
+}
+
+fn status(e: TimestampError) -> Status {
+    match e {
+        // the guard refused: our time is unusable, not the request
+        TimestampError::Unsafe(_) => Status::unavailable(e.to_string()),
+        TimestampError::Budget { .. } => Status::deadline_exceeded(e.to_string()),
+        TimestampError::Config(_) => Status::invalid_argument(e.to_string()),
+        _ => Status::unavailable(e.to_string()),
+    }
+}
+
+fn batch_status(e: BatchError) -> Status {
+    match e {
+        BatchError::Failed(inner) => match &*inner {
+            TimestampError::Budget { .. } => Status::deadline_exceeded(inner.to_string()),
+            _ => Status::unavailable(inner.to_string()),
+        },
+        BatchError::Full(_) => Status::resource_exhausted(e.to_string()),
+        BatchError::Timeout(_) => Status::deadline_exceeded(e.to_string()),
+        BatchError::Closed => Status::unavailable(e.to_string()),
+    }
+}
+
+fn to_hash(b: &[u8]) -> Option<[u8; 32]> {
+    <[u8; 32]>::try_from(b).ok()
+}
+
+fn bad_hash() -> Status {
+    Status::invalid_argument("hash must be 32 bytes")
+}
+
+fn micros(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/// The library proof `req` asks about, after the batch step if any.
+fn check(
+    hash: &[u8],
+    proof: &pb::Proof,
+    inclusion: Option<&pb::Inclusion>,
+) -> Result<Proof, TimestampError> {
+    let proof = Proof::try_from(proof)?;
+    let stamped = match inclusion {
+        Some(i) => {
+            let incl = Inclusion::from(i);
+            if !incl.verify(hash) {
+                return Err(TimestampError::BadProof("hash is not in the batch".into()));
+            }
+            incl.root
+        }
+        None if hash.is_empty() => return Ok(proof),
+        None => hex::encode(hash),
+    };
+    if !stamped.eq_ignore_ascii_case(&proof.hash) {
+        return Err(TimestampError::BadProof(
+            "proof is for a different hash".into(),
+        ));
+    }
+    Ok(proof)
+}
+
+// -------------------------------------------------------------------------
+// Conversions
+
+impl From<&TimestampResponse> for pb::TimestampResponse {
+    fn from(r: &TimestampResponse) -> Self {
+        Self {
+            input_hash: hex::decode(&r.input_hash).unwrap_or_default(),
+            timestamp: r.timestamp,
+            basis: match r.basis {
+                TimestampBasis::MedianMidpoint => pb::TimestampBasis::MedianMidpoint,
+                TimestampBasis::WeightedMidpoint => pb::TimestampBasis::WeightedMidpoint,
+            } as i32,
+            local_timestamp: r.local_timestamp,
+            metadata: Some((&r.metadata).into()),
+        }
+    }
+}
+
+impl From<&Metadata> for pb::Metadata {
+    fn from(m: &Metadata) -> Self {
+        Self {
+            beacons: m.beacons.iter().map(Into::into).collect(),
+            cross_checks: m.cross_checks.iter().map(Into::into).collect(),
+            drift_us: m.drift_us,
+            cross_check_delta_us: m.cross_check_delta_us,
+            hedged: m.hedged,
+            bumped_us: m.bumped_us,
+        }
+    }
+}
+
+impl From<&BeaconMeta> for pb::BeaconMeta {
+    fn from(b: &BeaconMeta) -> Self {
+        Self {
+            host: b.host.clone(),
+            rtt_ms: b.rtt_ms,
+            true_time_us: micros(b.true_time),
+            midpoint_us: b.midpoint_us,
+            offset_us: b.offset_us as i64,
+            uncert_us: b.uncert_us as i64,
+            radius_us: b.radius_us,
+            timing: match b.timing {
+                TimingSource::UserSpace => pb::TimingSource::UserSpace,
+                TimingSource::KernelRx => pb::TimingSource::KernelRx,
+                TimingSource::Kernel => pb::TimingSource::Kernel,
+            } as i32,
+            kind: match b.kind {
+                BeaconKind::Roughtime => pb::BeaconKind::Roughtime,
+                BeaconKind::Sntp => pb::BeaconKind::Sntp,
+                BeaconKind::LocalClock => pb::BeaconKind::LocalClock,
+                BeaconKind::Mock => pb::BeaconKind::Mock,
+                BeaconKind::Custom => pb::BeaconKind::Custom,
+            } as i32,
+            authenticated: b.authenticated,
+            evidence: evidence(&b.evidence),
+            addr: b.addr.map(|a| a.to_string()).unwrap_or_default(),
+            asymmetry_us: b.asymmetry_us,
+            paths: b.paths.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<&multipath::PathSample> for pb::PathSample {
+    fn from(p: &multipath::PathSample) -> Self {
+        Self {
+            path: p.path.clone(),
+            addr: p.addr.map(|a| a.to_string()).unwrap_or_default(),
+            rtt_ms: p.rtt_ms,
+            offset_us: p.offset_us as i64,
+            uncert_us: p.uncert_us as i64,
+        }
+    }
+}
+
+fn evidence(e: &Evidence) -> Option<pb::Evidence> {
+    let kind = match e {
+        Evidence::None => return None,
+        Evidence::Roughtime { .. } => pb::evidence::Kind::Roughtime(roughtime_evidence(e)?),
+        Evidence::Custom(bytes) => {
+            pb::evidence::Kind::Custom(hex::decode(bytes).unwrap_or_default())
+        }
+    };
+    Some(pb::Evidence { kind: Some(kind) })
+}
+
+fn roughtime_evidence(e: &Evidence) -> Option<pb::RoughtimeEvidence> {
+    let Evidence::Roughtime {
+        version,
+        nonce,
+        reply,
+    } = e
+    else {
+        return None;
+    };
+    Some(pb::RoughtimeEvidence {
+        version: pb::WireVersion::from(*version) as i32,
+        nonce: hex::decode(nonce).ok()?,
+        reply: hex::decode(reply).ok()?,
+    })
+}
+
+impl From<Version> for pb::WireVersion {
+    fn from(v: Version) -> Self {
+        match v {
+            Version::Classic => pb::WireVersion::Classic,
+            Version::Ietf => pb::WireVersion::Ietf,
+        }
+    }
+}
+
+impl From<&Proof> for pb::Proof {
+    fn from(p: &Proof) -> Self {
+        Self {
+            hash: hex::decode(&p.hash).unwrap_or_default(),
+            beacons: p
+                .beacons
+                .iter()
+                .map(|b| pb::ProofBeacon {
+                    host: b.host.clone(),
+                    evidence: roughtime_evidence(&b.evidence),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<&pb::Proof> for Proof {
+    type Error = TimestampError;
+
+    fn try_from(p: &pb::Proof) -> Result<Self, TimestampError> {
+        let beacons = p
+            .beacons
+            .iter()
+            .map(|b| {
+                let e = b.evidence.as_ref().ok_or_else(|| {
+                    TimestampError::BadProof(format!("{}: no signed reply", b.host))
+                })?;
+                let version = match pb::WireVersion::try_from(e.version) {
+                    Ok(pb::WireVersion::Classic) => Version::Classic,
+                    Ok(pb::WireVersion::Ietf) => Version::Ietf,
+                    Err(_) => {
+                        return Err(TimestampError::BadProof(format!(
+                            "{}: unknown wire version {}",
+                            b.host, e.version
+                        )))
+                    }
+                };
+                Ok(proof::ProofBeacon {
+                    host: b.host.clone(),
+                    evidence: Evidence::Roughtime {
+                        version,
+                        nonce: hex::encode(&e.nonce),
+                        reply: hex::encode(&e.reply),
+                    },
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Proof {
+            hash: hex::encode(&p.hash),
+            beacons,
+        })
+    }
+}
+
+impl From<&proof::VerifiedBeacon> for pb::VerifiedBeacon {
+    fn from(b: &proof::VerifiedBeacon) -> Self {
+        Self {
+            host: b.host.clone(),
+            version: pb::WireVersion::from(b.version) as i32,
+            midpoint_us: b.midpoint_us,
+            radius_us: b.radius_us,
+        }
+    }
+}
+
+impl From<&Inclusion> for pb::Inclusion {
+    fn from(i: &Inclusion) -> Self {
+        Self {
+            index: i.index,
+            path: hex::decode(&i.path).unwrap_or_default(),
+            root: hex::decode(&i.root).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<&pb::Inclusion> for Inclusion {
+    fn from(i: &pb::Inclusion) -> Self {
+        Self {
+            index: i.index,
+            path: hex::encode(&i.path),
+            root: hex::encode(&i.root),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MockSource;
+    use pb::timestamper_client::TimestamperClient;
+    use std::{net::TcpListener, thread};
+
+    /// `serve` on a free loopback port; returns the endpoint and the stop
+    /// handle.
+    fn start(cfg: GrpcConfig) -> (String, ShutdownHandle, thread::JoinHandle<()>) {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = TimestampClient::builder()
+            .source(Arc::new(MockSource::new("a", 1_000)))
+            .source(Arc::new(MockSource::new("b", 1_200)))
+            .build()
+            .unwrap();
+        let stop = ShutdownHandle::default();
+        let cfg = GrpcConfig {
+            bind: format!("127.0.0.1:{port}"),
+            ..cfg
+        };
+        let handle = stop.clone();
+        let server = thread::spawn(move || serve(cfg, client, handle).unwrap());
+        (format!("http://127.0.0.1:{port}"), stop, server)
+    }
+
+    async fn connect(endpoint: String) -> TimestamperClient<tonic::transport::Channel> {
+        for _ in 0..100 {
+            if let Ok(c) = TimestamperClient::connect(endpoint.clone()).await {
+                return c;
+            }
+            tokio::time::sleep(SHUTDOWN_POLL).await;
+        }
+        panic!("no server on {endpoint}");
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn timestamp_and_verify_round_trip() {
+        let (endpoint, stop, server) = start(GrpcConfig::default());
+        runtime().block_on(async {
+            let mut c = connect(endpoint).await;
+            let reply = c
+                .timestamp(pb::TimestampRequest { hash: vec![7; 32] })
+                .await
+                .unwrap()
+                .into_inner();
+            let resp = reply.response.unwrap();
+            let inclusion = Inclusion::from(&reply.inclusion.clone().unwrap());
+            assert!(inclusion.verify(&[7; 32]));
+            assert_eq!(resp.input_hash, hex::decode(&inclusion.root).unwrap());
+            assert_eq!(resp.metadata.unwrap().beacons.len(), 2);
+
+            let bad = c
+                .timestamp(pb::TimestampRequest { hash: vec![7; 31] })
+                .await;
+            assert_eq!(bad.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+            // mocks sign nothing: the proof must not pass
+            let verdict = c
+                .verify(pb::VerifyRequest {
+                    hash: vec![7; 32],
+                    proof: reply.proof,
+                    inclusion: reply.inclusion,
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert!(!verdict.valid);
+        });
+        stop.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn concurrent_unary_stamps_share_one_beacon_round() {
+        let cfg = GrpcConfig {
+            batch: BatchConfig {
+                max_items: 2,
+                max_wait: Duration::from_secs(5),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (endpoint, stop, server) = start(cfg);
+        runtime().block_on(async {
+            let (mut a, mut b) = (connect(endpoint.clone()).await, connect(endpoint).await);
+            // sealed by size, long before the deadline
+            let (ra, rb) = tokio::join!(
+                a.timestamp(pb::TimestampRequest { hash: vec![1; 32] }),
+                b.timestamp(pb::TimestampRequest { hash: vec![2; 32] }),
+            );
+            let (ra, rb) = (ra.unwrap().into_inner(), rb.unwrap().into_inner());
+            let (ia, ib) = (
+                Inclusion::from(&ra.inclusion.unwrap()),
+                Inclusion::from(&rb.inclusion.unwrap()),
+            );
+            assert!(ia.verify(&[1; 32]) && ib.verify(&[2; 32]));
+            assert_eq!(ia.root, ib.root);
+            assert_ne!(ia.index, ib.index);
+        });
+        stop.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn streams_more_hashes_than_the_window() {
+        let cfg = GrpcConfig {
+            stream_window: 2,
+            batch: BatchConfig {
+                max_items: 4,
+                max_wait: Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (endpoint, stop, server) = start(cfg);
+        runtime().block_on(async {
+            let mut c = connect(endpoint).await;
+            let requests = (0..10u8).map(|i| pb::StreamRequest {
+                id: i as u64,
+                hash: vec![i; 32],
+            });
+            let mut replies = c
+                .stream_timestamps(tokio_stream::iter(requests))
+                .await
+                .unwrap()
+                .into_inner();
+            let mut ids = Vec::new();
+            while let Some(r) = replies.message().await.unwrap() {
+                assert!(r.error.is_empty(), "{}", r.error);
+                assert_eq!(r.hash, vec![r.id as u8; 32]);
+                assert!(r.inclusion.is_some());
+                ids.push(r.id);
+            }
+            ids.sort();
+            assert_eq!(ids, (0..10).collect::<Vec<_>>());
+        });
+        stop.shutdown();
+        server.join().unwrap();
+    }
+}
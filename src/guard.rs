@@ -0,0 +1,515 @@
+//! Clock guard: refuse timestamps built on an unsafe time estimate.
+//!
+//! A sequencer must not issue stamps while our clock is far off, the
+//! beacons disagree, the bound is wide or the estimate is old.
+//! `ClockGuard::admit` checks a response against `GuardPolicy` and turns a
+//! violation into `TimestampError::Unsafe`.  The guard also keeps a
+//! halt/resume signal: `is_safe` and `status` for polling, `subscribe` for
+//! a channel that sees every transition.  Failed queries count as unsafe
+//! too, and the signal drops to `Halt` once the last good stamp is older
+//! than `max_staleness` (noticed on the next `admit`, `status` or
+//! `is_safe`, so a sequencer should check before every stamp).
+
+use crate::{
+    clock::{Clock, SystemClock},
+    TimestampError, TimestampResponse,
+};
+use std::{
+    fmt,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone)]
+pub struct GuardPolicy {
+    /// Allowed |anchored timestamp − local clock|.
+    pub max_offset: Duration,
+    /// Allowed offset spread between quorum beacons (`Metadata::drift_us`).
+    pub max_drift: Duration,
+    /// Allowed `Metadata::combined_uncert_us`.
+    pub max_uncert: Duration,
+    /// Allowed age of the measurement, and of the last good stamp.
+    pub max_staleness: Duration,
+}
+
+impl Default for GuardPolicy {
+    fn default() -> Self {
+        Self {
+            max_offset: Duration::from_millis(100),
+            max_drift: Duration::from_millis(50),
+            max_uncert: Duration::from_millis(50),
+            max_staleness: Duration::from_secs(5),
+        }
+    }
+}
+
+/// One limit a response broke (µs).
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub enum Violation {
+    Offset {
+        offset_us: i128,
+        limit_us: u64,
+    },
+    Drift {
+        drift_us: u64,
+        limit_us: u64,
+    },
+    Uncertainty {
+        uncert_us: u64,
+        limit_us: u64,
+    },
+    Stale {
+        age_us: u64,
+        limit_us: u64,
+    },
+    /// The local clock reads earlier than when the response was measured:
+    /// it stepped back, so the measured offset no longer describes it.
+    StepBack {
+        step_us: u64,
+        limit_us: u64,
+    },
+    /// The query itself failed.
+    Failed(String),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Offset {
+                offset_us,
+                limit_us,
+            } => {
+                write!(f, "offset {offset_us:+} µs beyond ±{limit_us} µs")
+            }
+            Violation::Drift { drift_us, limit_us } => {
+                write!(f, "beacons disagree by {drift_us} µs (limit {limit_us} µs)")
+            }
+            Violation::Uncertainty {
+                uncert_us,
+                limit_us,
+            } => write!(f, "uncertainty ±{uncert_us} µs (limit ±{limit_us} µs)"),
+            Violation::Stale { age_us, limit_us } => {
+                write!(f, "estimate {age_us} µs old (limit {limit_us} µs)")
+            }
+            Violation::StepBack { step_us, limit_us } => {
+                write!(
+                    f,
+                    "local clock stepped back {step_us} µs (limit {limit_us} µs)"
+                )
+            }
+            Violation::Failed(e) => write!(f, "query failed: {e}"),
+        }
+    }
+}
+
+/// What the sequencer acts on.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub enum GuardSignal {
+    Safe,
+    Halt(Vec<Violation>),
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct GuardStatus {
+    pub signal: GuardSignal,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub consecutive_rejects: u64,
+    /// Age of the last admitted stamp.
+    pub since_last_ok_ms: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct GuardState {
+    /// Last signal published to subscribers.
+    signal: Option<GuardSignal>,
+    violations: Vec<Violation>,
+    accepted: u64,
+    rejected: u64,
+    consecutive_rejects: u64,
+    last_ok: Option<Instant>,
+    subscribers: Vec<mpsc::Sender<GuardSignal>>,
+}
+
+#[derive(Debug)]
+pub struct ClockGuard {
+    policy: GuardPolicy,
+    clock: Arc<dyn Clock>,
+    state: Mutex<GuardState>,
+}
+
+impl ClockGuard {
+    pub fn new(policy: GuardPolicy) -> Self {
+        Self::with_clock(policy, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(policy: GuardPolicy, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            policy,
+            clock,
+            state: Mutex::new(GuardState::default()),
+        }
+    }
+
+    pub fn policy(&self) -> &GuardPolicy {
+        &self.policy
+    }
+
+    /// Limits `resp` breaks; empty when it is safe to stamp with.
+    pub fn violations(&self, resp: &TimestampResponse) -> Vec<Violation> {
+        let p = &self.policy;
+        let limit = |d: Duration| d.as_micros().min(u64::MAX as u128) as u64;
+        let mut out = Vec::new();
+
+        let offset_us = resp.offset_us();
+        if offset_us.unsigned_abs() > limit(p.max_offset) as u128 {
+            out.push(Violation::Offset {
+                offset_us,
+                limit_us: limit(p.max_offset),
+            });
+        }
+        let drift_us = resp.metadata.drift_us;
+        if drift_us > limit(p.max_drift) {
+            out.push(Violation::Drift {
+                drift_us,
+                limit_us: limit(p.max_drift),
+            });
+        }
+        let uncert_us = resp.metadata.combined_uncert_us();
+        if uncert_us > limit(p.max_uncert) {
+            out.push(Violation::Uncertainty {
+                uncert_us,
+                limit_us: limit(p.max_uncert),
+            });
+        }
+        let now_us = self
+            .clock
+            .wall()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let age_us = now_us.saturating_sub(resp.local_timestamp);
+        if age_us > limit(p.max_staleness) {
+            out.push(Violation::Stale {
+                age_us,
+                limit_us: limit(p.max_staleness),
+            });
+        }
+        // a step back adds to how far off the local clock now is
+        let step_us = resp.local_timestamp.saturating_sub(now_us);
+        if step_us > limit(p.max_offset) {
+            out.push(Violation::StepBack {
+                step_us,
+                limit_us: limit(p.max_offset),
+            });
+        }
+        out
+    }
+
+    /// Pass a query result through the guard: unsafe responses become
+    /// `TimestampError::Unsafe`, and the outcome updates the signal.
+    pub fn admit(
+        &self,
+        res: Result<TimestampResponse, TimestampError>,
+    ) -> Result<TimestampResponse, TimestampError> {
+        let violations = match &res {
+            Ok(resp) => self.violations(resp),
+            Err(e) => vec![Violation::Failed(e.to_string())],
+        };
+        let mut st = self.state.lock().unwrap();
+        if violations.is_empty() {
+            st.accepted += 1;
+            st.consecutive_rejects = 0;
+            st.last_ok = Some(self.clock.mono());
+            st.violations.clear();
+        } else {
+            st.rejected += 1;
+            st.consecutive_rejects += 1;
+            st.violations = violations.clone();
+        }
+        self.publish(&mut st);
+        drop(st);
+
+        match res {
+            Ok(_) if !violations.is_empty() => Err(TimestampError::Unsafe(violations)),
+            res => res,
+        }
+    }
+
+    /// Whether stamps may be issued right now.
+    pub fn is_safe(&self) -> bool {
+        self.status().signal == GuardSignal::Safe
+    }
+
+    /// Current signal; re-evaluates staleness of the last good stamp.
+    pub fn status(&self) -> GuardStatus {
+        let mut st = self.state.lock().unwrap();
+        let signal = self.publish(&mut st);
+        GuardStatus {
+            signal,
+            accepted: st.accepted,
+            rejected: st.rejected,
+            consecutive_rejects: st.consecutive_rejects,
+            since_last_ok_ms: st
+                .last_ok
+                .map(|t| (self.clock.mono() - t).as_millis() as u64),
+        }
+    }
+
+    /// Channel that receives the current signal, then every change.
+    pub fn subscribe(&self) -> mpsc::Receiver<GuardSignal> {
+        let (tx, rx) = mpsc::channel();
+        let mut st = self.state.lock().unwrap();
+        let signal = self.publish(&mut st);
+        let _ = tx.send(signal);
+        st.subscribers.push(tx);
+        rx
+    }
+
+    /// Work out the signal and tell subscribers if it changed.
+    fn publish(&self, st: &mut GuardState) -> GuardSignal {
+        let signal = if !st.violations.is_empty() {
+            GuardSignal::Halt(st.violations.clone())
+        } else {
+            match st.last_ok {
+                Some(t) if self.clock.mono() - t <= self.policy.max_staleness => GuardSignal::Safe,
+                Some(t) => GuardSignal::Halt(vec![Violation::Stale {
+                    age_us: (self.clock.mono() - t).as_micros() as u64,
+                    limit_us: self.policy.max_staleness.as_micros() as u64,
+                }]),
+                // nothing admitted yet
+                None => GuardSignal::Halt(Vec::new()),
+            }
+        };
+        let changed = !matches!(
+            (&st.signal, &signal),
+            (Some(GuardSignal::Safe), GuardSignal::Safe)
+                | (Some(GuardSignal::Halt(_)), GuardSignal::Halt(_))
+        );
+        if changed {
+            st.signal = Some(signal.clone());
+            st.subscribers.retain(|tx| tx.send(signal.clone()).is_ok());
+        }
+        signal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{MockSource, TimeSource};
+    use crate::{sys_to_us, Metadata, ProbeOptions, TimestampBasis};
+    use std::time::SystemTime;
+
+    /// Wall clock that can be stepped without moving the monotonic one.
+    #[derive(Debug)]
+    struct StepClock {
+        wall: Mutex<SystemTime>,
+        mono: Mutex<Instant>,
+    }
+
+    impl StepClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                wall: Mutex::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+                mono: Mutex::new(Instant::now()),
+            })
+        }
+
+        fn advance(&self, d: Duration) {
+            *self.wall.lock().unwrap() += d;
+            *self.mono.lock().unwrap() += d;
+        }
+
+        fn step_back(&self, d: Duration) {
+            *self.wall.lock().unwrap() -= d;
+        }
+
+        fn now_us(&self) -> u64 {
+            sys_to_us(self.wall())
+        }
+    }
+
+    impl Clock for StepClock {
+        fn wall(&self) -> SystemTime {
+            *self.wall.lock().unwrap()
+        }
+
+        fn mono(&self) -> Instant {
+            *self.mono.lock().unwrap()
+        }
+    }
+
+    /// One beacon's worth of response, measured at `local_us`.
+    fn response(
+        local_us: u64,
+        offset_us: i128,
+        drift_us: u64,
+        uncert_us: i128,
+    ) -> TimestampResponse {
+        let mut mock = MockSource::new("beacon", offset_us);
+        mock.rtt = Duration::ZERO;
+        mock.uncert_us = uncert_us as u64;
+        let b = mock.query(&[0; 32], &ProbeOptions::default()).unwrap();
+        TimestampResponse {
+            input_hash: String::new(),
+            timestamp: (local_us as i128 + offset_us) as u64,
+            basis: TimestampBasis::MedianMidpoint,
+            local_timestamp: local_us,
+            metadata: Metadata {
+                beacons: vec![b],
+                cross_checks: Vec::new(),
+                drift_us,
+                cross_check_delta_us: None,
+                hedged: 0,
+                bumped_us: 0,
+            },
+        }
+    }
+
+    fn guard() -> (Arc<StepClock>, ClockGuard) {
+        let clock = StepClock::new();
+        let guard = ClockGuard::with_clock(GuardPolicy::default(), clock.clone());
+        (clock, guard)
+    }
+
+    #[test]
+    fn each_limit_holds_up_to_and_including_its_value() {
+        let (clock, guard) = guard();
+        let now = clock.now_us();
+        assert!(guard
+            .violations(&response(now, -100_000, 50_000, 50_000))
+            .is_empty());
+
+        let v = guard.violations(&response(now, 100_001, 0, 1_000));
+        assert_eq!(
+            v,
+            [Violation::Offset {
+                offset_us: 100_001,
+                limit_us: 100_000
+            }]
+        );
+        let v = guard.violations(&response(now, 0, 50_001, 1_000));
+        assert_eq!(
+            v,
+            [Violation::Drift {
+                drift_us: 50_001,
+                limit_us: 50_000
+            }]
+        );
+        let v = guard.violations(&response(now, 0, 0, 50_001));
+        assert_eq!(
+            v,
+            [Violation::Uncertainty {
+                uncert_us: 50_001,
+                limit_us: 50_000
+            }]
+        );
+
+        let measured = response(now, 0, 0, 1_000);
+        clock.advance(Duration::from_secs(5));
+        assert!(guard.violations(&measured).is_empty());
+        clock.advance(Duration::from_micros(1));
+        assert_eq!(
+            guard.violations(&measured),
+            [Violation::Stale {
+                age_us: 5_000_001,
+                limit_us: 5_000_000
+            }]
+        );
+    }
+
+    #[test]
+    fn an_unsafe_response_is_refused_with_every_violation() {
+        let (clock, guard) = guard();
+        let err = guard
+            .admit(Ok(response(clock.now_us(), 250_000, 80_000, 1_000)))
+            .unwrap_err();
+        let TimestampError::Unsafe(v) = err else {
+            panic!("{err}")
+        };
+        assert_eq!(v.len(), 2);
+        assert!(matches!(v[0], Violation::Offset { .. }));
+        assert!(matches!(v[1], Violation::Drift { .. }));
+        let st = guard.status();
+        assert_eq!(
+            (st.accepted, st.rejected, st.consecutive_rejects),
+            (0, 1, 1)
+        );
+        assert_eq!(st.signal, GuardSignal::Halt(v));
+    }
+
+    #[test]
+    fn the_signal_follows_admits_failures_and_staleness() {
+        let (clock, guard) = guard();
+        let signals = guard.subscribe();
+        assert_eq!(signals.recv().unwrap(), GuardSignal::Halt(Vec::new()));
+        assert!(!guard.is_safe(), "nothing admitted yet");
+
+        guard
+            .admit(Ok(response(clock.now_us(), 10, 0, 1_000)))
+            .unwrap();
+        assert_eq!(signals.recv().unwrap(), GuardSignal::Safe);
+        guard
+            .admit(Ok(response(clock.now_us(), 20, 0, 1_000)))
+            .unwrap();
+        assert!(signals.try_recv().is_err(), "only transitions are sent");
+
+        let failed = guard.admit(Err(TimestampError::NoProbes)).unwrap_err();
+        assert!(matches!(failed, TimestampError::NoProbes));
+        let GuardSignal::Halt(v) = signals.recv().unwrap() else {
+            panic!("expected a halt")
+        };
+        assert!(matches!(&v[..], [Violation::Failed(_)]));
+
+        guard
+            .admit(Ok(response(clock.now_us(), 30, 0, 1_000)))
+            .unwrap();
+        assert_eq!(signals.recv().unwrap(), GuardSignal::Safe);
+        let st = guard.status();
+        assert_eq!(
+            (st.accepted, st.rejected, st.consecutive_rejects),
+            (3, 1, 0)
+        );
+
+        // no good stamp for longer than max_staleness: halt without a query
+        clock.advance(Duration::from_secs(5) + Duration::from_millis(1));
+        assert!(!guard.is_safe());
+        assert_eq!(
+            signals.recv().unwrap(),
+            GuardSignal::Halt(vec![Violation::Stale {
+                age_us: 5_001_000,
+                limit_us: 5_000_000
+            }])
+        );
+        assert_eq!(guard.status().since_last_ok_ms, Some(5_001));
+    }
+
+    #[test]
+    fn a_wall_clock_step_back_halts_the_sequencer() {
+        let (clock, guard) = guard();
+        let measured = clock.now_us();
+        guard.admit(Ok(response(measured, 0, 0, 1_000))).unwrap();
+
+        // a small step stays inside the offset limit
+        clock.step_back(Duration::from_millis(60));
+        guard.admit(Ok(response(measured, 0, 0, 1_000))).unwrap();
+
+        clock.step_back(Duration::from_millis(60));
+        let err = guard
+            .admit(Ok(response(measured, 0, 0, 1_000)))
+            .unwrap_err();
+        let TimestampError::Unsafe(v) = err else {
+            panic!("{err}")
+        };
+        assert_eq!(
+            v,
+            [Violation::StepBack {
+                step_us: 120_000,
+                limit_us: 100_000
+            }]
+        );
+        assert!(v[0].to_string().contains("stepped back 120000 µs"));
+        assert!(!guard.is_safe());
+    }
+}
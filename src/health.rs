@@ -0,0 +1,348 @@
+//! Per-server health across calls, with temporary ejection.
+//!
+//! Each round `get_timestamp_from` reports every source's outcome here
+//! (when `ProbeOptions::health` is set) and skips sources that are ejected.
+//! A server is ejected when its success rate drops below
+//! `min_success_rate`, when its offset keeps disagreeing with the consensus
+//! by more than `max_consensus_dev_us`, or on any signature failure.  The
+//! ejection lasts `base_backoff · 2^(n-1)` for the n-th consecutive one;
+//! afterwards the server gets one probe, and a success readmits it.  The
+//! backoff only unwinds with sustained health: every `recovery_successes`
+//! successes in a row take one step off it, and any failure restarts the
+//! count, so a flapping server keeps its long ejections.
+
+use crate::{
+    clock::{Clock, SystemClock},
+    TimestampError, TimestampResponse,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// RTTs kept per server for `rtt_quantile`.
+const RTT_WINDOW: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Weight of the newest sample in the EWMAs.
+    pub alpha: f64,
+    /// Outcomes (or consensus comparisons) needed before the success rate
+    /// (or the disagreement) can eject.
+    pub min_samples: u64,
+    pub min_success_rate: f64,
+    /// Allowed |EWMA of offset − consensus median|, µs.
+    pub max_consensus_dev_us: f64,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive successes that take one step off the backoff.
+    pub recovery_successes: u32,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            alpha: 0.2,
+            min_samples: 4,
+            min_success_rate: 0.5,
+            max_consensus_dev_us: 50_000.0,
+            base_backoff: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(3600),
+            recovery_successes: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ServerHealth {
+    queries: u64,
+    successes: u64,
+    success_ewma: Option<f64>,
+    rtt_ewma_ms: Option<f64>,
+    /// Recent RTTs (ms) for quantiles, newest last.
+    rtts: VecDeque<f64>,
+    consensus_dev_ewma_us: Option<f64>,
+    /// Consensus comparisons since the last ejection.
+    observed: u64,
+    sig_failures: u64,
+    /// Backoff exponent: ejections not yet worked off by `streak`s.
+    ejections: u32,
+    /// Successes since the last failure or ejection.
+    streak: u32,
+    ejected_until: Option<Instant>,
+    eject_reason: Option<String>,
+    last_error: Option<String>,
+}
+
+/// Public view of one server, see `HealthTracker::report`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct HealthReport {
+    pub server: String,
+    pub queries: u64,
+    pub successes: u64,
+    /// EWMA of success (1) / failure (0).
+    pub success_rate: Option<f64>,
+    pub rtt_ewma_ms: Option<f64>,
+    /// EWMA of offset − consensus median, µs.
+    pub consensus_dev_us: Option<f64>,
+    pub sig_failures: u64,
+    pub ejections: u32,
+    /// Time left in the current ejection.
+    pub ejected_for_ms: Option<u64>,
+    pub eject_reason: Option<String>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct HealthTracker {
+    cfg: HealthConfig,
+    clock: Arc<dyn Clock>,
+    servers: Mutex<HashMap<String, ServerHealth>>,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        Self::new(HealthConfig::default())
+    }
+}
+
+fn ewma(prev: Option<f64>, x: f64, alpha: f64) -> f64 {
+    prev.map_or(x, |p| p + alpha * (x - p))
+}
+
+impl HealthTracker {
+    pub fn new(cfg: HealthConfig) -> Self {
+        Self::with_clock(cfg, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(cfg: HealthConfig, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            cfg,
+            clock,
+            servers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `server` may be queried now.  Unknown servers are admitted.
+    pub fn admits(&self, server: &str) -> bool {
+        let now = self.clock.mono();
+        match self.servers.lock().unwrap().get(server) {
+            Some(h) => h.ejected_until.is_none_or(|t| now >= t),
+            None => true,
+        }
+    }
+
+    pub fn record_success(&self, server: &str, rtt: Duration) {
+        let alpha = self.cfg.alpha;
+        let mut map = self.servers.lock().unwrap();
+        let h = map.entry(server.to_string()).or_default();
+        h.queries += 1;
+        h.successes += 1;
+        h.success_ewma = Some(ewma(h.success_ewma, 1.0, alpha));
+        h.rtt_ewma_ms = Some(ewma(h.rtt_ewma_ms, rtt.as_secs_f64() * 1e3, alpha));
+        if h.rtts.len() == RTT_WINDOW {
+            h.rtts.pop_front();
+        }
+        h.rtts.push_back(rtt.as_secs_f64() * 1e3);
+        if h.ejected_until.is_some() {
+            // probe after ejection went through: readmit, keep the backoff
+            h.ejected_until = None;
+            h.eject_reason = None;
+        }
+        h.streak += 1;
+        if h.ejections > 0 && h.streak >= self.cfg.recovery_successes {
+            h.ejections -= 1;
+            h.streak = 0;
+        }
+        self.check(h);
+    }
+
+    /// A `BadProof` counts as a signature failure and ejects at once.
+    pub fn record_failure(&self, server: &str, err: &TimestampError) {
+        let alpha = self.cfg.alpha;
+        let mut map = self.servers.lock().unwrap();
+        let h = map.entry(server.to_string()).or_default();
+        h.queries += 1;
+        h.success_ewma = Some(ewma(h.success_ewma, 0.0, alpha));
+        h.last_error = Some(err.to_string());
+        h.streak = 0;
+        if matches!(err, TimestampError::BadProof(_)) {
+            h.sig_failures += 1;
+            self.eject(h, format!("signature failure: {err}"));
+        } else if h.ejected_until.is_some() {
+            // failed its probe: back off further
+            self.eject(h, format!("probe failed: {err}"));
+        } else {
+            self.check(h);
+        }
+    }
+
+    /// Feed a finished round: every beacon and cross-check's deviation from
+    /// the quorum median.
+    pub fn observe(&self, resp: &TimestampResponse) {
+        let consensus = resp.offset_us();
+        let alpha = self.cfg.alpha;
+        let mut map = self.servers.lock().unwrap();
+        for b in resp
+            .metadata
+            .beacons
+            .iter()
+            .chain(&resp.metadata.cross_checks)
+        {
+            let h = map.entry(b.host.clone()).or_default();
+            let dev = (b.offset_us - consensus) as f64;
+            h.consensus_dev_ewma_us = Some(ewma(h.consensus_dev_ewma_us, dev, alpha));
+            h.observed += 1;
+            self.check(h);
+        }
+    }
+
+    /// `q`-quantile (0..=1) of the recent RTTs; `None` below `min_samples`.
+    pub fn rtt_quantile(&self, server: &str, q: f64) -> Option<Duration> {
+        let map = self.servers.lock().unwrap();
+        let h = map.get(server)?;
+        if (h.rtts.len() as u64) < self.cfg.min_samples {
+            return None;
+        }
+        let mut v: Vec<f64> = h.rtts.iter().copied().collect();
+        v.sort_by(f64::total_cmp);
+        let i = ((v.len() - 1) as f64 * q.clamp(0.0, 1.0)).round() as usize;
+        Some(Duration::from_secs_f64(v[i] / 1e3))
+    }
+
+    /// Manually lift an ejection and forget the backoff.
+    pub fn reinstate(&self, server: &str) {
+        if let Some(h) = self.servers.lock().unwrap().get_mut(server) {
+            h.ejected_until = None;
+            h.eject_reason = None;
+            h.ejections = 0;
+            h.streak = 0;
+        }
+    }
+
+    pub fn report(&self) -> Vec<HealthReport> {
+        let now = self.clock.mono();
+        let map = self.servers.lock().unwrap();
+        let mut out: Vec<HealthReport> = map
+            .iter()
+            .map(|(name, h)| HealthReport {
+                server: name.clone(),
+                queries: h.queries,
+                successes: h.successes,
+                success_rate: h.success_ewma,
+                rtt_ewma_ms: h.rtt_ewma_ms,
+                consensus_dev_us: h.consensus_dev_ewma_us,
+                sig_failures: h.sig_failures,
+                ejections: h.ejections,
+                ejected_for_ms: h
+                    .ejected_until
+                    .filter(|t| *t > now)
+                    .map(|t| (t - now).as_millis() as u64),
+                eject_reason: h.eject_reason.clone(),
+                last_error: h.last_error.clone(),
+            })
+            .collect();
+        out.sort_by(|a, b| a.server.cmp(&b.server));
+        out
+    }
+
+    /// Eject on a low success rate or persistent disagreement.
+    fn check(&self, h: &mut ServerHealth) {
+        if h.ejected_until.is_some() {
+            return;
+        }
+        let rate = h.success_ewma.unwrap_or(1.0);
+        if h.queries >= self.cfg.min_samples && rate < self.cfg.min_success_rate {
+            self.eject(h, format!("success rate {rate:.2}"));
+        } else if let Some(dev) = h.consensus_dev_ewma_us {
+            if h.observed >= self.cfg.min_samples && dev.abs() > self.cfg.max_consensus_dev_us {
+                self.eject(h, format!("{dev:+.0} µs from consensus"));
+            }
+        }
+    }
+
+    fn eject(&self, h: &mut ServerHealth, reason: String) {
+        h.ejections = h.ejections.saturating_add(1);
+        let factor = 1u32 << (h.ejections - 1).min(16);
+        let backoff = (self.cfg.base_backoff * factor).min(self.cfg.max_backoff);
+        h.ejected_until = Some(self.clock.mono() + backoff);
+        h.eject_reason = Some(reason);
+        h.streak = 0;
+        // start the next period from a clean slate
+        h.success_ewma = None;
+        h.consensus_dev_ewma_us = None;
+        h.observed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SimClock;
+    use std::{io, time::SystemTime};
+
+    fn tracker() -> (HealthTracker, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new(SystemTime::UNIX_EPOCH));
+        let cfg = HealthConfig {
+            recovery_successes: 4,
+            ..Default::default()
+        };
+        (HealthTracker::with_clock(cfg, clock.clone()), clock)
+    }
+
+    fn fail(t: &HealthTracker, n: u32) {
+        for _ in 0..n {
+            let e = io::Error::new(io::ErrorKind::TimedOut, "no reply");
+            t.record_failure("a", &TimestampError::Io(e));
+        }
+    }
+
+    fn ejections(t: &HealthTracker) -> u32 {
+        t.report()[0].ejections
+    }
+
+    #[test]
+    fn one_good_probe_keeps_the_backoff() {
+        let (t, clock) = tracker();
+        fail(&t, 4);
+        assert!(!t.admits("a"));
+        clock.advance(Duration::from_secs(30));
+        fail(&t, 1); // failed probe: 60 s now
+        assert_eq!(ejections(&t), 2);
+        clock.advance(Duration::from_secs(60));
+        assert!(t.admits("a"));
+
+        t.record_success("a", Duration::from_millis(10));
+        assert!(t.admits("a"));
+        assert_eq!(ejections(&t), 2, "a single success must not reset");
+
+        // flapping: the next ejection backs off further, not from scratch
+        fail(&t, 4);
+        assert_eq!(ejections(&t), 3);
+        assert_eq!(t.report()[0].ejected_for_ms, Some(120_000));
+    }
+
+    #[test]
+    fn sustained_successes_work_the_backoff_off() {
+        let (t, clock) = tracker();
+        fail(&t, 4);
+        clock.advance(Duration::from_secs(30));
+        fail(&t, 1);
+        clock.advance(Duration::from_secs(60));
+        for _ in 0..3 {
+            t.record_success("a", Duration::from_millis(10));
+        }
+        fail(&t, 1); // breaks the streak
+        for _ in 0..3 {
+            t.record_success("a", Duration::from_millis(10));
+        }
+        assert_eq!(ejections(&t), 2);
+        t.record_success("a", Duration::from_millis(10));
+        assert_eq!(ejections(&t), 1);
+        for _ in 0..4 {
+            t.record_success("a", Duration::from_millis(10));
+        }
+        assert_eq!(ejections(&t), 0);
+    }
+}
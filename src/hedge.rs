@@ -0,0 +1,338 @@
+//! Hedged rounds: backups join when the primaries are slow.
+//!
+//! The primaries are queried at once.  If no quorum has answered by the
+//! hedge delay — the slowest primary's tracked p95 RTT, or
+//! `HedgePolicy::default_delay` while there is no history — the backups get
+//! the same nonce.  The round returns as soon as a quorum is in; stragglers
+//! finish in the background and still feed `ProbeOptions::health`.
+//! `Metadata::hedged` counts only the backups the quorum ended up needing,
+//! not every backup that was sent.
+
+use crate::{
+    aggregate, run_query, source::TimeSource, BeaconMeta, ProbeOptions, QuorumPolicy,
+    TimestampError, TimestampResponse,
+};
+use std::{
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct HedgePolicy {
+    /// RTT quantile that triggers the hedge.
+    pub quantile: f64,
+    /// Hedge delay while a primary has no RTT history.
+    pub default_delay: Duration,
+    pub min_delay: Duration,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        Self {
+            quantile: 0.95,
+            default_delay: Duration::from_millis(500),
+            min_delay: Duration::from_millis(10),
+        }
+    }
+}
+
+impl HedgePolicy {
+    /// When to bring in the backups, measured from the primaries' launch.
+    pub fn delay(&self, primaries: &[Arc<dyn TimeSource>], opts: &ProbeOptions) -> Duration {
+        let delay = primaries
+            .iter()
+            .map(|s| {
+                opts.health
+                    .as_ref()
+                    .and_then(|h| h.rtt_quantile(s.name(), self.quantile))
+                    .unwrap_or(self.default_delay)
+            })
+            .max()
+            .unwrap_or(self.default_delay);
+        delay.max(self.min_delay)
+    }
+}
+
+/// One round over `primaries`, hedged with `backups`.
+///
+/// The quorum is `opts.min_quorum`, else every quorum-eligible primary
+/// (authenticated ones under `QuorumPolicy::AuthenticatedOnly`).
+/// `Metadata::hedged` counts the backups whose answers are among the
+/// response's beacons.
+pub fn get_timestamp_hedged(
+    hash: [u8; 32],
+    primaries: &[Arc<dyn TimeSource>],
+    backups: &[Arc<dyn TimeSource>],
+    opts: &ProbeOptions,
+    policy: &HedgePolicy,
+) -> Result<TimestampResponse, TimestampError> {
+    let admitted = |list: &[Arc<dyn TimeSource>]| -> Vec<Arc<dyn TimeSource>> {
+        list.iter()
+            .filter(|s| opts.admits(s.name()))
+            .cloned()
+            .collect()
+    };
+    let mut primaries = admitted(primaries);
+    let mut backups = admitted(backups);
+    if primaries.is_empty() {
+        // everyone ejected: promote the backups rather than give up
+        primaries = std::mem::take(&mut backups);
+    }
+    if primaries.is_empty() {
+        return Err(TimestampError::NoProbes);
+    }
+    opts.check_rate(&primaries)?;
+
+    let counts = |authenticated: bool| authenticated || opts.quorum == QuorumPolicy::Any;
+    let need = opts
+        .min_quorum
+        .unwrap_or_else(|| {
+            primaries
+                .iter()
+                .filter(|s| counts(s.authenticated()))
+                .count()
+        })
+        .max(1);
+
+    // (from a backup, authenticated, answer)
+    type Answer = (bool, bool, Result<BeaconMeta, TimestampError>);
+    let (tx, rx) = mpsc::channel::<Answer>();
+    let launch = |sources: &[Arc<dyn TimeSource>], backup: bool| {
+        for s in sources {
+            let (s, tx, opts) = (s.clone(), tx.clone(), opts.clone());
+            thread::spawn(move || {
+                let res = run_query(&*s, &hash, &opts);
+                let _ = tx.send((backup, s.authenticated(), res)); // receiver may be gone
+            });
+        }
+    };
+
+    let hedge_at = Instant::now() + policy.delay(&primaries, opts);
+    launch(&primaries, false);
+    let mut outstanding = primaries.len();
+    let mut pending_backups = !backups.is_empty();
+    // answers from backups, (authenticated, unauthenticated)
+    let (mut backup_auth, mut backup_unauth) = (0u32, 0u32);
+    let (mut beacons, mut unauthenticated) = (Vec::new(), Vec::new());
+    let mut last_err = None;
+
+    loop {
+        let have = beacons.len()
+            + if opts.quorum == QuorumPolicy::Any {
+                unauthenticated.len()
+            } else {
+                0
+            };
+        if have >= need {
+            break;
+        }
+        let now = Instant::now();
+        if pending_backups && (outstanding == 0 || now >= hedge_at) {
+            launch(&backups, true);
+            outstanding += backups.len();
+            pending_backups = false;
+            continue;
+        }
+        if outstanding == 0 {
+            break;
+        }
+        let msg = if pending_backups {
+            match rx.recv_timeout(hedge_at - now) {
+                Ok(m) => m,
+                Err(_) => continue, // hedge time
+            }
+        } else {
+            rx.recv().map_err(|_| TimestampError::Join)?
+        };
+        outstanding -= 1;
+        match msg {
+            (backup, true, Ok(b)) => {
+                backup_auth += backup as u32;
+                beacons.push(b);
+            }
+            (backup, false, Ok(b)) => {
+                backup_unauth += backup as u32;
+                unauthenticated.push(b);
+            }
+            (_, _, Err(e)) => last_err = Some(e),
+        }
+    }
+
+    let (cross_checks, hedged) = match opts.quorum {
+        QuorumPolicy::AuthenticatedOnly => (unauthenticated, backup_auth),
+        QuorumPolicy::Any => {
+            beacons.extend(unauthenticated);
+            (Vec::new(), backup_auth + backup_unauth)
+        }
+    };
+    if beacons.is_empty() {
+        return Err(last_err.unwrap_or(TimestampError::NoProbes));
+    }
+    if beacons.len() < need {
+        return Err(TimestampError::Quorum {
+            got: beacons.len(),
+            need,
+        });
+    }
+    let mut resp = aggregate(hash, beacons, cross_checks, opts.aggregation);
+    resp.metadata.hedged = hedged;
+    if let Some(health) = &opts.health {
+        health.observe(&resp);
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{health::HealthTracker, source::MockSource};
+
+    const P95: Duration = Duration::from_millis(40);
+
+    /// Options whose health tracker has `P95` as every primary's p95 RTT.
+    fn opts(primaries: &[&str]) -> ProbeOptions {
+        let health = HealthTracker::default();
+        for name in primaries {
+            // the top 2 of 20 samples: index round(19 × 0.95) = 18
+            for i in 0..20 {
+                let rtt = if i < 18 {
+                    Duration::from_millis(10)
+                } else {
+                    P95
+                };
+                health.record_success(name, rtt);
+            }
+        }
+        ProbeOptions {
+            health: Some(Arc::new(health)),
+            min_quorum: Some(1),
+            rate_limit: None,
+            ..Default::default()
+        }
+    }
+
+    fn mock(name: &str, rtt_ms: u64) -> Arc<dyn TimeSource> {
+        let mut m = MockSource::new(name, 0);
+        m.rtt = Duration::from_millis(rtt_ms);
+        Arc::new(m)
+    }
+
+    fn hosts(resp: &TimestampResponse) -> Vec<&str> {
+        resp.metadata
+            .beacons
+            .iter()
+            .map(|b| b.host.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn the_delay_is_the_slowest_primarys_p95() {
+        let o = opts(&["a", "b"]);
+        let policy = HedgePolicy::default();
+        assert_eq!(policy.delay(&[mock("a", 1), mock("b", 1)], &o), P95);
+        // no history: the default, never below the floor
+        assert_eq!(policy.delay(&[mock("new", 1)], &o), policy.default_delay);
+        let eager = HedgePolicy {
+            min_delay: Duration::from_millis(100),
+            ..Default::default()
+        };
+        assert_eq!(eager.delay(&[mock("a", 1)], &o), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn a_slow_primary_brings_in_the_backup_at_its_p95() {
+        let started = Instant::now();
+        let resp = get_timestamp_hedged(
+            [1; 32],
+            &[mock("a", 400)],
+            &[mock("backup", 1)],
+            &opts(&["a"]),
+            &HedgePolicy::default(),
+        )
+        .unwrap();
+        let took = started.elapsed();
+        assert!(took >= P95 && took < Duration::from_millis(300), "{took:?}");
+        assert_eq!(hosts(&resp), ["backup"]);
+        assert_eq!(resp.metadata.hedged, 1);
+    }
+
+    #[test]
+    fn a_primary_inside_its_p95_leaves_the_backup_alone() {
+        let resp = get_timestamp_hedged(
+            [2; 32],
+            &[mock("a", 5)],
+            &[mock("backup", 1)],
+            &opts(&["a"]),
+            &HedgePolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(hosts(&resp), ["a"]);
+        assert_eq!(resp.metadata.hedged, 0);
+    }
+
+    #[test]
+    fn a_backup_sent_but_beaten_is_not_counted() {
+        let resp = get_timestamp_hedged(
+            [3; 32],
+            &[mock("a", 80)],
+            &[mock("backup", 400)],
+            &opts(&["a"]),
+            &HedgePolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(hosts(&resp), ["a"]);
+        assert_eq!(resp.metadata.hedged, 0);
+    }
+
+    #[test]
+    fn failed_primaries_bring_in_the_backups_at_once() {
+        let mut broken = MockSource::new("a", 0);
+        broken.fail = true;
+        let started = Instant::now();
+        let resp = get_timestamp_hedged(
+            [4; 32],
+            &[Arc::new(broken)],
+            &[mock("backup", 1)],
+            &opts(&["a"]),
+            &HedgePolicy::default(),
+        )
+        .unwrap();
+        assert!(started.elapsed() < P95, "{:?}", started.elapsed());
+        assert_eq!(hosts(&resp), ["backup"]);
+        assert_eq!(resp.metadata.hedged, 1);
+    }
+
+    #[test]
+    fn unauthenticated_backups_count_only_under_any() {
+        let mut sntp = MockSource::new("sntp", 0);
+        sntp.authenticated = false;
+        let sntp: Arc<dyn TimeSource> = Arc::new(sntp);
+        let resp = get_timestamp_hedged(
+            [5; 32],
+            &[mock("a", 200)],
+            &[sntp.clone(), mock("backup", 1)],
+            &opts(&["a"]),
+            &HedgePolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(hosts(&resp), ["backup"]);
+        assert_eq!(resp.metadata.hedged, 1);
+
+        let any = ProbeOptions {
+            quorum: QuorumPolicy::Any,
+            min_quorum: Some(2),
+            ..opts(&["a"])
+        };
+        let resp = get_timestamp_hedged(
+            [6; 32],
+            &[mock("a", 400)],
+            &[sntp, mock("backup", 1)],
+            &any,
+            &HedgePolicy::default(),
+        )
+        .unwrap();
+        assert_eq!(resp.metadata.beacons.len(), 2);
+        assert_eq!(resp.metadata.hedged, 2);
+    }
+}
@@ -0,0 +1,711 @@
+//! HTTP/JSON front end for services that cannot link the library.
+//!
+//! | route | |
+//! |---|---|
+//! | `POST /v1/timestamp` | `{"hash": "<64 hex>"}` → `{"proof": …, "batch": …, "response": …}` |
+//! | `POST /v1/verify` | a `proof::Proof`, optionally with `"batch"` → `{"valid": …, "beacons": […]}` or `{"valid": false, "error": …}` |
+//! | `GET /v1/time` | corrected time and bound (`corrected::Reading`) |
+//! | `GET /healthz` | 200 when ready, 503 otherwise; per-server health either way |
+//!
+//! Deliberately small: HTTP/1.1 with `Content-Length` bodies only, one
+//! request per connection, a thread per connection up to
+//! `max_connections`.  Headers and bodies over the limits get 431 / 413.
+//! `ShutdownHandle::shutdown` stops accepting and lets in-flight requests
+//! finish for up to `drain_timeout`.
+//!
+//! Stamps go through a `batch::Batcher`: concurrent requests share one
+//! beacon round over the Merkle root of their hashes.  `response` and
+//! `proof` cover that root; `batch` holds the submitted hash and its
+//! `batch::Inclusion`, and `/v1/verify` checks that step too when the
+//! proof carries it.
+
+use crate::{
+    batch::{BatchConfig, BatchError, Batcher, Inclusion, Stamped, Ticket},
+    client::TimestampClient,
+    corrected::CorrectedClock,
+    fresh_nonce,
+    guard::GuardSignal,
+    log,
+    proof::Proof,
+    shutdown::ShutdownHandle,
+    TimestampError,
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Accept-loop poll interval; bounds how long `shutdown` takes to notice.
+const ACCEPT_POLL: Duration = Duration::from_millis(25);
+
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub bind: String,
+    /// Request line plus headers, bytes.
+    pub max_header: usize,
+    pub max_body: usize,
+    pub max_connections: usize,
+    /// Read / write timeout per connection.
+    pub io_timeout: Duration,
+    pub drain_timeout: Duration,
+    /// Background refresh of the `/v1/time` estimate.
+    pub poll: Duration,
+    pub max_age: Duration,
+    /// Batching of `/v1/timestamp` requests.
+    pub batch: BatchConfig,
+    /// Long-term keys by host, for `/v1/verify`; a proof from a beacon
+    /// without one is not valid.
+    pub keys: HashMap<String, Vec<u8>>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1:8080".into(),
+            max_header: 8 * 1024,
+            max_body: 64 * 1024,
+            max_connections: 64,
+            io_timeout: Duration::from_secs(10),
+            drain_timeout: Duration::from_secs(10),
+            poll: Duration::from_secs(64),
+            max_age: Duration::from_secs(1024),
+            batch: BatchConfig::default(),
+            keys: HashMap::new(),
+        }
+    }
+}
+
+struct Shared {
+    cfg: HttpConfig,
+    client: TimestampClient,
+    clock: CorrectedClock,
+    batcher: Batcher,
+}
+
+pub struct HttpServer {
+    listener: TcpListener,
+    shared: Arc<Shared>,
+    stop: ShutdownHandle,
+}
+
+impl HttpServer {
+    pub fn bind(cfg: HttpConfig, client: TimestampClient) -> io::Result<Self> {
+        let listener = TcpListener::bind(&cfg.bind)?;
+        listener.set_nonblocking(true)?;
+        let clock = CorrectedClock::new(cfg.max_age);
+        let batcher = Batcher::new(client.clone(), cfg.batch.clone());
+        Ok(Self {
+            listener,
+            shared: Arc::new(Shared {
+                cfg,
+                client,
+                clock,
+                batcher,
+            }),
+            stop: ShutdownHandle::default(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.stop.clone()
+    }
+
+    /// Serve until shut down, then drain.
+    pub fn serve(self) -> io::Result<()> {
+        let active = Arc::new(AtomicUsize::new(0));
+        let refresher = self.spawn_refresher();
+
+        while !self.stop.is_shutdown() {
+            let stream = match self.listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::error!("http        : accept failed: {e}");
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+            };
+            let _ = stream.set_nonblocking(false);
+            let _ = stream.set_read_timeout(Some(self.shared.cfg.io_timeout));
+            let _ = stream.set_write_timeout(Some(self.shared.cfg.io_timeout));
+            if active.load(Ordering::SeqCst) >= self.shared.cfg.max_connections {
+                let _ = respond(stream, 503, &json!({"error": "too many connections"}));
+                continue;
+            }
+            active.fetch_add(1, Ordering::SeqCst);
+            let (shared, active) = (self.shared.clone(), active.clone());
+            thread::spawn(move || {
+                if let Err(e) = handle(&shared, stream) {
+                    log::error!("http        : {e}");
+                }
+                active.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        drop(self.listener);
+        let until = Instant::now() + self.shared.cfg.drain_timeout;
+        while active.load(Ordering::SeqCst) > 0 && Instant::now() < until {
+            thread::sleep(ACCEPT_POLL);
+        }
+        let left = active.load(Ordering::SeqCst);
+        if left > 0 {
+            log::error!("http        : {left} request(s) still running at shutdown");
+        }
+        let _ = refresher.join();
+        Ok(())
+    }
+
+    /// Keeps `/v1/time` fresh between stamps; exits on shutdown.
+    fn spawn_refresher(&self) -> thread::JoinHandle<()> {
+        let (shared, stop) = (self.shared.clone(), self.stop.clone());
+        thread::spawn(move || {
+            let mut next = Instant::now();
+            while !stop.is_shutdown() {
+                if Instant::now() >= next {
+                    match fresh_nonce()
+                        .map_err(TimestampError::from)
+                        .and_then(|n| shared.client.timestamp(n))
+                    {
+                        Ok(resp) => {
+                            if let Err(e) = shared.clock.refresh(&resp, &shared.client) {
+                                log::error!("http        : drift file not saved: {e}");
+                            }
+                        }
+                        Err(e) => log::error!("http        : refresh failed: {e}"),
+                    }
+                    next = Instant::now() + shared.cfg.poll;
+                }
+                thread::sleep(ACCEPT_POLL);
+            }
+        })
+    }
+}
+
+// -------------------------------------------------------------------------
+// Requests
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Protocol-level failure: status and message.
+struct Reject(u16, String);
+
+impl From<io::Error> for Reject {
+    fn from(e: io::Error) -> Self {
+        Reject(400, e.to_string())
+    }
+}
+
+fn handle(shared: &Shared, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let (status, body) = match read_request(&mut reader, &shared.cfg) {
+        Ok(req) => route(shared, &req),
+        Err(Reject(status, msg)) => (status, json!({ "error": msg })),
+    };
+    respond(stream, status, &body)
+}
+
+fn read_request(r: &mut impl BufRead, cfg: &HttpConfig) -> Result<Request, Reject> {
+    let mut head = Vec::new();
+    loop {
+        let n = r
+            .by_ref()
+            .take((cfg.max_header + 1 - head.len()) as u64)
+            .read_until(b'\n', &mut head)?;
+        if head.len() > cfg.max_header {
+            return Err(Reject(431, "headers too large".into()));
+        }
+        if n == 0 {
+            return Err(Reject(400, "connection closed mid-request".into()));
+        }
+        if head.ends_with(b"\r\n\r\n") || head.ends_with(b"\n\n") {
+            break;
+        }
+    }
+    let head = String::from_utf8(head).map_err(|_| Reject(400, "headers not UTF-8".into()))?;
+    let mut lines = head.lines();
+    let mut parts = lines.next().unwrap_or("").split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(m), Some(t), Some(v)) if v.starts_with("HTTP/1.") => (m, t),
+        _ => return Err(Reject(400, "bad request line".into())),
+    };
+
+    let mut length = 0usize;
+    for line in lines.take_while(|l| !l.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(Reject(400, "bad header line".into()));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-length" => {
+                length = value
+                    .parse()
+                    .map_err(|_| Reject(400, "bad Content-Length".into()))?
+            }
+            "transfer-encoding" => {
+                return Err(Reject(
+                    411,
+                    "chunked bodies not supported; send Content-Length".into(),
+                ))
+            }
+            _ => {}
+        }
+    }
+    if length > cfg.max_body {
+        return Err(Reject(413, format!("body over {} bytes", cfg.max_body)));
+    }
+    let mut body = vec![0; length];
+    r.read_exact(&mut body)?;
+    Ok(Request {
+        method: method.to_string(),
+        path: target.split('?').next().unwrap_or("").to_string(),
+        body,
+    })
+}
+
+fn route(shared: &Shared, req: &Request) -> (u16, serde_json::Value) {
+    match (req.method.as_str(), req.path.as_str()) {
+        ("POST", "/v1/timestamp") => timestamp(shared, &req.body),
+        ("POST", "/v1/verify") => verify(shared, &req.body),
+        ("GET", "/v1/time") => time(shared),
+        ("GET", "/healthz") => healthz(shared),
+        (_, "/v1/timestamp" | "/v1/verify" | "/v1/time" | "/healthz") => {
+            (405, json!({"error": "method not allowed"}))
+        }
+        _ => (404, json!({"error": "not found"})),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct StampRequest {
+    hash: String,
+}
+
+fn timestamp(shared: &Shared, body: &[u8]) -> (u16, serde_json::Value) {
+    let hash = match serde_json::from_slice::<StampRequest>(body)
+        .map_err(|e| e.to_string())
+        .and_then(|r| {
+            hex::decode(r.hash.trim())
+                .ok()
+                .and_then(|h| <[u8; 32]>::try_from(h).ok())
+                .ok_or_else(|| "hash must be 32 bytes (64 hex chars)".to_string())
+        }) {
+        Ok(h) => h,
+        Err(e) => return (400, json!({ "error": e })),
+    };
+    match shared.batcher.submit(hash).and_then(Ticket::wait) {
+        Ok(Stamped {
+            response,
+            inclusion,
+        }) => {
+            shared.clock.observe(&response, &shared.client);
+            let batch = BatchStep {
+                hash: hex::encode(hash),
+                inclusion,
+            };
+            (
+                200,
+                json!({
+                    "proof": Proof::from_response(&response),
+                    "batch": batch,
+                    "response": &*response,
+                }),
+            )
+        }
+        // the guard refused: our time is unusable, not the request
+        Err(BatchError::Failed(e)) if matches!(*e, TimestampError::Unsafe(_)) => {
+            (503, json!({ "error": e.to_string() }))
+        }
+        Err(e @ BatchError::Closed) => (503, json!({ "error": e.to_string() })),
+        Err(e) => (502, json!({ "error": e.to_string() })),
+    }
+}
+
+/// A batched stamp's extra step: `hash` is leaf `inclusion.index` of the
+/// tree whose root the proof stamps.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BatchStep {
+    hash: String,
+    inclusion: Inclusion,
+}
+
+#[derive(serde::Deserialize)]
+struct VerifyRequest {
+    #[serde(flatten)]
+    proof: Proof,
+    #[serde(default)]
+    batch: Option<BatchStep>,
+}
+
+fn verify(shared: &Shared, body: &[u8]) -> (u16, serde_json::Value) {
+    let VerifyRequest { proof, batch } = match serde_json::from_slice(body) {
+        Ok(r) => r,
+        Err(e) => return (400, json!({ "error": e.to_string() })),
+    };
+    if let Some(step) = batch {
+        let in_batch = hex::decode(&step.hash).is_ok_and(|h| step.inclusion.verify(&h));
+        if !in_batch {
+            return (
+                200,
+                json!({ "valid": false, "error": "hash is not in the batch" }),
+            );
+        }
+        if !step.inclusion.root.eq_ignore_ascii_case(&proof.hash) {
+            return (
+                200,
+                json!({ "valid": false, "error": "proof is for a different batch" }),
+            );
+        }
+    }
+    match proof.verify(&shared.cfg.keys) {
+        Ok(beacons) => (200, json!({ "valid": true, "beacons": beacons })),
+        Err(e) => (200, json!({ "valid": false, "error": e.to_string() })),
+    }
+}
+
+fn time(shared: &Shared) -> (u16, serde_json::Value) {
+    let Some(r) = shared.clock.now() else {
+        return (503, json!({"error": "no beacon round yet"}));
+    };
+    let time_us = r
+        .time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let local_us = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    (
+        200,
+        json!({
+            "time_us": time_us,
+            "local_us": local_us,
+            "uncert_us": r.uncert_us,
+            "age_ms": r.age.as_millis() as u64,
+            "stale": r.stale,
+        }),
+    )
+}
+
+/// Ready: a fresh estimate, enough admitted sources for the quorum, and
+/// no halt from the guard.
+fn healthz(shared: &Shared) -> (u16, serde_json::Value) {
+    let client = &shared.client;
+    let health = client.health();
+    let admitted = client
+        .sources()
+        .iter()
+        .filter(|s| health.admits(s.name()))
+        .count();
+    let need = client.options().min_quorum.unwrap_or(1);
+    let fresh = shared.clock.now().is_some_and(|r| !r.stale);
+    let guard = client.guard().map(|g| g.status());
+    let halted = guard
+        .as_ref()
+        .is_some_and(|g| matches!(g.signal, GuardSignal::Halt(_)));
+
+    let ready = fresh && admitted >= need && !halted;
+    let body = json!({
+        "ready": ready,
+        "fresh_estimate": fresh,
+        "admitted_sources": admitted,
+        "quorum": need,
+        "guard": guard,
+        "servers": health.report(),
+    });
+    (if ready { 200 } else { 503 }, body)
+}
+
+fn respond(mut stream: TcpStream, status: u16, body: &serde_json::Value) -> io::Result<()> {
+    let body = serde_json::to_vec(body).map_err(io::Error::other)?;
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        sim::{SimNetwork, SimServerConfig},
+        source::MockSource,
+    };
+    use serde_json::Value;
+
+    fn start(
+        cfg: HttpConfig,
+        client: TimestampClient,
+    ) -> (
+        SocketAddr,
+        ShutdownHandle,
+        thread::JoinHandle<io::Result<()>>,
+    ) {
+        let server = HttpServer::bind(
+            HttpConfig {
+                bind: "127.0.0.1:0".into(),
+                ..cfg
+            },
+            client,
+        )
+        .unwrap();
+        let (addr, stop) = (server.local_addr().unwrap(), server.shutdown_handle());
+        (addr, stop, thread::spawn(move || server.serve()))
+    }
+
+    fn parse(raw: &str) -> (u16, Value) {
+        let status = raw
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0);
+        let body = raw.split_once("\r\n\r\n").map_or("", |(_, b)| b);
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    fn post(addr: SocketAddr, path: &str, body: &Value) -> (u16, Value) {
+        let body = body.to_string();
+        let mut s = TcpStream::connect(addr).unwrap();
+        write!(
+            s,
+            "POST {path} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut out = String::new();
+        s.read_to_string(&mut out).unwrap();
+        parse(&out)
+    }
+
+    #[test]
+    fn stamps_verify_only_when_signed_untampered_and_pinned() {
+        let start_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as u64;
+        let clock = Arc::new(SimClock::new(UNIX_EPOCH + Duration::from_micros(start_us)));
+        let net = SimNetwork::new(clock.clone(), 0);
+        let beacon = net
+            .server(SimServerConfig::symmetric("sim", Duration::from_millis(10)))
+            .unwrap();
+        let keys = HashMap::from([("sim".to_string(), beacon.public_key().unwrap().to_vec())]);
+        let client = TimestampClient::builder()
+            .source(Arc::new(beacon))
+            .clock(clock)
+            .build()
+            .unwrap();
+        let (addr, stop, server) = start(
+            HttpConfig {
+                keys,
+                ..Default::default()
+            },
+            client,
+        );
+
+        let (status, stamp) = post(addr, "/v1/timestamp", &json!({ "hash": "ab".repeat(32) }));
+        assert_eq!(status, 200, "{stamp}");
+        let proof = stamp["proof"].clone();
+        assert_eq!(proof["beacons"].as_array().map(Vec::len), Some(1));
+
+        let (status, verdict) = post(addr, "/v1/verify", &proof);
+        assert_eq!(status, 200);
+        assert_eq!(verdict["valid"], true, "{verdict}");
+
+        // the proof covers the batch root; `batch` leads from our hash to it
+        assert_eq!(stamp["batch"]["hash"], "ab".repeat(32));
+        assert_eq!(proof["hash"], stamp["batch"]["inclusion"]["root"]);
+        let mut batched = proof.clone();
+        batched["batch"] = stamp["batch"].clone();
+        let (_, through) = post(addr, "/v1/verify", &batched);
+        assert_eq!(through["valid"], true, "{through}");
+        batched["batch"]["hash"] = json!("cd".repeat(32));
+        let (_, outside) = post(addr, "/v1/verify", &batched);
+        assert_eq!(outside["valid"], false, "{outside}");
+        assert_eq!(outside["error"], "hash is not in the batch");
+
+        // the signed midpoint moved by a second
+        let midpoint = verdict["beacons"][0]["midpoint_us"].as_u64().unwrap();
+        let mut tampered = proof.clone();
+        let evidence = &mut tampered["beacons"][0]["evidence"]["Roughtime"]["reply"];
+        let signed = hex::encode(midpoint.to_le_bytes());
+        let moved = hex::encode((midpoint + 1_000_000).to_le_bytes());
+        assert!(evidence.as_str().unwrap().contains(&signed));
+        *evidence = json!(evidence.as_str().unwrap().replace(&signed, &moved));
+        let (_, verdict) = post(addr, "/v1/verify", &tampered);
+        assert_eq!(verdict["valid"], false, "{verdict}");
+
+        // no signed reply at all
+        let mut unsigned = proof.clone();
+        unsigned["beacons"][0]["evidence"] = json!("None");
+        let (_, verdict) = post(addr, "/v1/verify", &unsigned);
+        assert_eq!(verdict["valid"], false, "{verdict}");
+
+        // signed, but by a host we hold no key for
+        let mut unpinned = proof;
+        unpinned["beacons"][0]["host"] = json!("elsewhere");
+        let (_, verdict) = post(addr, "/v1/verify", &unpinned);
+        assert_eq!(verdict["valid"], false, "{verdict}");
+        assert!(verdict["error"].as_str().unwrap().contains("no pinned key"));
+
+        stop.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn concurrent_stamps_share_one_beacon_round() {
+        let client = TimestampClient::builder()
+            .source(Arc::new(MockSource::new("a", 1_000)))
+            .build()
+            .unwrap();
+        let cfg = HttpConfig {
+            batch: BatchConfig {
+                max_items: 3,
+                max_wait: Duration::from_secs(5),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (addr, stop, server) = start(cfg, client);
+
+        // sealed by size: three requests, one round
+        let stamps: Vec<_> = (1..=3u8)
+            .map(|i| {
+                thread::spawn(move || {
+                    post(
+                        addr,
+                        "/v1/timestamp",
+                        &json!({ "hash": hex::encode([i; 32]) }),
+                    )
+                })
+            })
+            .collect();
+        let mut roots = Vec::new();
+        for (i, t) in (1..=3u8).zip(stamps) {
+            let (status, stamp) = t.join().unwrap();
+            assert_eq!(status, 200, "{stamp}");
+            let inclusion: Inclusion =
+                serde_json::from_value(stamp["batch"]["inclusion"].clone()).unwrap();
+            assert!(inclusion.verify(&[i; 32]));
+            assert_eq!(stamp["response"]["input_hash"], json!(inclusion.root));
+            roots.push(inclusion.root);
+        }
+        roots.dedup();
+        assert_eq!(roots.len(), 1, "{roots:?}");
+
+        stop.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn shutdown_lets_in_flight_requests_finish() {
+        let client = TimestampClient::builder()
+            .source(Arc::new(MockSource::new("a", 1_000)))
+            .build()
+            .unwrap();
+        let (addr, stop, server) = start(HttpConfig::default(), client);
+
+        let mut slow = TcpStream::connect(addr).unwrap();
+        slow.write_all(b"GET /healthz HTTP/1.1\r\n").unwrap();
+        thread::sleep(ACCEPT_POLL * 4); // accepted and being read
+        stop.shutdown();
+        thread::sleep(ACCEPT_POLL * 4);
+        slow.write_all(b"\r\n").unwrap();
+        let mut out = String::new();
+        slow.read_to_string(&mut out).unwrap();
+        let (status, body) = parse(&out);
+        assert!(status == 200 || status == 503, "{out}");
+        assert!(body["servers"].is_array(), "{body}");
+
+        server.join().unwrap().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    fn send(addr: SocketAddr, raw: &[u8]) -> (u16, Value) {
+        let mut s = TcpStream::connect(addr).unwrap();
+        s.write_all(raw).unwrap();
+        let mut out = String::new();
+        s.read_to_string(&mut out).unwrap();
+        parse(&out)
+    }
+
+    /// A GET whose request line and headers take exactly `len` bytes.
+    fn head_of(len: usize) -> Vec<u8> {
+        let start = "GET /healthz HTTP/1.1\r\nX-Pad: ";
+        let pad = len - start.len() - "\r\n\r\n".len();
+        format!("{start}{}\r\n\r\n", "x".repeat(pad)).into_bytes()
+    }
+
+    #[test]
+    fn requests_over_the_limits_get_431_and_413() {
+        let client = TimestampClient::builder()
+            .source(Arc::new(MockSource::new("a", 1_000)))
+            .build()
+            .unwrap();
+        let cfg = HttpConfig {
+            max_header: 256,
+            max_body: 64,
+            ..Default::default()
+        };
+        let (addr, stop, server) = start(cfg, client);
+
+        let (status, _) = send(addr, &head_of(256));
+        assert!(status == 200 || status == 503, "at the limit: {status}");
+        let (status, body) = send(addr, &head_of(257));
+        assert_eq!(status, 431, "{body}");
+        assert_eq!(body["error"], "headers too large");
+
+        // refused on the declared length, before any of the body is read
+        let (status, body) = send(
+            addr,
+            b"POST /v1/verify HTTP/1.1\r\nContent-Length: 65\r\n\r\n",
+        );
+        assert_eq!(status, 413, "{body}");
+        assert_eq!(body["error"], "body over 64 bytes");
+        let at_limit = format!(
+            "POST /v1/verify HTTP/1.1\r\nContent-Length: 64\r\n\r\n{}",
+            " ".repeat(64)
+        );
+        let (status, body) = send(addr, at_limit.as_bytes());
+        assert_ne!(status, 413, "{body}");
+
+        stop.shutdown();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,817 @@
+//! Local IPC: stamps and corrected time over a Unix stream socket.
+//!
+//! For processes on the same host that want the daemon's answers without
+//! HTTP or TCP.  Every message is a frame, a big-endian `u32` length and
+//! that many bytes; integers inside are big-endian too.
+//!
+//! ```text
+//! request  op:u8 …
+//!   0x01 STAMP   hash[32]
+//!   0x02 BATCH   n:u32 hash[32]×n
+//!   0x03 TIME
+//!   0x04 HEALTH
+//! reply    status:u8 …
+//!   0x01 ERROR   message (utf-8, rest of frame)
+//!   0x00 OK, then by op:
+//!     STAMP   timestamp:u64 uncert_us:u64 local:u64 bumped_us:u64
+//!             proof_len:u32 proof (`proof::Proof` as JSON)
+//!             index:u32 path_len:u16 path
+//!     BATCH   as STAMP up to the proof, for the root, then root[32]
+//!             n:u32 (index:u32 path_len:u16 path)×n
+//!     TIME    time_us:u64 uncert_us:u64 age_ms:u64 stale:u8
+//!     HEALTH  ready:u8 admitted:u32 quorum:u32 halted:u8
+//! ```
+//!
+//! A connection carries any number of requests, one at a time.  Access
+//! is checked once per connection from the kernel's peer credentials
+//! (`SO_PEERCRED`): root and the daemon's own user always, others only
+//! if listed in `allow_uids` / `allow_gids`.  The kernel reports only the
+//! peer's primary group, so `allow_gids` does not see supplementary
+//! groups; grant those through the socket's group and `mode` instead.
+//! The socket's file mode is the first gate, this the second.
+//!
+//! STAMP requests from all connections go through one `batch::Batcher`,
+//! so concurrent callers share a beacon round: the stamp and proof cover
+//! the batch root, and the trailing path leads from the hash to it.
+
+use crate::{
+    batch::{self, BatchConfig, Batcher, Inclusion, Ticket},
+    client::TimestampClient,
+    corrected::CorrectedClock,
+    fresh_nonce,
+    guard::GuardSignal,
+    log,
+    proof::Proof,
+    shutdown::ShutdownHandle,
+    TimestampError, TimestampResponse,
+};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    os::unix::{
+        fs::PermissionsExt,
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_PATH: &str = "/var/run/rt_ping.sock";
+
+const OP_STAMP: u8 = 0x01;
+const OP_BATCH: u8 = 0x02;
+const OP_TIME: u8 = 0x03;
+const OP_HEALTH: u8 = 0x04;
+const STATUS_OK: u8 = 0x00;
+const STATUS_ERROR: u8 = 0x01;
+
+/// Accept-loop poll interval; bounds how long `shutdown` takes to notice.
+const ACCEPT_POLL: Duration = Duration::from_millis(25);
+
+#[derive(Debug, Clone)]
+pub struct IpcConfig {
+    pub path: PathBuf,
+    /// Socket file mode.
+    pub mode: u32,
+    pub allow_uids: Vec<u32>,
+    /// Matched against the peer's primary group only; supplementary
+    /// groups are not reported by `SO_PEERCRED`.
+    pub allow_gids: Vec<u32>,
+    /// Largest request frame, bytes.
+    pub max_frame: usize,
+    pub max_batch: usize,
+    pub max_connections: usize,
+    /// Connections idle this long are closed.
+    pub idle_timeout: Duration,
+    pub drain_timeout: Duration,
+    /// Background refresh of the TIME estimate.
+    pub poll: Duration,
+    pub max_age: Duration,
+    /// Batching of STAMP requests.
+    pub batch: BatchConfig,
+}
+
+impl Default for IpcConfig {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_PATH.into(),
+            mode: 0o660,
+            allow_uids: Vec::new(),
+            allow_gids: Vec::new(),
+            max_frame: 1 << 20,
+            max_batch: 4096,
+            max_connections: 256,
+            idle_timeout: Duration::from_secs(60),
+            drain_timeout: Duration::from_secs(10),
+            poll: Duration::from_secs(64),
+            max_age: Duration::from_secs(1024),
+            batch: BatchConfig::default(),
+        }
+    }
+}
+
+/// Who is on the other end, as the kernel reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCred {
+    /// Not available on every platform.
+    pub pid: Option<i32>,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_cred(s: &UnixStream) -> io::Result<PeerCred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let rc = unsafe {
+        libc::getsockopt(
+            s.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: Some(cred.pid),
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn peer_cred(s: &UnixStream) -> io::Result<PeerCred> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(s.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(PeerCred {
+        pid: None,
+        uid,
+        gid,
+    })
+}
+
+// -------------------------------------------------------------------------
+// Encoding
+
+/// Cursor over a received frame; running short is `InvalidData`.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short frame"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn hash(&mut self) -> io::Result<[u8; 32]> {
+        Ok(self.take(32)?.try_into().unwrap())
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+}
+
+fn read_frame(r: &mut impl Read, max: usize) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes, limit {max}"),
+        ));
+    }
+    let mut body = vec![0u8; len];
+    r.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn write_frame(w: &mut impl Write, body: &[u8]) -> io::Result<()> {
+    let mut out = Vec::with_capacity(4 + body.len());
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(body);
+    w.write_all(&out)
+}
+
+fn put_stamp(out: &mut Vec<u8>, resp: &TimestampResponse) -> Result<(), TimestampError> {
+    let proof = serde_json::to_vec(&Proof::from_response(resp))
+        .map_err(|e| TimestampError::Config(e.to_string()))?;
+    out.extend_from_slice(&resp.timestamp.to_be_bytes());
+    out.extend_from_slice(&resp.metadata.combined_uncert_us().to_be_bytes());
+    out.extend_from_slice(&resp.local_timestamp.to_be_bytes());
+    out.extend_from_slice(&resp.metadata.bumped_us.to_be_bytes());
+    out.extend_from_slice(&(proof.len() as u32).to_be_bytes());
+    out.extend_from_slice(&proof);
+    Ok(())
+}
+
+fn put_inclusion(out: &mut Vec<u8>, i: &Inclusion) {
+    let path = hex::decode(&i.path).unwrap_or_default();
+    out.extend_from_slice(&i.index.to_be_bytes());
+    out.extend_from_slice(&(path.len() as u16).to_be_bytes());
+    out.extend_from_slice(&path);
+}
+
+fn get_inclusion(c: &mut Cursor, root: &str) -> io::Result<Inclusion> {
+    let index = c.u32()?;
+    let len = c.u16()? as usize;
+    Ok(Inclusion {
+        index,
+        path: hex::encode(c.take(len)?),
+        root: root.to_string(),
+    })
+}
+
+fn get_stamp(c: &mut Cursor) -> Result<Stamp, TimestampError> {
+    let (timestamp_us, uncert_us, local_us, bumped_us) = (c.u64()?, c.u64()?, c.u64()?, c.u64()?);
+    let len = c.u32()? as usize;
+    let proof = serde_json::from_slice(c.take(len)?)
+        .map_err(|e| TimestampError::BadProof(format!("daemon sent: {e}")))?;
+    Ok(Stamp {
+        timestamp_us,
+        uncert_us,
+        local_us,
+        bumped_us,
+        proof,
+        inclusion: None,
+    })
+}
+
+// -------------------------------------------------------------------------
+// Server
+
+struct Shared {
+    cfg: IpcConfig,
+    client: TimestampClient,
+    clock: CorrectedClock,
+    batcher: Batcher,
+    own_uid: u32,
+}
+
+pub struct IpcServer {
+    listener: UnixListener,
+    shared: Arc<Shared>,
+    stop: ShutdownHandle,
+}
+
+impl IpcServer {
+    /// Bind `cfg.path`, replacing a stale socket left by a dead daemon
+    /// but not one that still answers.  The socket is created owner-only
+    /// and opened up to `cfg.mode` afterwards, so it is never reachable
+    /// with the process umask's looser default.
+    pub fn bind(cfg: IpcConfig, client: TimestampClient) -> io::Result<Self> {
+        if cfg.path.exists() {
+            if UnixStream::connect(&cfg.path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is served by another process", cfg.path.display()),
+                ));
+            }
+            fs::remove_file(&cfg.path)?;
+        }
+        // umask is process-wide; binding happens once, at start-up
+        let umask = unsafe { libc::umask(0o177) };
+        let bound = UnixListener::bind(&cfg.path);
+        unsafe { libc::umask(umask) };
+        let listener = bound?;
+        fs::set_permissions(&cfg.path, fs::Permissions::from_mode(cfg.mode))?;
+        listener.set_nonblocking(true)?;
+        let clock = CorrectedClock::new(cfg.max_age);
+        Ok(Self {
+            listener,
+            shared: Arc::new(Shared {
+                batcher: Batcher::new(client.clone(), cfg.batch.clone()),
+                cfg,
+                client,
+                clock,
+                own_uid: unsafe { libc::geteuid() },
+            }),
+            stop: ShutdownHandle::default(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.shared.cfg.path
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.stop.clone()
+    }
+
+    /// Serve until shut down, let running requests finish, remove the
+    /// socket file.
+    pub fn serve(self) -> io::Result<()> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let busy = Arc::new(AtomicUsize::new(0));
+        let refresher = self.spawn_refresher();
+
+        while !self.stop.is_shutdown() {
+            let stream = match self.listener.accept() {
+                Ok((s, _)) => s,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    log::error!("ipc         : accept failed: {e}");
+                    thread::sleep(ACCEPT_POLL);
+                    continue;
+                }
+            };
+            let _ = stream.set_nonblocking(false);
+            if connections.load(Ordering::SeqCst) >= self.shared.cfg.max_connections {
+                let _ = error_reply(&stream, "too many connections");
+                continue;
+            }
+            connections.fetch_add(1, Ordering::SeqCst);
+            let (shared, stop) = (self.shared.clone(), self.stop.clone());
+            let (connections, busy) = (connections.clone(), busy.clone());
+            thread::spawn(move || {
+                if let Err(e) = handle(&shared, &stop, &busy, stream) {
+                    log::error!("ipc         : {e}");
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+
+        let _ = fs::remove_file(&self.shared.cfg.path);
+        let until = Instant::now() + self.shared.cfg.drain_timeout;
+        while busy.load(Ordering::SeqCst) > 0 && Instant::now() < until {
+            thread::sleep(ACCEPT_POLL);
+        }
+        let left = busy.load(Ordering::SeqCst);
+        if left > 0 {
+            log::error!("ipc         : {left} request(s) still running at shutdown");
+        }
+        let _ = refresher.join();
+        Ok(())
+    }
+
+    /// Keeps TIME fresh between stamps; exits on shutdown.
+    fn spawn_refresher(&self) -> thread::JoinHandle<()> {
+        let (shared, stop) = (self.shared.clone(), self.stop.clone());
+        thread::spawn(move || {
+            let mut next = Instant::now();
+            while !stop.is_shutdown() {
+                if Instant::now() >= next {
+                    match fresh_nonce()
+                        .map_err(TimestampError::from)
+                        .and_then(|n| shared.client.timestamp(n))
+                    {
+                        Ok(resp) => {
+                            if let Err(e) = shared.clock.refresh(&resp, &shared.client) {
+                                log::error!("ipc         : drift file not saved: {e}");
+                            }
+                        }
+                        Err(e) => log::error!("ipc         : refresh failed: {e}"),
+                    }
+                    next = Instant::now() + shared.cfg.poll;
+                }
+                thread::sleep(ACCEPT_POLL);
+            }
+        })
+    }
+}
+
+impl Shared {
+    /// `peer.gid` is the primary group; supplementary groups are unknown.
+    fn admits(&self, peer: &PeerCred) -> bool {
+        peer.uid == 0
+            || peer.uid == self.own_uid
+            || self.cfg.allow_uids.contains(&peer.uid)
+            || self.cfg.allow_gids.contains(&peer.gid)
+    }
+}
+
+fn error_reply(mut stream: &UnixStream, msg: &str) -> io::Result<()> {
+    let mut body = vec![STATUS_ERROR];
+    body.extend_from_slice(msg.as_bytes());
+    write_frame(&mut stream, &body)
+}
+
+fn handle(
+    shared: &Shared,
+    stop: &ShutdownHandle,
+    busy: &AtomicUsize,
+    mut stream: UnixStream,
+) -> io::Result<()> {
+    let peer = peer_cred(&stream)?;
+    if !shared.admits(&peer) {
+        log::error!(
+            "ipc         : refused uid {} gid {} (pid {:?})",
+            peer.uid,
+            peer.gid,
+            peer.pid
+        );
+        return error_reply(&stream, "permission denied");
+    }
+    stream.set_read_timeout(Some(shared.cfg.idle_timeout))?;
+    stream.set_write_timeout(Some(shared.cfg.idle_timeout))?;
+    while !stop.is_shutdown() {
+        let req = match read_frame(&mut stream, shared.cfg.max_frame) {
+            Ok(r) => r,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            // oversized: say why, then drop the connection (the stream is
+            // out of step with the framing)
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return error_reply(&stream, &e.to_string())
+            }
+            Err(e) => return Err(e),
+        };
+        busy.fetch_add(1, Ordering::SeqCst);
+        let reply = dispatch(shared, &req);
+        busy.fetch_sub(1, Ordering::SeqCst);
+        let body = match reply {
+            Ok(mut body) => {
+                body.insert(0, STATUS_OK);
+                body
+            }
+            Err(e) => {
+                let msg = match e {
+                    TimestampError::Remote(m) => m,
+                    e => e.to_string(),
+                };
+                let mut body = vec![STATUS_ERROR];
+                body.extend_from_slice(msg.as_bytes());
+                body
+            }
+        };
+        write_frame(&mut stream, &body)?;
+    }
+    Ok(())
+}
+
+fn dispatch(shared: &Shared, req: &[u8]) -> Result<Vec<u8>, TimestampError> {
+    let mut c = Cursor(req);
+    let mut out = Vec::new();
+    match c.u8()? {
+        OP_STAMP => {
+            let stamped = shared
+                .batcher
+                .submit(c.hash()?)
+                .and_then(Ticket::wait)
+                .map_err(|e| TimestampError::Remote(e.to_string()))?;
+            shared.clock.observe(&stamped.response, &shared.client);
+            put_stamp(&mut out, &stamped.response)?;
+            put_inclusion(&mut out, &stamped.inclusion);
+        }
+        OP_BATCH => {
+            let n = c.u32()? as usize;
+            if n > shared.cfg.max_batch {
+                return Err(TimestampError::Config(format!(
+                    "{n} hashes, at most {} per batch",
+                    shared.cfg.max_batch
+                )));
+            }
+            let hashes = (0..n).map(|_| c.hash()).collect::<io::Result<Vec<_>>>()?;
+            let (resp, items) = batch::stamp_batch(&shared.client, &hashes)?;
+            shared.clock.observe(&resp, &shared.client);
+            put_stamp(&mut out, &resp)?;
+            out.extend_from_slice(&hex::decode(&resp.input_hash).unwrap_or_default());
+            out.extend_from_slice(&(items.len() as u32).to_be_bytes());
+            for i in &items {
+                put_inclusion(&mut out, i);
+            }
+        }
+        OP_TIME => {
+            let r = shared
+                .clock
+                .now()
+                .ok_or_else(|| TimestampError::Remote("no beacon round yet".into()))?;
+            let time_us = r
+                .time
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64;
+            out.extend_from_slice(&time_us.to_be_bytes());
+            out.extend_from_slice(&r.uncert_us.to_be_bytes());
+            out.extend_from_slice(&(r.age.as_millis() as u64).to_be_bytes());
+            out.push(r.stale as u8);
+        }
+        OP_HEALTH => {
+            let h = health(shared);
+            out.push(h.ready as u8);
+            out.extend_from_slice(&h.admitted_sources.to_be_bytes());
+            out.extend_from_slice(&h.quorum.to_be_bytes());
+            out.push(h.halted as u8);
+        }
+        op => return Err(TimestampError::Config(format!("unknown op {op:#04x}"))),
+    }
+    Ok(out)
+}
+
+/// Same readiness rule as `http`'s `/healthz`.
+fn health(shared: &Shared) -> Health {
+    let client = &shared.client;
+    let tracker = client.health();
+    let admitted = client
+        .sources()
+        .iter()
+        .filter(|s| tracker.admits(s.name()))
+        .count();
+    let quorum = client.options().min_quorum.unwrap_or(1);
+    let fresh = shared.clock.now().is_some_and(|r| !r.stale);
+    let halted = client
+        .guard()
+        .is_some_and(|g| matches!(g.status().signal, GuardSignal::Halt(_)));
+    Health {
+        ready: fresh && admitted >= quorum && !halted,
+        admitted_sources: admitted as u32,
+        quorum: quorum as u32,
+        halted,
+    }
+}
+
+// -------------------------------------------------------------------------
+// Client
+
+/// A stamp as the daemon reports it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    pub timestamp_us: u64,
+    pub uncert_us: u64,
+    pub local_us: u64,
+    pub bumped_us: u64,
+    /// Over the batch root, not the hash itself.
+    pub proof: Proof,
+    /// STAMP only: the hash's path to `proof.hash`.
+    pub inclusion: Option<Inclusion>,
+}
+
+/// One stamp over the batch root, plus each hash's path to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchStamp {
+    pub stamp: Stamp,
+    pub items: Vec<Inclusion>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeReading {
+    pub time: SystemTime,
+    pub uncert_us: u64,
+    pub age: Duration,
+    pub stale: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Health {
+    pub ready: bool,
+    pub admitted_sources: u32,
+    pub quorum: u32,
+    pub halted: bool,
+}
+
+/// Connection to a running `IpcServer`; requests go one at a time.
+pub struct IpcClient {
+    stream: UnixStream,
+}
+
+impl IpcClient {
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self {
+            stream: UnixStream::connect(path)?,
+        })
+    }
+
+    /// Read / write timeout per request (`None` = wait forever).
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
+
+    pub fn stamp(&mut self, hash: [u8; 32]) -> Result<Stamp, TimestampError> {
+        let mut req = vec![OP_STAMP];
+        req.extend_from_slice(&hash);
+        let reply = self.call(&req)?;
+        let mut c = Cursor(&reply);
+        let mut stamp = get_stamp(&mut c)?;
+        stamp.inclusion = Some(get_inclusion(&mut c, &stamp.proof.hash)?);
+        Ok(stamp)
+    }
+
+    pub fn stamp_batch(&mut self, hashes: &[[u8; 32]]) -> Result<BatchStamp, TimestampError> {
+        let mut req = Vec::with_capacity(5 + 32 * hashes.len());
+        req.push(OP_BATCH);
+        req.extend_from_slice(&(hashes.len() as u32).to_be_bytes());
+        for h in hashes {
+            req.extend_from_slice(h);
+        }
+        let reply = self.call(&req)?;
+        let mut c = Cursor(&reply);
+        let stamp = get_stamp(&mut c)?;
+        let root = hex::encode(c.hash()?);
+        let n = c.u32()? as usize;
+        let items = (0..n)
+            .map(|_| get_inclusion(&mut c, &root))
+            .collect::<io::Result<_>>()?;
+        Ok(BatchStamp { stamp, items })
+    }
+
+    pub fn time(&mut self) -> Result<TimeReading, TimestampError> {
+        let reply = self.call(&[OP_TIME])?;
+        let mut c = Cursor(&reply);
+        Ok(TimeReading {
+            time: UNIX_EPOCH + Duration::from_micros(c.u64()?),
+            uncert_us: c.u64()?,
+            age: Duration::from_millis(c.u64()?),
+            stale: c.u8()? != 0,
+        })
+    }
+
+    pub fn health(&mut self) -> Result<Health, TimestampError> {
+        let reply = self.call(&[OP_HEALTH])?;
+        let mut c = Cursor(&reply);
+        Ok(Health {
+            ready: c.u8()? != 0,
+            admitted_sources: c.u32()?,
+            quorum: c.u32()?,
+            halted: c.u8()? != 0,
+        })
+    }
+
+    /// Send `req`, return the OK payload or the daemon's error.
+    fn call(&mut self, req: &[u8]) -> Result<Vec<u8>, TimestampError> {
+        write_frame(&mut self.stream, req)?;
+        let reply = read_frame(&mut self.stream, usize::MAX)?;
+        let mut c = Cursor(&reply);
+        match c.u8()? {
+            STATUS_OK => Ok(c.rest().to_vec()),
+            _ => Err(TimestampError::Remote(
+                String::from_utf8_lossy(c.rest()).into_owned(),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MockSource;
+
+    fn client() -> TimestampClient {
+        TimestampClient::builder()
+            .source(Arc::new(MockSource::new("a", 1_000)))
+            .build()
+            .unwrap()
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rt_ping-ipc-{name}-{}.sock", std::process::id()))
+    }
+
+    fn start(cfg: IpcConfig) -> (ShutdownHandle, thread::JoinHandle<io::Result<()>>) {
+        let server = IpcServer::bind(cfg, client()).unwrap();
+        let stop = server.shutdown_handle();
+        (stop, thread::spawn(move || server.serve()))
+    }
+
+    #[test]
+    fn strangers_are_refused_by_credentials() {
+        let shared = Shared {
+            cfg: IpcConfig {
+                allow_uids: vec![1001],
+                allow_gids: vec![2002],
+                ..Default::default()
+            },
+            client: client(),
+            clock: CorrectedClock::new(Duration::from_secs(1)),
+            batcher: Batcher::new(client(), BatchConfig::default()),
+            own_uid: 1000,
+        };
+        let peer = |uid, gid| PeerCred {
+            pid: None,
+            uid,
+            gid,
+        };
+        assert!(shared.admits(&peer(0, 0)));
+        assert!(shared.admits(&peer(1000, 5)));
+        assert!(shared.admits(&peer(1001, 5)));
+        assert!(shared.admits(&peer(4242, 2002)));
+        assert!(!shared.admits(&peer(4242, 5)));
+        assert!(!shared.admits(&peer(65534, 65534)));
+    }
+
+    #[test]
+    fn peer_credentials_come_from_the_kernel() {
+        let (a, _b) = UnixStream::pair().unwrap();
+        let cred = peer_cred(&a).unwrap();
+        assert_eq!(cred.uid, unsafe { libc::geteuid() });
+        assert_eq!(cred.gid, unsafe { libc::getegid() });
+    }
+
+    #[test]
+    fn socket_gets_the_configured_mode() {
+        let path = socket_path("mode");
+        let (stop, server) = start(IpcConfig {
+            path: path.clone(),
+            mode: 0o600,
+            ..Default::default()
+        });
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+        stop.shutdown();
+        server.join().unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn concurrent_stamps_share_one_beacon_round() {
+        let path = socket_path("batch");
+        let (stop, server) = start(IpcConfig {
+            path: path.clone(),
+            batch: BatchConfig {
+                max_items: 3,
+                max_wait: Duration::from_secs(5),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        // sealed by size: three connections, one round
+        let stamps: Vec<_> = (1..=3u8)
+            .map(|i| {
+                let path = path.clone();
+                thread::spawn(move || IpcClient::connect(&path).unwrap().stamp([i; 32]))
+            })
+            .collect();
+        let mut roots = Vec::new();
+        for (i, t) in (1..=3u8).zip(stamps) {
+            let stamp = t.join().unwrap().unwrap();
+            let inclusion = stamp.inclusion.unwrap();
+            assert!(inclusion.verify(&[i; 32]));
+            assert_eq!(inclusion.root, stamp.proof.hash);
+            roots.push(inclusion.root);
+        }
+        roots.dedup();
+        assert_eq!(roots.len(), 1, "{roots:?}");
+        stop.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn oversized_frames_are_refused_and_the_connection_dropped() {
+        let path = socket_path("frame");
+        let (stop, server) = start(IpcConfig {
+            path: path.clone(),
+            max_frame: 64,
+            ..Default::default()
+        });
+        let mut c = IpcClient::connect(&path).unwrap();
+        c.set_timeout(Some(Duration::from_secs(5))).unwrap();
+        let err = c.stamp_batch(&[[1; 32]; 4]).unwrap_err();
+        assert!(err.to_string().contains("limit 64"), "{err}");
+        assert!(c.health().is_err());
+
+        // a frame within the limit still goes through on a new connection
+        let mut c = IpcClient::connect(&path).unwrap();
+        let stamp = c.stamp([2; 32]).unwrap();
+        let inclusion = stamp.inclusion.unwrap();
+        assert!(inclusion.verify(&[2; 32]));
+        assert_eq!(inclusion.root, stamp.proof.hash);
+        stop.shutdown();
+        server.join().unwrap().unwrap();
+    }
+}
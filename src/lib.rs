@@ -1,29 +1,108 @@
 //! rt_timestamp 0.2 – latency-adjusted Roughtime querier.
 
 use chrono::{DateTime, Local, Utc};
-use roughenough::{merkle::MerkleTree, RtMessage, Tag};
 use std::{
-    convert::TryInto,
-    net::UdpSocket,
+    io,
+    net::SocketAddr,
     sync::{Arc, Barrier},
     thread,
     time::{Duration, Instant, SystemTime},
 };
 
+#[cfg(unix)]
+pub mod chrony;
+pub mod batch;
+pub mod budget;
+pub mod client;
+pub mod clock;
+pub mod config;
+pub mod corrected;
+pub mod drift;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod guard;
+pub mod health;
+pub mod hedge;
+pub mod http;
+#[cfg(unix)]
+pub mod ipc;
+pub mod log;
+pub mod monotonic;
+pub mod multipath;
+pub mod net;
+pub mod ntp;
+pub mod proof;
+pub mod ratelimit;
+pub mod rto;
+pub mod server;
+pub mod shutdown;
+#[cfg(unix)]
+pub mod shm;
+pub mod sim;
+pub mod source;
+mod timestamping;
+pub mod wire;
+
+use clock::{Clock, SystemClock};
+use source::{Evidence, TimeSource};
+use wire::Version;
+
 // -------------------------------------------------------------------------
 // Public structs
 
 #[derive(Debug, serde::Serialize)]
 pub struct TimestampResponse {
     pub input_hash: String,
-    pub timestamp: u64,        // median true-time (µs since epoch)
+    pub timestamp: u64,        // server-anchored time (µs since epoch), see `basis`
+    pub basis: TimestampBasis,
+    pub local_timestamp: u64,  // our own clock at the same instant (µs since epoch)
     pub metadata: Metadata,
 }
 
+/// What `TimestampResponse::timestamp` is derived from.
+#[derive(Debug, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampBasis {
+    /// Median of the beacons' signed `MIDP`s, each carried to the common
+    /// reference instant (`local_timestamp`) by its latency-adjusted offset,
+    /// i.e. `local_timestamp + median(offset_us)`.
+    MedianMidpoint,
+    /// As above, but the offsets are averaged with weights 1 / uncert².
+    WeightedMidpoint,
+}
+
 #[derive(Debug, serde::Serialize)]
 pub struct Metadata {
-    pub beacons: [BeaconMeta; 2],
-    pub drift_us: u64,
+    pub beacons: Vec<BeaconMeta>,       // quorum: what `timestamp` is built from
+    pub cross_checks: Vec<BeaconMeta>,  // consulted, but not counted
+    pub drift_us: u64,                  // max offset spread within the quorum
+    /// Largest |offset − quorum median| among `cross_checks`.
+    pub cross_check_delta_us: Option<u64>,
+    /// Backup beacons in `beacons`, queried because primaries were slow
+    /// (`hedge`).
+    pub hedged: u32,
+    /// How far `timestamp` was raised to stay above the previous stamp
+    /// (`monotonic`), µs.
+    pub bumped_us: u64,
+}
+
+impl TimestampResponse {
+    /// Anchored − local time, µs, as the beacons measured it: the
+    /// monotonic bump (`Metadata::bumped_us`) is not part of the offset.
+    pub fn offset_us(&self) -> i128 {
+        self.timestamp.saturating_sub(self.metadata.bumped_us) as i128 - self.local_timestamp as i128
+    }
+}
+
+impl Metadata {
+    /// Bound on the anchored timestamp: the widest per-beacon bound, since
+    /// the median of honest beacons lies within each of their intervals.
+    pub fn combined_uncert_us(&self) -> u64 {
+        self.beacons
+            .iter()
+            .map(|b| b.uncert_us.max(0) as u64)
+            .max()
+            .unwrap_or(0)
+    }
 }
 
 #[derive(Debug, serde::Serialize, Clone)]
@@ -31,9 +110,165 @@ pub struct BeaconMeta {
     pub host: String,
     pub rtt_ms: f64,
     pub true_time: SystemTime,
+    pub midpoint_us: u64,      // signed MIDP as sent by the server
     pub offset_us: i128,
     pub uncert_us: i128,
     pub radius_us: u32,
+    pub timing: TimingSource,
+    pub kind: BeaconKind,
+    pub authenticated: bool,   // signed reply (Roughtime) vs. plain SNTP
+    pub evidence: Evidence,
+    pub addr: Option<SocketAddr>, // address that answered (network sources)
+    /// Offset spread across paths (`multipath::MultiPathSource`), µs.
+    pub asymmetry_us: Option<u64>,
+    pub paths: Vec<multipath::PathSample>, // per-path answers, if multi-path
+}
+
+#[derive(Debug, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum BeaconKind {
+    Roughtime,
+    /// Unauthenticated; `midpoint_us` is the server's (rx + tx) / 2 and
+    /// `radius_us` its root dispersion + ½ root delay.
+    Sntp,
+    /// Our own clock (`source::LocalClockSource`).
+    LocalClock,
+    /// Canned answers (`source::MockSource`).
+    Mock,
+    /// Out-of-tree `TimeSource` implementations.
+    Custom,
+}
+
+/// Which beacons may form the quorum behind `timestamp`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QuorumPolicy {
+    /// Roughtime only; SNTP answers land in `cross_checks`.
+    #[default]
+    AuthenticatedOnly,
+    /// Every beacon that answered counts.
+    Any,
+}
+
+/// Where the send/receive instants behind `rtt_ms` and `true_time` came from.
+#[derive(Debug, serde::Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum TimingSource {
+    /// `SystemTime::now()` / `Instant::now()` around `send_to` / `recv_from`.
+    UserSpace,
+    /// Kernel RX stamp, user-space send time (no TX stamp came back).
+    KernelRx,
+    /// Kernel software TX and RX stamps (`SO_TIMESTAMPING`).
+    Kernel,
+}
+
+/// How the quorum's offsets become one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Aggregation {
+    #[default]
+    Median,
+    /// Inverse-variance weighted mean (tight beacons count more).
+    InverseVariance,
+}
+
+/// How the 32-byte hash becomes the Roughtime nonce.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NonceDerivation {
+    /// Hash, zero-padded to 64 bytes (classic) / as-is (IETF).
+    #[default]
+    PaddedHash,
+    /// Classic only: hash followed by 32 random bytes, so replies cannot be
+    /// precomputed for a known hash.  IETF nonces stay the bare hash.
+    HashPlusRandom,
+}
+
+/// Knobs for a `get_timestamp_with` call; `client::TimestampClient` keeps
+/// one of these for reuse.
+#[derive(Debug, Clone)]
+pub struct ProbeOptions {
+    /// Use kernel software timestamps where available (Linux); falls back to
+    /// user-space timing otherwise.
+    pub kernel_timestamps: bool,
+    pub quorum: QuorumPolicy,
+    /// Clock for user-space send/receive readings; `None` = `SystemClock`.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Deadline for one query, across all of its address attempts.
+    pub timeout: Duration,
+    /// Largest reply datagram accepted, bytes; anything longer is refused
+    /// rather than read truncated.
+    pub max_reply: usize,
+    /// Extra attempts after an I/O failure (not after a bad proof).
+    pub retries: u32,
+    /// Local address probes bind to; `None` = the wildcard of whichever
+    /// family the server address has.  Restricts probes to that family.
+    pub bind: Option<SocketAddr>,
+    /// Network interface to pin probes to (Linux `SO_BINDTODEVICE`).
+    pub interface: Option<String>,
+    pub ip: net::IpPreference,
+    /// `None`: every authenticated source must answer.  `Some(n)`: failures
+    /// are tolerated while at least `n` beacons make the quorum.
+    pub min_quorum: Option<usize>,
+    pub aggregation: Aggregation,
+    pub nonce: NonceDerivation,
+    /// Record outcomes here and skip ejected sources.
+    pub health: Option<Arc<health::HealthTracker>>,
+    /// Per-server adaptive timeouts, used once a server has RTT history;
+    /// `timeout` still caps them.
+    pub rto: Option<Arc<rto::RtoEstimator>>,
+    /// Per-server token buckets; every attempt to a remote source takes a
+    /// token.  Defaults to the process-wide `ratelimit::shared()`.
+    pub rate_limit: Option<Arc<ratelimit::RateLimiter>>,
+}
+
+impl Default for ProbeOptions {
+    fn default() -> Self {
+        Self {
+            kernel_timestamps: false,
+            quorum: QuorumPolicy::default(),
+            clock: None,
+            timeout: Duration::from_secs(3),
+            max_reply: 4096,
+            retries: 0,
+            bind: None,
+            interface: None,
+            ip: net::IpPreference::default(),
+            min_quorum: None,
+            aggregation: Aggregation::default(),
+            nonce: NonceDerivation::default(),
+            health: None,
+            rto: None,
+            rate_limit: Some(ratelimit::shared()),
+        }
+    }
+}
+
+impl ProbeOptions {
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_deref().unwrap_or(&SystemClock)
+    }
+
+    /// Whether `server` takes part in the next round: not ejected by
+    /// `health`, and not out of tokens under `OverLimit::Failover`.
+    pub fn admits(&self, server: &str) -> bool {
+        self.health.as_ref().is_none_or(|h| h.admits(server))
+            && self.rate_limit.as_ref().is_none_or(|r| {
+                r.policy() != ratelimit::OverLimit::Failover || r.available(server)
+            })
+    }
+
+    /// Under `OverLimit::FailFast`, fail before a round in which any of
+    /// `sources` would be over its budget.
+    pub(crate) fn check_rate<'a>(
+        &self,
+        sources: impl IntoIterator<Item = &'a Arc<dyn TimeSource>>,
+    ) -> Result<(), TimestampError> {
+        match &self.rate_limit {
+            Some(limit) => limit.check_all(
+                sources.into_iter().filter(|s| s.remote()).map(|s| s.name()),
+            ),
+            None => Ok(()),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -44,12 +279,41 @@ pub enum TimestampError {
     Join,
     #[error("All probes failed")]
     NoProbes,
+    #[error("Bad proof: {0}")]
+    BadProof(String),
+    #[error("Quorum not met: {got} of {need} beacons answered")]
+    Quorum { got: usize, need: usize },
+    #[error("Config: {0}")]
+    Config(String),
+    /// Deadline passed before the bound reached the target (`budget`);
+    /// `best_us` is `None` if no quorum answered at all.
+    #[error(
+        "Uncertainty budget missed: best bound {}, wanted ±{target_us} µs",
+        .best_us.map_or("none".into(), |b| format!("±{b} µs"))
+    )]
+    Budget { best_us: Option<u64>, target_us: u64 },
+    /// Refused by a `guard::ClockGuard`.
+    #[error(
+        "Unsafe time estimate: {}",
+        .0.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("; ")
+    )]
+    Unsafe(Vec<guard::Violation>),
+    /// Reported by the daemon on the other end of `ipc`.
+    #[error("Daemon: {0}")]
+    Remote(String),
+    /// `server`'s token bucket is empty (`ratelimit`).
+    #[error("Rate limited: {server} has no query budget for {retry_after:?}")]
+    RateLimited { server: String, retry_after: Duration },
+    /// An NTP server's kiss-o'-death (stratum 0, RFC 5905 §7.4), e.g.
+    /// `RATE` or `DENY`; never retried.
+    #[error("Kiss-o'-death from {server}: {code}")]
+    KissOfDeath { server: String, code: String },
 }
 
 // -------------------------------------------------------------------------
 // Constants & helpers
 
-const DEFAULT_HOSTS: [&str; 2] = [
+pub const DEFAULT_HOSTS: [&str; 2] = [
     "roughtime.cloudflare.com:2003",
     "time.cloudflare.com:2003",
 ];
@@ -61,11 +325,48 @@ fn pad_nonce(hash: [u8; 32]) -> Vec<u8> {
     v
 }
 
+/// 32 random bytes for callers that need a nonce rather than a hash stamp.
+fn fresh_nonce() -> std::io::Result<[u8; 32]> {
+    use std::io::Read;
+    let mut n = [0u8; 32];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut n)?;
+    Ok(n)
+}
+
 #[inline]
 fn rt_to_io(e: roughenough::Error) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, format!("{:?}", e))
 }
 
+/// Median of the per-beacon offsets (mean of the middle pair for even n).
+fn median_offset_us(offsets: &[i128]) -> i128 {
+    let mut v = offsets.to_vec();
+    v.sort_unstable();
+    let n = v.len();
+    if n == 0 {
+        0
+    } else if n % 2 == 1 {
+        v[n / 2]
+    } else {
+        (v[n / 2 - 1] + v[n / 2]) / 2
+    }
+}
+
+/// Offsets averaged with weights 1 / uncert² (uncert floored at 1 µs).
+fn weighted_offset_us(beacons: &[BeaconMeta]) -> i128 {
+    let (mut num, mut den) = (0.0f64, 0.0f64);
+    for b in beacons {
+        let w = 1.0 / (b.uncert_us.max(1) as f64).powi(2);
+        num += w * b.offset_us as f64;
+        den += w;
+    }
+    if den > 0.0 {
+        (num / den).round() as i128
+    } else {
+        0
+    }
+}
+
 #[inline]
 fn sys_to_us(t: SystemTime) -> u64 {
     t.duration_since(SystemTime::UNIX_EPOCH)
@@ -80,101 +381,445 @@ pub fn get_timestamp(hash: [u8; 32]) -> Result<TimestampResponse, TimestampError
     get_timestamp_custom(hash, &DEFAULT_HOSTS)
 }
 
+/// One round against classic Roughtime `hosts`, through a default
+/// `client::TimestampClient` (and so the shared rate limiter).
 pub fn get_timestamp_custom(
     hash: [u8; 32],
     hosts: &[&str; 2],
 ) -> Result<TimestampResponse, TimestampError> {
-    let nonce = Arc::new(pad_nonce(hash));
-    let gate  = Arc::new(Barrier::new(3));            // 2 workers + main
+    hosts
+        .iter()
+        .fold(client::TimestampClient::builder(), |b, h| b.roughtime(*h, None))
+        .build()?
+        .timestamp(hash)
+}
+
+pub fn get_timestamp_with(
+    hash: [u8; 32],
+    hosts: &[&str],
+    opts: &ProbeOptions,
+) -> Result<TimestampResponse, TimestampError> {
+    get_timestamp_cross_checked(hash, hosts, &[], opts)
+}
+
+/// Classic Roughtime `hosts` plus unauthenticated SNTP `sntp_hosts`.
+pub fn get_timestamp_cross_checked(
+    hash: [u8; 32],
+    hosts: &[&str],
+    sntp_hosts: &[&str],
+    opts: &ProbeOptions,
+) -> Result<TimestampResponse, TimestampError> {
+    let mut sources: Vec<Arc<dyn TimeSource>> = Vec::with_capacity(hosts.len() + sntp_hosts.len());
+    for h in hosts {
+        sources.push(Arc::new(source::RoughtimeSource::classic(*h)));
+    }
+    for h in sntp_hosts {
+        sources.push(Arc::new(source::SntpSource::new(*h)));
+    }
+    get_timestamp_from(hash, &sources, opts)
+}
+
+/// Query every source at once and aggregate.  `opts.quorum` decides whether
+/// unauthenticated sources count or only cross-check.  A failing
+/// authenticated source fails the call; a silent unauthenticated one is
+/// skipped.
+pub fn get_timestamp_from(
+    hash: [u8; 32],
+    sources: &[Arc<dyn TimeSource>],
+    opts: &ProbeOptions,
+) -> Result<TimestampResponse, TimestampError> {
+    // ejected (or rate-limited) sources sit out, unless that would leave nobody
+    let admitted: Vec<&Arc<dyn TimeSource>> = sources.iter().filter(|s| opts.admits(s.name())).collect();
+    let sources = if admitted.is_empty() { sources.iter().collect() } else { admitted };
+    opts.check_rate(sources.iter().copied())?;
+
+    let gate = Arc::new(Barrier::new(sources.len() + 1));     // workers + main
 
-    let h1 = spawn_probe(hosts[0].into(), nonce.clone(), gate.clone());
-    let h2 = spawn_probe(hosts[1].into(), nonce.clone(), gate.clone());
+    let handles: Vec<_> = sources
+        .iter()
+        .map(|s| (*s, spawn_query((*s).clone(), hash, gate.clone(), opts.clone())))
+        .collect();
 
     gate.wait();                                      // launch simultaneously
 
-    let p1 = h1.join().map_err(|_| TimestampError::Join)??;
-    let p2 = h2.join().map_err(|_| TimestampError::Join)??;
+    let fails_over =
+        opts.rate_limit.as_ref().is_some_and(|r| r.policy() == ratelimit::OverLimit::Failover);
+    let mut limited = None;
+    let mut beacons = Vec::with_capacity(handles.len());
+    let mut unauthenticated = Vec::new();
+    for (source, h) in handles {
+        let res = h.join().map_err(|_| TimestampError::Join)?;
+        match res {
+            Ok(b) if source.authenticated() => beacons.push(b),
+            Ok(b) => unauthenticated.push(b),
+            // admitted with a token to spare, but another caller took it
+            // first: sit out as if never admitted
+            Err(e @ TimestampError::RateLimited { .. }) if fails_over => {
+                limited.get_or_insert(e);
+            }
+            Err(e) if source.authenticated() && opts.min_quorum.is_none() => return Err(e),
+            Err(_) => {}
+        }
+    }
+    let cross_checks = match opts.quorum {
+        QuorumPolicy::AuthenticatedOnly => unauthenticated,
+        QuorumPolicy::Any => {
+            beacons.extend(unauthenticated);
+            Vec::new()
+        }
+    };
+    if beacons.is_empty() {
+        return Err(limited.unwrap_or(TimestampError::NoProbes));
+    }
+    if let Some(need) = opts.min_quorum {
+        if beacons.len() < need {
+            return Err(TimestampError::Quorum { got: beacons.len(), need });
+        }
+    }
+
+    let resp = aggregate(hash, beacons, cross_checks, opts.aggregation);
+    if let Some(health) = &opts.health {
+        health.observe(&resp);
+    }
+    Ok(resp)
+}
 
-    // median-of-2 (earlier true_time wins)
-    let (median_st, _) = if p1.true_time <= p2.true_time {
-        (p1.true_time, p1.clone())
-    } else {
-        (p2.true_time, p2.clone())
+/// Anchored response over `beacons` (non-empty).
+pub(crate) fn aggregate(
+    hash: [u8; 32],
+    beacons: Vec<BeaconMeta>,
+    cross_checks: Vec<BeaconMeta>,
+    policy: Aggregation,
+) -> TimestampResponse {
+    let offsets: Vec<i128> = beacons.iter().map(|b| b.offset_us).collect();
+    let median = median_offset_us(&offsets);
+    let (offset, basis) = match policy {
+        Aggregation::Median => (median, TimestampBasis::MedianMidpoint),
+        Aggregation::InverseVariance => {
+            (weighted_offset_us(&beacons), TimestampBasis::WeightedMidpoint)
+        }
     };
 
-    let drift_us = (p1.offset_us - p2.offset_us).abs() as u64;
+    // common reference instant: the earliest local true_time
+    let local_us = beacons.iter().map(|b| sys_to_us(b.true_time)).min().unwrap_or(0);
+    let anchored = local_us as i128 + offset;
+
+    let spread = offsets.iter().max().unwrap_or(&0) - offsets.iter().min().unwrap_or(&0);
+    let cross_check_delta_us = cross_checks
+        .iter()
+        .map(|c| (c.offset_us - median).unsigned_abs() as u64)
+        .max();
 
-    Ok(TimestampResponse {
+    TimestampResponse {
         input_hash: hex::encode(hash),
-        timestamp: sys_to_us(median_st),
+        timestamp: anchored.max(0) as u64,
+        basis,
+        local_timestamp: local_us,
         metadata: Metadata {
-            beacons: [p1, p2],
-            drift_us,
+            beacons,
+            cross_checks,
+            drift_us: spread as u64,
+            cross_check_delta_us,
+            hedged: 0,
+            bumped_us: 0,
         },
-    })
+    }
 }
 
 // -------------------------------------------------------------------------
 // Thread worker
 
-fn spawn_probe(
-    host: String,
-    nonce: Arc<Vec<u8>>,
+fn spawn_query(
+    source: Arc<dyn TimeSource>,
+    hash: [u8; 32],
     gate: Arc<Barrier>,
+    opts: ProbeOptions,
 ) -> thread::JoinHandle<Result<BeaconMeta, TimestampError>> {
     thread::spawn(move || {
-        let packet = build_packet(&nonce)?;
-        let sock   = UdpSocket::bind("0.0.0.0:0")?;
-        sock.set_read_timeout(Some(Duration::from_secs(3)))?;
-
         gate.wait();
+        run_query(&*source, &hash, &opts)
+    })
+}
 
-        let t_send_wall = SystemTime::now();
-        let t_send_inst = Instant::now();
-        sock.send_to(&packet, &host)?;
-        let mut buf = [0u8; 4096];
-        let (len, _) = sock.recv_from(&mut buf)?;
-        let rtt = t_send_inst.elapsed();
+/// `source.query` with `opts.retries` on I/O errors (and adaptive,
+/// doubling timeouts with `opts.rto`); the outcome goes to `opts.health`.
+/// Every attempt to a remote source first takes a token per request from
+/// `opts.rate_limit`; time spent waiting for them comes off the attempt's
+/// timeout.
+pub(crate) fn run_query(
+    source: &dyn TimeSource,
+    hash: &[u8; 32],
+    opts: &ProbeOptions,
+) -> Result<BeaconMeta, TimestampError> {
+    let mut attempt = 0;
+    let res = loop {
+        let mut timeout = opts.timeout;
+        if let (Some(limit), true) = (&opts.rate_limit, source.remote()) {
+            // our own limit, not the server's fault: no health record
+            for _ in 0..source.requests() {
+                timeout = timeout.saturating_sub(limit.acquire(source.name(), timeout)?);
+            }
+            if timeout.is_zero() {
+                return Err(TimestampError::RateLimited {
+                    server: source.name().to_string(),
+                    retry_after: Duration::ZERO,
+                });
+            }
+        }
+        let res = match &opts.rto {
+            Some(rto) => {
+                let base = rto.timeout_for(source.name()).unwrap_or(timeout);
+                let mut o = opts.clone();
+                o.timeout = rto.backoff(base, attempt).min(timeout);
+                source.query(hash, &o)
+            }
+            None if timeout < opts.timeout => {
+                let mut o = opts.clone();
+                o.timeout = timeout;
+                source.query(hash, &o)
+            }
+            None => source.query(hash, opts),
+        };
+        match res {
+            Err(TimestampError::Io(_)) if attempt < opts.retries => attempt += 1,
+            res => break res,
+        }
+    };
+    if let (Some(rto), Ok(b)) = (&opts.rto, &res) {
+        rto.observe(source.name(), Duration::from_secs_f64(b.rtt_ms / 1e3));
+    }
+    if let Some(health) = &opts.health {
+        match &res {
+            Ok(b) => {
+                let rtt = Duration::from_secs_f64(b.rtt_ms / 1e3);
+                health.record_success(source.name(), rtt)
+            }
+            Err(e) => health.record_failure(source.name(), e),
+        }
+    }
+    res
+}
 
-        parse_reply(&host, &nonce, &buf[..len], t_send_wall, rtt)
-    })
+/// One Roughtime round trip to `host` in wire format `version`.
+/// With `public_key`, the reply's signature chain is verified as well.
+pub(crate) fn query_roughtime(
+    host: &str,
+    version: Version,
+    public_key: Option<&[u8]>,
+    hash: &[u8; 32],
+    opts: &ProbeOptions,
+) -> Result<BeaconMeta, TimestampError> {
+    let nonce = match (version, opts.nonce) {
+        (Version::Classic, NonceDerivation::PaddedHash) => pad_nonce(*hash),
+        (Version::Classic, NonceDerivation::HashPlusRandom) => {
+            let mut n = hash.to_vec();
+            n.extend_from_slice(&fresh_nonce()?);
+            n
+        }
+        (Version::Ietf, _) => hash.to_vec(),
+    };
+    let packet = wire::build_request(version, &nonce)?;
+
+    let mut buf = vec![0u8; opts.max_reply + 1];
+    let ex = exchange(opts, &packet, host, &mut buf)?;
+    let reply = &buf[..ex.len];
+    if let Some(pk) = public_key {
+        let parsed = wire::parse_reply(reply)?;
+        wire::verify_reply(&parsed, pk)
+            .map_err(|e| TimestampError::BadProof(format!("{host}: {e}")))?;
+    }
+    let mut meta = parse_reply(host, version, &nonce, reply, ex.t_send_wall, ex.rtt, ex.timing)?;
+    meta.addr = Some(ex.addr);
+    Ok(meta)
 }
 
-// -------------------------------------------------------------------------
-// Packet build & parse
+/// One SNTP round trip to `host`.
+pub(crate) fn query_sntp(host: &str, opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError> {
+    let req = ntp::NtpPacket {
+        version: 4,
+        mode: ntp::MODE_CLIENT,
+        tx_ts: ntp::to_ntp_ts(opts.clock().wall()),
+        ..Default::default()
+    };
+    let mut buf = vec![0u8; opts.max_reply + 1];
+    let ex = exchange(opts, &req.encode(), host, &mut buf)?;
+    let mut meta =
+        ntp::beacon_from_reply(host, &req, &buf[..ex.len], ex.t_send_wall, ex.rtt, ex.timing)?;
+    meta.addr = Some(ex.addr);
+    Ok(meta)
+}
+
+struct Exchange {
+    len: usize,
+    addr: SocketAddr,
+    t_send_wall: SystemTime,
+    rtt: Duration,
+    timing: TimingSource,
+}
 
-fn build_packet(nonce: &[u8]) -> Result<Vec<u8>, TimestampError> {
-    let mut req = RtMessage::with_capacity(2);
-    req.add_field(Tag::NONC, nonce).map_err(rt_to_io)?;
-    req.add_field(Tag::PAD, &[]).map_err(rt_to_io)?;
-    let pad = vec![0u8; req.calculate_padding_length()];
-    req.clear();
-    req.add_field(Tag::NONC, nonce).map_err(rt_to_io)?;
-    req.add_field(Tag::PAD, &pad).map_err(rt_to_io)?;
-    Ok(req.encode().map_err(rt_to_io)?)
+/// One outstanding request in the address race.
+struct Attempt {
+    sock: std::net::UdpSocket,
+    kernel: bool,
+    addr: SocketAddr,
+    t_send_wall: SystemTime,
+    t_send_inst: Instant,
+    /// Kernel TX stamp, if it was drained before the reply arrived.
+    tx: Option<SystemTime>,
 }
 
-fn parse_reply(
+/// One request/response round trip with the best timing available.
+///
+/// Happy Eyeballs over `net::candidates(host)`: a new address joins the
+/// race every `net::ATTEMPT_DELAY` until one answers or `opts.timeout`
+/// runs out.  Timing is taken from the attempt that answered.  `buf` is
+/// one byte larger than the largest acceptable reply: a datagram that
+/// fills it may have been cut short and is refused.
+fn exchange(
+    opts: &ProbeOptions,
+    packet: &[u8],
     host: &str,
-    nonce: &[u8],
-    buf: &[u8],
+    buf: &mut [u8],
+) -> Result<Exchange, TimestampError> {
+    let clock = opts.clock();
+    let addrs = net::candidates(host, opts)?;
+    // real time, not `clock`: a simulated clock need not move
+    let deadline = Instant::now() + opts.timeout;
+    let mut pending = addrs.into_iter();
+    let mut attempts: Vec<Attempt> = Vec::new();
+    let mut last_err = None;
+
+    loop {
+        let more = pending.len() > 0;
+        if let Some(addr) = pending.next() {
+            match start_attempt(addr, opts, clock, packet) {
+                Ok(a) => attempts.push(a),
+                Err(e) => {
+                    last_err = Some(e);
+                    continue; // unreachable family etc.: next address at once
+                }
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline || (attempts.is_empty() && !more) {
+            return Err(last_err
+                .unwrap_or_else(|| {
+                    io::Error::new(io::ErrorKind::TimedOut, format!("{host}: no reply"))
+                })
+                .into());
+        }
+        let left = deadline - now;
+        let wait = if pending.len() > 0 { left.min(net::ATTEMPT_DELAY) } else { left };
+        let socks: Vec<&std::net::UdpSocket> = attempts.iter().map(|a| &a.sock).collect();
+        match net::wait_readable(&socks, wait)? {
+            net::Ready::None => {}
+            net::Ready::Readable(i) => match finish_attempt(&attempts[i], clock, buf) {
+                Ok(ex) => return Ok(ex),
+                Err(e) => {
+                    attempts.remove(i);
+                    last_err = Some(e);
+                }
+            },
+            net::Ready::Error(i) => {
+                // ICMP unreachable: drop this address; a TX stamp: keep it
+                let a = &mut attempts[i];
+                let err = a.sock.take_error()?;
+                let stamp = match err {
+                    None if a.kernel => timestamping::tx_stamp(&a.sock),
+                    _ => None,
+                };
+                match (err, stamp) {
+                    (None, Some(tx)) => a.tx = Some(tx),
+                    (err, _) => {
+                        let e = err.unwrap_or_else(|| {
+                            io::Error::other(format!("{}: socket error", a.addr))
+                        });
+                        attempts.remove(i);
+                        last_err = Some(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn start_attempt(
+    addr: SocketAddr,
+    opts: &ProbeOptions,
+    clock: &dyn Clock,
+    packet: &[u8],
+) -> io::Result<Attempt> {
+    let sock = net::socket_for(addr, opts)?;
+    let kernel = opts.kernel_timestamps && timestamping::try_enable(&sock);
+    let t_send_wall = clock.wall();
+    let t_send_inst = clock.mono();
+    sock.send(packet)?;
+    Ok(Attempt {
+        sock,
+        kernel,
+        addr,
+        t_send_wall,
+        t_send_inst,
+        tx: None,
+    })
+}
+
+fn finish_attempt(a: &Attempt, clock: &dyn Clock, buf: &mut [u8]) -> io::Result<Exchange> {
+    let (len, rx) = if a.kernel {
+        timestamping::recv(&a.sock, buf)?
+    } else {
+        (a.sock.recv(buf)?, None)
+    };
+    if len >= buf.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: reply over {} bytes", a.addr, buf.len().saturating_sub(1)),
+        ));
+    }
+    let rtt = clock.mono().saturating_duration_since(a.t_send_inst);
+
+    let (t_send_wall, rtt, timing) = if a.kernel {
+        let mut k = timestamping::exchange_stamps(&a.sock, rx);
+        k.tx = a.tx.or(k.tx);
+        pick_timing(k, a.t_send_wall, rtt)
+    } else {
+        (a.t_send_wall, rtt, TimingSource::UserSpace)
+    };
+    Ok(Exchange { len, addr: a.addr, t_send_wall, rtt, timing })
+}
+
+/// Prefer kernel stamps; keep the user-space reading for any side that is
+/// missing or inconsistent (RX before TX).
+fn pick_timing(
+    k: timestamping::KernelStamps,
     t_send_wall: SystemTime,
     rtt: Duration,
-) -> Result<BeaconMeta, TimestampError> {
-    let resp = RtMessage::from_bytes(buf).map_err(rt_to_io)?;
-    let srep = RtMessage::from_bytes(resp.get_field(Tag::SREP).unwrap()).map_err(rt_to_io)?;
-
-    let radius_us =
-        u32::from_le_bytes(srep.get_field(Tag::RADI).unwrap()[..4].try_into().unwrap());
-    let mid_us =
-        u64::from_le_bytes(srep.get_field(Tag::MIDP).unwrap()[..8].try_into().unwrap());
+) -> (SystemTime, Duration, TimingSource) {
+    match (k.tx, k.rx) {
+        (Some(tx), Some(rx)) => match rx.duration_since(tx) {
+            Ok(d) => (tx, d, TimingSource::Kernel),
+            Err(_) => (t_send_wall, rtt, TimingSource::UserSpace),
+        },
+        (None, Some(rx)) => match rx.duration_since(t_send_wall) {
+            Ok(d) => (t_send_wall, d, TimingSource::KernelRx),
+            Err(_) => (t_send_wall, rtt, TimingSource::UserSpace),
+        },
+        _ => (t_send_wall, rtt, TimingSource::UserSpace),
+    }
+}
 
-    // Merkle inclusion proof
-    let idx = u32::from_le_bytes(resp.get_field(Tag::INDX).unwrap()[..4].try_into().unwrap());
-    let path = resp.get_field(Tag::PATH).unwrap();
-    let root = MerkleTree::new_sha512_google().root_from_paths(idx as usize, nonce, path);
-    assert_eq!(root, srep.get_field(Tag::ROOT).unwrap(), "Merkle path invalid");
+// -------------------------------------------------------------------------
+// Reply parse
 
+/// Latency-adjusted reading of one reply, assuming a symmetric path:
+/// `(true_time, offset_us, uncert_us)` with `true_time = send + ½ RTT`,
+/// `offset = MIDP − true_time`, `uncert = radius + ½ RTT`.
+pub(crate) fn measure(
+    t_send_wall: SystemTime,
+    rtt: Duration,
+    mid_us: u64,
+    radius_us: u32,
+) -> (SystemTime, i128, i128) {
     let half_rtt  = Duration::from_micros((rtt.as_micros() / 2) as u64);
     let true_time = t_send_wall + half_rtt;
     let mid_wall  = SystemTime::UNIX_EPOCH + Duration::from_micros(mid_us);
@@ -183,32 +828,201 @@ fn parse_reply(
         Ok(d)  =>  d.as_micros() as i128,
         Err(e) => -(e.duration().as_micros() as i128),
     };
+    (true_time, offset_us, radius_us as i128 + half_rtt.as_micros() as i128)
+}
+
+fn parse_reply(
+    host: &str,
+    version: Version,
+    nonce: &[u8],
+    buf: &[u8],
+    t_send_wall: SystemTime,
+    rtt: Duration,
+    timing: TimingSource,
+) -> Result<BeaconMeta, TimestampError> {
+    let reply = wire::parse_reply(buf)?;
+    if reply.version != version {
+        return Err(TimestampError::BadProof(format!("{host}: answered in {:?}", reply.version)));
+    }
+
+    wire::verify_inclusion(&reply, nonce)
+        .map_err(|e| TimestampError::BadProof(format!("{host}: {e}")))?;
+
+    let mid_us    = reply.midpoint_us;
+    let radius_us = reply.radius_us.min(u32::MAX as u64) as u32;
+    let (true_time, offset_us, uncert_us) = measure(t_send_wall, rtt, mid_us, radius_us);
 
     Ok(BeaconMeta {
-        host: host.to_string(),                 // ← fixed line
+        host: host.to_string(),
         rtt_ms: rtt.as_secs_f64() * 1e3,
         true_time,
+        midpoint_us: mid_us,
         offset_us,
-        uncert_us: radius_us as i128 + half_rtt.as_micros() as i128,
+        uncert_us,
         radius_us,
+        timing,
+        kind: BeaconKind::Roughtime,
+        authenticated: true,
+        evidence: Evidence::Roughtime {
+            version,
+            nonce: hex::encode(nonce),
+            reply: hex::encode(buf),
+        },
+        addr: None,
+        asymmetry_us: None,
+        paths: Vec::new(),
     })
 }
 
 // -------------------------------------------------------------------------
-// Pretty-printer for quick manual test (optional)
+// Pretty-printer (the CLI's `text` output format)
 
-#[allow(dead_code)]
-fn print(resp: &TimestampResponse) {
+pub fn print(resp: &TimestampResponse) {
     println!("input hash  : {}", resp.input_hash);
-    println!("timestamp   : {}", resp.timestamp);
+    println!("timestamp   : {}  ({:?})", resp.timestamp, resp.basis);
+    println!("local clock : {}", resp.local_timestamp);
     for (i, b) in resp.metadata.beacons.iter().enumerate() {
         let dt_utc: DateTime<Utc> = b.true_time.into();
         let dt_loc: DateTime<Local> = b.true_time.into();
-        println!("-- Beacon {i}  {host}", host = b.host);
+        println!("-- Beacon {i}  {host}  ({kind:?})", host = b.host, kind = b.kind);
         println!("   RTT          : {:.3} ms", b.rtt_ms);
         println!("   true-time    : {dt_utc}  (local {dt_loc})");
+        println!("   midpoint     : {} µs", b.midpoint_us);
         println!("   offset       : {:+} µs", b.offset_us);
         println!("   uncert       : ±{} µs  (radius + ½ RTT)", b.uncert_us);
+        println!("   timing       : {:?}", b.timing);
+        if let Some(addr) = b.addr {
+            println!("   address      : {addr}");
+        }
+        for p in &b.paths {
+            println!(
+                "   path         : {:+} µs  (±{} µs, RTT {:.3} ms)  via {}",
+                p.offset_us, p.uncert_us, p.rtt_ms, p.path
+            );
+        }
+        if let Some(a) = b.asymmetry_us {
+            println!("   asymmetry    : {a} µs between paths");
+        }
     }
     println!("drift (adj) : {} µs", resp.metadata.drift_us);
+    for c in &resp.metadata.cross_checks {
+        println!("-- Cross-check {}  ({:?}, unauthenticated)", c.host, c.kind);
+        println!("   offset       : {:+} µs  (±{} µs)", c.offset_us, c.uncert_us);
+    }
+    if let Some(d) = resp.metadata.cross_check_delta_us {
+        println!("cross-check : {} µs from quorum", d);
+    }
+    if resp.metadata.hedged > 0 {
+        println!("hedged      : {} backup beacon(s) used", resp.metadata.hedged);
+    }
+    if resp.metadata.bumped_us > 0 {
+        println!("bumped      : +{} µs to stay monotonic", resp.metadata.bumped_us);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamping::KernelStamps;
+
+    fn at(ms: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000) + Duration::from_millis(ms)
+    }
+
+    const USER_RTT: Duration = Duration::from_millis(12);
+
+    fn beacon(host: &str, true_ms: u64, offset_us: i128, uncert_us: i128) -> BeaconMeta {
+        BeaconMeta {
+            host: host.into(),
+            rtt_ms: 2.0,
+            true_time: at(true_ms),
+            midpoint_us: (sys_to_us(at(true_ms)) as i128 + offset_us) as u64,
+            offset_us,
+            uncert_us,
+            radius_us: 0,
+            timing: TimingSource::UserSpace,
+            kind: BeaconKind::Mock,
+            authenticated: true,
+            evidence: Evidence::None,
+            addr: None,
+            asymmetry_us: None,
+            paths: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn aggregate_anchors_the_median_at_the_earliest_reading() {
+        let beacons = vec![
+            beacon("a", 5, 100, 50),
+            beacon("b", 2, -50, 50),
+            beacon("c", 9, 400, 50),
+        ];
+        let cross = vec![beacon("ntp", 3, 700, 50)];
+        let resp = aggregate([1; 32], beacons, cross, Aggregation::Median);
+
+        // every beacon's offset is carried to the earliest local instant
+        assert_eq!(resp.local_timestamp, sys_to_us(at(2)));
+        assert_eq!(resp.timestamp, sys_to_us(at(2)) + 100);
+        assert_eq!(resp.basis, TimestampBasis::MedianMidpoint);
+        assert_eq!(resp.metadata.drift_us, 450);
+        assert_eq!(resp.metadata.cross_check_delta_us, Some(600));
+        assert_eq!(resp.metadata.combined_uncert_us(), 50);
+    }
+
+    #[test]
+    fn aggregate_averages_an_even_median_and_weights_by_uncertainty() {
+        let two = || vec![beacon("a", 0, 0, 100), beacon("b", 0, 1_000, 200)];
+        let resp = aggregate([1; 32], two(), Vec::new(), Aggregation::Median);
+        assert_eq!(resp.offset_us(), 500);
+
+        // weights 1/100² and 1/200²: 1000 · 1 / (4 + 1)
+        let resp = aggregate([1; 32], two(), Vec::new(), Aggregation::InverseVariance);
+        assert_eq!(resp.offset_us(), 200);
+        assert_eq!(resp.basis, TimestampBasis::WeightedMidpoint);
+    }
+
+    #[test]
+    fn offset_ignores_the_monotonic_bump() {
+        let one = vec![beacon("a", 0, -300, 10)];
+        let mut resp = aggregate([1; 32], one, Vec::new(), Aggregation::Median);
+        assert_eq!(resp.offset_us(), -300);
+        resp.timestamp += 40;
+        resp.metadata.bumped_us = 40;
+        assert_eq!(resp.offset_us(), -300);
+
+        // a bump larger than the stamp cannot wrap around
+        resp.timestamp = 10;
+        resp.metadata.bumped_us = 40;
+        assert_eq!(resp.offset_us(), -(resp.local_timestamp as i128));
+    }
+
+    #[test]
+    fn kernel_stamps_on_both_sides_win() {
+        let k = KernelStamps { tx: Some(at(1)), rx: Some(at(10)) };
+        let (send, rtt, timing) = pick_timing(k, at(0), USER_RTT);
+        assert_eq!((send, rtt, timing), (at(1), Duration::from_millis(9), TimingSource::Kernel));
+    }
+
+    #[test]
+    fn rx_alone_is_measured_from_the_user_space_send() {
+        let k = KernelStamps { tx: None, rx: Some(at(10)) };
+        let (send, rtt, timing) = pick_timing(k, at(0), USER_RTT);
+        assert_eq!((send, rtt, timing), (at(0), Duration::from_millis(10), TimingSource::KernelRx));
+    }
+
+    #[test]
+    fn inconsistent_or_missing_stamps_fall_back_to_user_space() {
+        let user = (at(0), USER_RTT, TimingSource::UserSpace);
+        // RX before TX
+        let k = KernelStamps { tx: Some(at(5)), rx: Some(at(4)) };
+        assert_eq!(pick_timing(k, at(0), USER_RTT), user);
+        // RX before the user-space send
+        let k = KernelStamps { tx: None, rx: Some(SystemTime::UNIX_EPOCH) };
+        assert_eq!(pick_timing(k, at(0), USER_RTT), user);
+        // TX alone says nothing about the reply
+        let k = KernelStamps { tx: Some(at(1)), rx: None };
+        assert_eq!(pick_timing(k, at(0), USER_RTT), user);
+        let k = KernelStamps { tx: None, rx: None };
+        assert_eq!(pick_timing(k, at(0), USER_RTT), user);
+    }
 }
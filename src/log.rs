@@ -0,0 +1,80 @@
+//! Daemon log lines on stderr, filtered by one process-wide level.
+//!
+//! The binary sets the level from `log.level` (see `config`) before
+//! starting a daemon; library users get `Info` unless they call
+//! `set_level`.  Failures go through `error!`, status lines through
+//! `info!`.
+
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    /// Nothing at all.
+    Quiet,
+    /// Failures only.
+    Error,
+    /// Failures and status lines.
+    #[default]
+    Info,
+    /// Also the effective configuration at startup.
+    Debug,
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Quiet,
+        1 => LogLevel::Error,
+        2 => LogLevel::Info,
+        _ => LogLevel::Debug,
+    }
+}
+
+/// Whether lines at `at` are written.
+pub fn enabled(at: LogLevel) -> bool {
+    at != LogLevel::Quiet && at <= level()
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Error) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::LogLevel::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+pub(crate) use {error, info};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_include_everything_below_them() {
+        let before = level();
+        set_level(LogLevel::Error);
+        assert!(enabled(LogLevel::Error));
+        assert!(!enabled(LogLevel::Info));
+        set_level(LogLevel::Debug);
+        assert!(enabled(LogLevel::Info) && enabled(LogLevel::Debug));
+        set_level(LogLevel::Quiet);
+        assert!(!enabled(LogLevel::Error));
+        assert!(!enabled(LogLevel::Quiet), "quiet is not a level to log at");
+        set_level(before);
+    }
+}
@@ -1 +1,196 @@
-fn main(){let nonce=[42u8;32];println!("{:?}",rt_ping::get_timestamp(nonce).unwrap());}
+use rt_ping::{
+    config::{Config, OutputFormat, ServerEntry},
+    log::{self, LogLevel},
+    TimestampResponse,
+};
+use std::{env, path::PathBuf, time::Duration};
+
+/// Command line split into positionals, `--config` and config overrides.
+struct CliArgs {
+    args: Vec<String>,
+    config: Option<PathBuf>,
+    flags: Vec<(String, String)>,
+}
+
+/// Accepts `--key value` and `--key=value` anywhere on the line.
+fn split_args(raw: impl Iterator<Item = String>) -> anyhow::Result<CliArgs> {
+    let (mut args, mut config, mut flags) = (Vec::new(), None, Vec::new());
+    let mut raw = raw.peekable();
+    while let Some(a) = raw.next() {
+        let Some(opt) = a.strip_prefix("--") else {
+            args.push(a);
+            continue;
+        };
+        let (key, value) = match opt.split_once('=') {
+            Some((k, v)) => (k.to_string(), v.to_string()),
+            None => {
+                let v = raw
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--{opt} needs a value"))?;
+                (opt.to_string(), v)
+            }
+        };
+        if key == "config" {
+            config = Some(PathBuf::from(value));
+        } else {
+            flags.push((key, value));
+        }
+    }
+    Ok(CliArgs { args, config, flags })
+}
+
+fn emit(cfg: &Config, resp: &TimestampResponse) -> anyhow::Result<()> {
+    match cfg.output.format {
+        OutputFormat::Debug => println!("{:?}", resp),
+        OutputFormat::Text => rt_ping::print(resp),
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(resp)?),
+    }
+    Ok(())
+}
+
+/// The servers stamp through the configured limiter; say which.
+fn log_rate_limit(cfg: &Config) {
+    if !log::enabled(LogLevel::Info) {
+        return;
+    }
+    let rl = &cfg.rate_limit;
+    if rl.enabled {
+        eprintln!(
+            "rate limit  : burst {} then {}/min per server, {:?} when over",
+            rl.burst, rl.per_minute, rl.policy
+        );
+    } else {
+        eprintln!("rate limit  : off");
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let CliArgs { args, config, flags } = split_args(env::args().skip(1))?;
+    let mut cfg = Config::load(config.as_deref(), &flags)?;
+    log::set_level(cfg.log.level);
+    if log::enabled(LogLevel::Debug) {
+        eprintln!("config      : {:?}", cfg);
+    }
+    match args.first().map(String::as_str) {
+        // rt_ping chrony [socket] [interval-secs]
+        Some("chrony") => {
+            let sock = args.get(1).map(String::as_str).unwrap_or(rt_ping::chrony::DEFAULT_SOCK);
+            let every = args.get(2).map(|s| s.parse()).transpose()?.unwrap_or(16);
+            rt_ping::chrony::run(sock, &cfg.client()?, Duration::from_secs(every))?;
+        }
+        // rt_ping shm [path] [interval-secs] [octal-mode]
+        Some("shm") => {
+            let mut shm = rt_ping::shm::ShmConfig::default();
+            if let Some(path) = args.get(1) {
+                shm.path = path.into();
+            }
+            if let Some(secs) = args.get(2) {
+                shm.every = Duration::from_secs(secs.parse()?);
+                shm.max_age = shm.every * 4;
+            }
+            if let Some(mode) = args.get(3) {
+                shm.mode = u32::from_str_radix(mode, 8)?;
+            }
+            rt_ping::shm::run(&shm, &cfg.client()?)?;
+        }
+        // rt_ping http-server [bind-addr]
+        Some("http-server") => {
+            let mut http = rt_ping::http::HttpConfig { keys: cfg.public_keys(), ..Default::default() };
+            if let Some(bind) = args.get(1) {
+                http.bind = bind.clone();
+            }
+            let server = rt_ping::http::HttpServer::bind(http, cfg.client()?)?;
+            log_rate_limit(&cfg);
+            rt_ping::shutdown::on_signals(server.shutdown_handle());
+            if log::enabled(LogLevel::Info) {
+                eprintln!("http        : listening on {}", server.local_addr()?);
+            }
+            server.serve()?;
+        }
+        // rt_ping grpc-server [bind-addr]
+        #[cfg(feature = "grpc")]
+        Some("grpc-server") => {
+            let mut grpc = rt_ping::grpc::GrpcConfig { keys: cfg.public_keys(), ..Default::default() };
+            if let Some(bind) = args.get(1) {
+                grpc.bind = bind.clone();
+            }
+            let stop = rt_ping::shutdown::ShutdownHandle::default();
+            rt_ping::shutdown::on_signals(stop.clone());
+            log_rate_limit(&cfg);
+            rt_ping::grpc::serve(grpc, cfg.client()?, stop)?;
+        }
+        // rt_ping ipc-server [socket-path]
+        Some("ipc-server") => {
+            let mut ipc = rt_ping::ipc::IpcConfig::default();
+            if let Some(path) = args.get(1) {
+                ipc.path = path.into();
+            }
+            let server = rt_ping::ipc::IpcServer::bind(ipc, cfg.client()?)?;
+            log_rate_limit(&cfg);
+            rt_ping::shutdown::on_signals(server.shutdown_handle());
+            if log::enabled(LogLevel::Info) {
+                eprintln!("ipc         : listening on {}", server.path().display());
+            }
+            server.serve()?;
+        }
+        // rt_ping ntp-server [bind-addr]
+        Some("ntp-server") => {
+            let mut ntp = rt_ping::ntp::NtpServerConfig::default();
+            if let Some(bind) = args.get(1) {
+                ntp.bind = bind.clone();
+            }
+            rt_ping::ntp::serve(&ntp, cfg.client()?)?;
+        }
+        // rt_ping roughtime-server [bind-addr] [key-file]
+        Some("roughtime-server") => {
+            let mut server = rt_ping::server::ServerConfig::default();
+            if let Some(bind) = args.get(1) {
+                server.bind = bind.clone();
+            }
+            if let Some(key) = args.get(2) {
+                server.key_file = key.into();
+            }
+            rt_ping::server::serve(server, cfg.client()?)?;
+        }
+        // rt_ping cross-check [sntp-host:port ...]
+        Some("cross-check") => {
+            let sntp: Vec<&str> = if args.len() > 1 {
+                args[1..].iter().map(String::as_str).collect()
+            } else {
+                vec!["pool.ntp.org:123"]
+            };
+            // extra servers of the same client, so guard and issuer still apply
+            for h in sntp {
+                cfg.servers.push(ServerEntry {
+                    spec: format!("sntp://{h}"),
+                    public_key: None,
+                    burst: None,
+                    per_minute: None,
+                });
+            }
+            emit(&cfg, &cfg.client()?.timestamp([42u8; 32])?)?;
+        }
+        // rt_ping stamp <64-hex-hash> [source-spec ...]
+        Some("stamp") => {
+            let hash: [u8; 32] = hex::decode(args.get(1).map(String::as_str).unwrap_or(""))?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("hash must be 32 bytes (64 hex chars)"))?;
+            if args.len() > 2 {
+                // as if given with --servers
+                cfg.set("servers", &args[2..].join(","), "stamp")?;
+            }
+            emit(&cfg, &cfg.client()?.timestamp(hash)?)?;
+        }
+        #[cfg(not(feature = "grpc"))]
+        Some("grpc-server") => anyhow::bail!("grpc-server: built without the `grpc` feature"),
+        Some(other) => anyhow::bail!(
+            "unknown command {other:?}; expected chrony, shm, http-server, grpc-server, \
+             ipc-server, ntp-server, roughtime-server, cross-check or stamp"
+        ),
+        None => {
+            let nonce = [42u8; 32];
+            emit(&cfg, &cfg.client()?.timestamp(nonce)?)?;
+        }
+    }
+    Ok(())
+}
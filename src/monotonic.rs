@@ -0,0 +1,248 @@
+//! Strictly increasing timestamps that survive restarts.
+//!
+//! Each `get_timestamp` is a fresh estimate, so two calls (or two runs) can
+//! go backwards.  `MonotonicIssuer` hands out `max(estimate, last + 1 µs)`
+//! and says by how much it had to raise the estimate.  The high-water mark
+//! lives in a file, written with fsync before any value above it is
+//! returned.  To avoid an fsync per stamp the mark is reserved `reserve`
+//! ahead of the last issued value; after a restart issuing resumes above
+//! the reservation, so a crash can cost up to `reserve` of forward jump but
+//! never a repeat.
+
+use crate::TimestampResponse;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Default distance between the last issued stamp and the durable mark.
+pub const DEFAULT_RESERVE: Duration = Duration::from_secs(1);
+
+/// One issued value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Issued {
+    /// µs since epoch; greater than every value issued before.
+    pub timestamp_us: u64,
+    /// How far the estimate was raised to keep the order (0 = not at all).
+    pub bumped_us: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    last_us: u64,
+    /// Durable high-water mark; nothing above it has been issued.
+    persisted_us: u64,
+    issued: u64,
+    bumps: u64,
+}
+
+#[derive(Debug)]
+pub struct MonotonicIssuer {
+    path: PathBuf,
+    reserve: Duration,
+    state: Mutex<State>,
+}
+
+impl MonotonicIssuer {
+    /// Load the mark from `path` (a missing file starts from zero).
+    pub fn open(path: impl AsRef<Path>, reserve: Duration) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mark = match fs::read_to_string(&path) {
+            Ok(text) => text.trim().parse::<u64>().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: bad high-water mark: {e}", path.display()),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path,
+            reserve,
+            state: Mutex::new(State {
+                last_us: mark,
+                persisted_us: mark,
+                issued: 0,
+                bumps: 0,
+            }),
+        })
+    }
+
+    /// Next value for an estimate of `estimate_us`.  Fails, issuing
+    /// nothing, when the mark cannot be made durable.
+    pub fn issue(&self, estimate_us: u64) -> io::Result<Issued> {
+        let mut st = self.state.lock().unwrap();
+        let next = estimate_us.max(st.last_us.saturating_add(1));
+        if next > st.persisted_us {
+            let mark = next.saturating_add(self.reserve.as_micros() as u64);
+            write_durably(&self.path, mark)?;
+            st.persisted_us = mark;
+        }
+        st.last_us = next;
+        st.issued += 1;
+        let bumped_us = next - estimate_us.min(next);
+        if bumped_us > 0 {
+            st.bumps += 1;
+        }
+        Ok(Issued {
+            timestamp_us: next,
+            bumped_us,
+        })
+    }
+
+    /// `issue` for a response: rewrites `timestamp` and records the bump
+    /// in `Metadata::bumped_us`.
+    pub fn stamp(&self, resp: &mut TimestampResponse) -> io::Result<Issued> {
+        let issued = self.issue(resp.timestamp)?;
+        resp.timestamp = issued.timestamp_us;
+        resp.metadata.bumped_us = issued.bumped_us;
+        Ok(issued)
+    }
+
+    /// Last value issued (or the mark loaded at `open`).
+    pub fn last(&self) -> u64 {
+        self.state.lock().unwrap().last_us
+    }
+
+    /// (values issued, of which bumped) since `open`.
+    pub fn counts(&self) -> (u64, u64) {
+        let st = self.state.lock().unwrap();
+        (st.issued, st.bumps)
+    }
+}
+
+/// Replace the file with `mark`: temp file, fsync, rename, fsync the
+/// directory so the rename itself is durable.
+fn write_durably(path: &Path, mark: u64) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut f = File::create(&tmp)?;
+    writeln!(f, "{mark}")?;
+    f.sync_all()?;
+    drop(f);
+    fs::rename(&tmp, path)?;
+    #[cfg(unix)]
+    {
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mark_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rt_ping-mark-{name}-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn mark(path: &Path) -> u64 {
+        fs::read_to_string(path).unwrap().trim().parse().unwrap()
+    }
+
+    #[test]
+    fn a_wall_clock_step_back_is_bumped_not_repeated() {
+        let path = mark_file("step");
+        let m = MonotonicIssuer::open(&path, DEFAULT_RESERVE).unwrap();
+        let first = m.issue(5_000_000).unwrap();
+        assert_eq!(
+            first,
+            Issued {
+                timestamp_us: 5_000_000,
+                bumped_us: 0
+            }
+        );
+        // the estimate steps back 2 s, then repeats itself
+        let back = m.issue(3_000_000).unwrap();
+        assert_eq!(
+            back,
+            Issued {
+                timestamp_us: 5_000_001,
+                bumped_us: 2_000_001
+            }
+        );
+        let same = m.issue(5_000_001).unwrap();
+        assert_eq!(
+            same,
+            Issued {
+                timestamp_us: 5_000_002,
+                bumped_us: 1
+            }
+        );
+        // once the estimate is past the last value it is used as is
+        let ahead = m.issue(5_000_100).unwrap();
+        assert_eq!(
+            ahead,
+            Issued {
+                timestamp_us: 5_000_100,
+                bumped_us: 0
+            }
+        );
+        assert_eq!(m.counts(), (4, 2));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_mark_is_reserved_ahead_and_rewritten_past_it() {
+        let path = mark_file("reserve");
+        let m = MonotonicIssuer::open(&path, Duration::from_millis(10)).unwrap();
+        m.issue(1_000_000).unwrap();
+        assert_eq!(mark(&path), 1_010_000);
+        // inside the reservation nothing is written
+        fs::write(&path, "1010000 untouched\n").unwrap();
+        m.issue(1_010_000).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1010000 untouched\n");
+        // crossing it writes a new mark `reserve` past the new value
+        m.issue(1_010_001).unwrap();
+        assert_eq!(mark(&path), 1_020_001);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_restart_resumes_above_the_persisted_mark() {
+        let path = mark_file("restart");
+        {
+            let m = MonotonicIssuer::open(&path, Duration::from_millis(10)).unwrap();
+            m.issue(2_000_000).unwrap();
+            m.issue(2_000_500).unwrap();
+        }
+        // the new run's clock is behind what the old one issued
+        let m = MonotonicIssuer::open(&path, Duration::from_millis(10)).unwrap();
+        assert_eq!(m.last(), 2_010_000);
+        let next = m.issue(2_000_600).unwrap();
+        assert_eq!(next.timestamp_us, 2_010_001);
+        assert_eq!(next.bumped_us, 9_401);
+        assert_eq!(mark(&path), 2_020_001);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_bad_mark_file_is_refused() {
+        let path = mark_file("bad");
+        fs::write(&path, "soon\n").unwrap();
+        let err = MonotonicIssuer::open(&path, DEFAULT_RESERVE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("bad high-water mark"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn nothing_is_issued_when_the_mark_cannot_be_written() {
+        let path = std::env::temp_dir()
+            .join(format!("rt_ping-missing-{}", std::process::id()))
+            .join("mark");
+        let m = MonotonicIssuer::open(&path, DEFAULT_RESERVE).unwrap();
+        assert!(m.issue(1_000).is_err());
+        assert_eq!(m.last(), 0);
+        assert_eq!(m.counts(), (0, 0));
+    }
+}
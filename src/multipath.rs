@@ -0,0 +1,341 @@
+//! Multi-path probing: one beacon, several network paths.
+//!
+//! Half-RTT compensation is exact only on symmetric routes; an asymmetric
+//! one shifts the offset by (out − back) / 2 without showing up in the
+//! RTT.  Asking the same server over different source addresses,
+//! interfaces or address families rarely hits the same asymmetry twice, so
+//! the spread of the per-path offsets is a lower bound on the error the
+//! single-path estimate hides.  `MultiPathSource` reports that spread as
+//! `BeaconMeta::asymmetry_us` and widens `uncert_us` to cover every path.
+//! The offset itself stays the lowest-RTT path's, so that it still matches
+//! the midpoint and evidence it came with; every path's own offset is in
+//! `BeaconMeta::paths`.
+
+use crate::{net::IpPreference, source::TimeSource, BeaconMeta, ProbeOptions, TimestampError};
+use std::{io, net::SocketAddr, sync::Arc, thread};
+
+/// One way out of this host.  `None` fields keep the caller's setting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PathSpec {
+    pub bind: Option<SocketAddr>,
+    pub interface: Option<String>,
+    pub ip: Option<IpPreference>,
+}
+
+impl PathSpec {
+    /// Same server over IPv4 and over IPv6.
+    pub fn dual_stack() -> Vec<PathSpec> {
+        vec![
+            PathSpec {
+                ip: Some(IpPreference::V4Only),
+                ..Default::default()
+            },
+            PathSpec {
+                ip: Some(IpPreference::V6Only),
+                ..Default::default()
+            },
+        ]
+    }
+
+    /// `v4`, `v6`, `if:<name>` or `bind:<addr>`.
+    pub fn parse(s: &str) -> io::Result<PathSpec> {
+        let mut p = PathSpec::default();
+        match s.split_once(':') {
+            _ if s == "v4" => p.ip = Some(IpPreference::V4Only),
+            _ if s == "v6" => p.ip = Some(IpPreference::V6Only),
+            Some(("if", name)) if !name.is_empty() => p.interface = Some(name.to_string()),
+            Some(("bind", addr)) => {
+                p.bind = Some(addr.parse().map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("{addr:?}: {e}"))
+                })?)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown path {s:?} (want v4, v6, if:<name>, bind:<addr>)"),
+                ))
+            }
+        }
+        Ok(p)
+    }
+
+    pub fn label(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(ip) = self.ip {
+            parts.push(format!("{ip:?}"));
+        }
+        if let Some(dev) = &self.interface {
+            parts.push(format!("if:{dev}"));
+        }
+        if let Some(b) = self.bind {
+            parts.push(format!("bind:{b}"));
+        }
+        if parts.is_empty() {
+            "default".into()
+        } else {
+            parts.join(",")
+        }
+    }
+
+    fn apply(&self, opts: &ProbeOptions) -> ProbeOptions {
+        let mut o = opts.clone();
+        if self.bind.is_some() {
+            o.bind = self.bind;
+        }
+        if self.interface.is_some() {
+            o.interface = self.interface.clone();
+        }
+        if let Some(ip) = self.ip {
+            o.ip = ip;
+        }
+        o
+    }
+}
+
+/// One path's answer, as kept in `BeaconMeta::paths`.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PathSample {
+    pub path: String,
+    pub addr: Option<SocketAddr>,
+    pub rtt_ms: f64,
+    pub offset_us: i128,
+    pub uncert_us: i128,
+}
+
+/// Wraps a network source and queries it over each of `paths` at once.
+pub struct MultiPathSource {
+    pub inner: Arc<dyn TimeSource>,
+    pub paths: Vec<PathSpec>,
+}
+
+impl MultiPathSource {
+    pub fn new(inner: Arc<dyn TimeSource>, paths: Vec<PathSpec>) -> Self {
+        Self { inner, paths }
+    }
+}
+
+impl TimeSource for MultiPathSource {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn authenticated(&self) -> bool {
+        self.inner.authenticated()
+    }
+
+    fn remote(&self) -> bool {
+        self.inner.remote()
+    }
+
+    /// One request per path.
+    fn requests(&self) -> u32 {
+        self.paths.len() as u32 * self.inner.requests()
+    }
+
+    /// Fails only when every path does.
+    fn query(&self, hash: &[u8; 32], opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError> {
+        let results: Vec<_> = thread::scope(|s| {
+            let handles: Vec<_> = self
+                .paths
+                .iter()
+                .map(|p| {
+                    let o = p.apply(opts);
+                    s.spawn(move || (p.label(), self.inner.query(hash, &o)))
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join()).collect()
+        });
+
+        let mut metas = Vec::with_capacity(results.len());
+        let mut last_err = None;
+        for r in results {
+            match r.map_err(|_| TimestampError::Join)? {
+                (label, Ok(m)) => metas.push((label, m)),
+                (_, Err(e)) => last_err = Some(e),
+            }
+        }
+        if metas.is_empty() {
+            return Err(last_err.unwrap_or(TimestampError::NoProbes));
+        }
+        Ok(combine(metas))
+    }
+}
+
+/// Fold per-path answers into one meta: the lowest-RTT path's answer
+/// (offset, midpoint, timing and evidence agree with each other), its
+/// uncertainty widened to contain every path's interval, and the offset
+/// spread as asymmetry.
+pub fn combine(metas: Vec<(String, BeaconMeta)>) -> BeaconMeta {
+    let (lo, hi) = metas
+        .iter()
+        .fold((i128::MAX, i128::MIN), |(lo, hi), (_, m)| {
+            (lo.min(m.offset_us), hi.max(m.offset_us))
+        });
+    let paths: Vec<PathSample> = metas
+        .iter()
+        .map(|(label, m)| PathSample {
+            path: label.clone(),
+            addr: m.addr,
+            rtt_ms: m.rtt_ms,
+            offset_us: m.offset_us,
+            uncert_us: m.uncert_us,
+        })
+        .collect();
+    let (_, mut best) = metas
+        .into_iter()
+        .min_by(|(_, a), (_, b)| a.rtt_ms.total_cmp(&b.rtt_ms))
+        .expect("non-empty");
+    best.uncert_us = paths
+        .iter()
+        .map(|p| p.uncert_us + (p.offset_us - best.offset_us).abs())
+        .max()
+        .unwrap_or(best.uncert_us);
+    best.asymmetry_us = (paths.len() > 1).then(|| (hi - lo) as u64);
+    best.paths = paths;
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MockSource;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn meta(offset_us: i128, uncert_us: i128, rtt_ms: f64) -> BeaconMeta {
+        let mut m = MockSource::new("beacon", offset_us)
+            .query(&[0; 32], &ProbeOptions::default())
+            .unwrap();
+        m.uncert_us = uncert_us;
+        m.rtt_ms = rtt_ms;
+        m
+    }
+
+    #[test]
+    fn combine_keeps_the_fastest_paths_answer_and_widens_its_bound() {
+        let fast = meta(1_000, 200, 4.0);
+        let combined = combine(vec![
+            ("v4".into(), meta(1_600, 300, 9.0)),
+            ("v6".into(), fast.clone()),
+            ("if:wan1".into(), meta(700, 100, 6.0)),
+        ]);
+        assert_eq!(combined.offset_us, fast.offset_us);
+        assert_eq!(combined.midpoint_us, fast.midpoint_us);
+        assert_eq!(combined.true_time, fast.true_time);
+        assert_eq!(combined.rtt_ms, 4.0);
+        assert_eq!(combined.evidence, fast.evidence);
+        // 1600 ± 300 reaches 900 µs past 1000; 700 ± 100 only 400 µs
+        assert_eq!(combined.uncert_us, 900);
+        assert_eq!(combined.asymmetry_us, Some(900));
+        let offsets: Vec<_> = combined
+            .paths
+            .iter()
+            .map(|p| (p.path.as_str(), p.offset_us))
+            .collect();
+        assert_eq!(offsets, [("v4", 1_600), ("v6", 1_000), ("if:wan1", 700)]);
+    }
+
+    #[test]
+    fn agreeing_paths_leave_the_bound_alone() {
+        let combined = combine(vec![
+            ("v4".into(), meta(500, 300, 5.0)),
+            ("v6".into(), meta(500, 100, 7.0)),
+        ]);
+        assert_eq!(combined.offset_us, 500);
+        assert_eq!(combined.uncert_us, 300);
+        assert_eq!(combined.asymmetry_us, Some(0));
+    }
+
+    #[test]
+    fn one_path_is_reported_without_asymmetry() {
+        let combined = combine(vec![("v4".into(), meta(-250, 80, 3.0))]);
+        assert_eq!((combined.offset_us, combined.uncert_us), (-250, 80));
+        assert_eq!(combined.asymmetry_us, None);
+        assert_eq!(combined.paths.len(), 1);
+    }
+
+    /// Answers `offset_us` over IPv4 and `offset_us + skew` over IPv6;
+    /// fails over IPv6 when `skew` is `None`.
+    struct PerFamily {
+        offset_us: i128,
+        skew: Option<i128>,
+        queries: AtomicUsize,
+    }
+
+    impl TimeSource for PerFamily {
+        fn name(&self) -> &str {
+            "dual"
+        }
+
+        fn authenticated(&self) -> bool {
+            true
+        }
+
+        fn query(
+            &self,
+            hash: &[u8; 32],
+            opts: &ProbeOptions,
+        ) -> Result<BeaconMeta, TimestampError> {
+            self.queries.fetch_add(1, Ordering::SeqCst);
+            let offset = match (opts.ip, self.skew) {
+                (IpPreference::V6Only, None) => return Err(TimestampError::NoProbes),
+                (IpPreference::V6Only, Some(skew)) => self.offset_us + skew,
+                _ => self.offset_us,
+            };
+            let rtt = if opts.ip == IpPreference::V6Only {
+                2.0
+            } else {
+                5.0
+            };
+            let mut m = MockSource::new("dual", offset).query(hash, opts)?;
+            m.rtt_ms = rtt;
+            Ok(m)
+        }
+    }
+
+    fn dual(offset_us: i128, skew: Option<i128>) -> (Arc<PerFamily>, MultiPathSource) {
+        let inner = Arc::new(PerFamily {
+            offset_us,
+            skew,
+            queries: AtomicUsize::new(0),
+        });
+        let src = MultiPathSource::new(inner.clone(), PathSpec::dual_stack());
+        (inner, src)
+    }
+
+    #[test]
+    fn every_path_is_queried_and_folded() {
+        let (inner, src) = dual(2_000, Some(600));
+        let m = src.query(&[0; 32], &ProbeOptions::default()).unwrap();
+        assert_eq!(inner.queries.load(Ordering::SeqCst), 2);
+        assert_eq!(m.offset_us, 2_600, "IPv6 answered faster");
+        assert_eq!(m.asymmetry_us, Some(600));
+        assert_eq!(m.uncert_us, 1_000 + 600);
+        assert_eq!(m.paths[0].path, "V4Only");
+    }
+
+    #[test]
+    fn a_failed_path_is_left_out() {
+        let (_, src) = dual(2_000, None);
+        let m = src.query(&[0; 32], &ProbeOptions::default()).unwrap();
+        assert_eq!(m.offset_us, 2_000);
+        assert_eq!(m.asymmetry_us, None);
+        assert_eq!(m.paths.len(), 1);
+    }
+
+    #[test]
+    fn path_specs_parse() {
+        assert_eq!(
+            PathSpec::parse("v4").unwrap().ip,
+            Some(IpPreference::V4Only)
+        );
+        assert_eq!(
+            PathSpec::parse("if:wan0").unwrap().interface.as_deref(),
+            Some("wan0")
+        );
+        let bind = PathSpec::parse("bind:192.0.2.7:0").unwrap().bind;
+        assert_eq!(bind, Some("192.0.2.7:0".parse().unwrap()));
+        for bad in ["v5", "if:", "bind:nowhere", ""] {
+            assert!(PathSpec::parse(bad).is_err(), "{bad}");
+        }
+    }
+}
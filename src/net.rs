@@ -0,0 +1,341 @@
+//! Address resolution and probe sockets.
+//!
+//! Hosts are resolved once per `DNS_TTL` and the results ordered RFC 8305
+//! style: preferred family first, then alternating families.  `exchange`
+//! in the crate root sends to the first candidate and, if nothing has come
+//! back after `ATTEMPT_DELAY`, to the next one too, keeping earlier
+//! attempts alive; the first reply wins and its address is recorded in
+//! `BeaconMeta::addr`.
+
+use crate::ProbeOptions;
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// RFC 8305 "Connection Attempt Delay".
+pub const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// How long resolved addresses are reused.
+pub const DNS_TTL: Duration = Duration::from_secs(300);
+
+/// Address family preference for probes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpPreference {
+    /// Happy Eyeballs: IPv6 first, IPv4 close behind.
+    #[default]
+    Auto,
+    /// As `Auto`, but IPv4 first.
+    PreferV4,
+    V4Only,
+    V6Only,
+}
+
+type Cache = Mutex<HashMap<String, (Instant, Vec<SocketAddr>)>>;
+
+fn cache() -> &'static Cache {
+    static CACHE: OnceLock<Cache> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// `host:port` → addresses, through the process-wide cache.  A failed
+/// lookup falls back to an expired entry when there is one.
+pub fn resolve(host: &str) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let now = Instant::now();
+    let stale = match cache().lock().unwrap().get(host) {
+        Some((at, addrs)) if now.duration_since(*at) < DNS_TTL => return Ok(addrs.clone()),
+        Some((_, addrs)) => Some(addrs.clone()),
+        None => None,
+    };
+    match host.to_socket_addrs() {
+        Ok(it) => {
+            let addrs: Vec<SocketAddr> = it.collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{host}: no addresses"),
+                ));
+            }
+            cache()
+                .lock()
+                .unwrap()
+                .insert(host.to_string(), (now, addrs.clone()));
+            Ok(addrs)
+        }
+        Err(e) => stale.ok_or(e),
+    }
+}
+
+pub fn clear_dns_cache() {
+    cache().lock().unwrap().clear();
+}
+
+/// Order `addrs` by `pref`, interleaving families; drops excluded ones.
+pub fn order(addrs: &[SocketAddr], pref: IpPreference) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.iter().copied().partition(SocketAddr::is_ipv6);
+    let (first, second) = match pref {
+        IpPreference::Auto => (v6, v4),
+        IpPreference::PreferV4 => (v4, v6),
+        IpPreference::V4Only => (v4, Vec::new()),
+        IpPreference::V6Only => (v6, Vec::new()),
+    };
+    let mut out = Vec::with_capacity(first.len() + second.len());
+    let (mut a, mut b) = (first.into_iter(), second.into_iter());
+    loop {
+        match (a.next(), b.next()) {
+            (None, None) => break,
+            (x, y) => out.extend(x.into_iter().chain(y)),
+        }
+    }
+    out
+}
+
+/// Resolved, ordered candidates for `host` that `opts.bind` can reach.
+pub(crate) fn candidates(host: &str, opts: &ProbeOptions) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs = order(&resolve(host)?, opts.ip);
+    if let Some(bind) = opts.bind {
+        addrs.retain(|a| a.is_ipv6() == bind.is_ipv6());
+    }
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!(
+                "{host}: no address usable with {:?} / bind {:?}",
+                opts.ip, opts.bind
+            ),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// UDP socket connected to `target`, bound per `opts` (address, device).
+pub(crate) fn socket_for(target: SocketAddr, opts: &ProbeOptions) -> io::Result<UdpSocket> {
+    let local = match opts.bind {
+        Some(b) => b,
+        None if target.is_ipv6() => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        None => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+    };
+    let sock = UdpSocket::bind(local)?;
+    if let Some(dev) = &opts.interface {
+        bind_device(&sock, dev)?;
+    }
+    sock.set_read_timeout(Some(opts.timeout))?;
+    sock.connect(target)?;
+    Ok(sock)
+}
+
+/// Pin `sock` to a network interface (`SO_BINDTODEVICE`, needs
+/// `CAP_NET_RAW`).
+#[cfg(target_os = "linux")]
+pub fn bind_device(sock: &UdpSocket, name: &str) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let rc = unsafe {
+        libc::setsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            name.as_ptr() as *const libc::c_void,
+            name.len() as libc::socklen_t,
+        )
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_device(_sock: &UdpSocket, name: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("binding to interface {name:?} needs Linux"),
+    ))
+}
+
+/// What `wait_readable` saw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Ready {
+    /// Timed out (or interrupted).
+    None,
+    /// Socket `i` has a datagram.
+    Readable(usize),
+    /// Socket `i` has a pending error or error-queue entry (a TX stamp);
+    /// it stays flagged until consumed.
+    Error(usize),
+}
+
+/// Wait up to `timeout` for one of `socks` to become readable.
+#[cfg(unix)]
+pub(crate) fn wait_readable(socks: &[&UdpSocket], timeout: Duration) -> io::Result<Ready> {
+    use std::os::unix::io::AsRawFd;
+    let mut fds: Vec<libc::pollfd> = socks
+        .iter()
+        .map(|s| libc::pollfd {
+            fd: s.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    let ms = timeout.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+    let rc = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, ms) };
+    if rc == -1 {
+        return match io::Error::last_os_error() {
+            e if e.kind() == io::ErrorKind::Interrupted => Ok(Ready::None),
+            e => Err(e),
+        };
+    }
+    if let Some(i) = fds.iter().position(|f| f.revents & libc::POLLIN != 0) {
+        return Ok(Ready::Readable(i));
+    }
+    Ok(match fds.iter().position(|f| f.revents != 0) {
+        Some(i) => Ready::Error(i),
+        None => Ready::None,
+    })
+}
+
+/// Without `poll`: peek at every attempt in turn, non-blocking, until
+/// one has a datagram or `timeout` passes.
+#[cfg(not(unix))]
+pub(crate) fn wait_readable(socks: &[&UdpSocket], timeout: Duration) -> io::Result<Ready> {
+    const SPIN: Duration = Duration::from_millis(1);
+    // large enough that peeking a whole datagram never reports truncation
+    let mut buf = vec![0u8; 65_536];
+    for s in socks {
+        s.set_nonblocking(true)?;
+    }
+    let until = Instant::now() + timeout;
+    let ready = 'wait: loop {
+        for (i, s) in socks.iter().enumerate() {
+            match s.peek(&mut buf) {
+                Ok(_) => break 'wait Ready::Readable(i),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // e.g. ICMP port unreachable, reported on the next read
+                Err(_) => break 'wait Ready::Error(i),
+            }
+        }
+        if Instant::now() >= until {
+            break Ready::None;
+        }
+        std::thread::sleep(SPIN);
+    };
+    for s in socks {
+        s.set_nonblocking(false)?;
+    }
+    Ok(ready)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    fn mixed() -> Vec<SocketAddr> {
+        [
+            "192.0.2.1:2002",
+            "192.0.2.2:2002",
+            "[2001:db8::1]:2002",
+            "192.0.2.3:2002",
+        ]
+        .into_iter()
+        .map(addr)
+        .collect()
+    }
+
+    /// Put `addrs` in the cache for `host`, `age` old.
+    fn cached(host: &str, age: Duration, addrs: &[SocketAddr]) {
+        let at = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        cache()
+            .lock()
+            .unwrap()
+            .insert(host.to_string(), (at, addrs.to_vec()));
+    }
+
+    #[test]
+    fn order_interleaves_families_preferred_first() {
+        let v6 = addr("[2001:db8::1]:2002");
+        let v4 = |n: u8| SocketAddr::from(([192, 0, 2, n], 2002));
+        assert_eq!(
+            order(&mixed(), IpPreference::Auto),
+            [v6, v4(1), v4(2), v4(3)]
+        );
+        assert_eq!(
+            order(&mixed(), IpPreference::PreferV4),
+            [v4(1), v6, v4(2), v4(3)]
+        );
+        assert_eq!(order(&mixed(), IpPreference::V4Only), [v4(1), v4(2), v4(3)]);
+        assert_eq!(order(&mixed(), IpPreference::V6Only), [v6]);
+        assert!(order(&[], IpPreference::Auto).is_empty());
+    }
+
+    #[test]
+    fn literal_addresses_skip_the_resolver() {
+        assert_eq!(resolve("127.0.0.1:123").unwrap(), [addr("127.0.0.1:123")]);
+        assert_eq!(resolve("[::1]:123").unwrap(), [addr("[::1]:123")]);
+    }
+
+    #[test]
+    fn cached_names_are_reused_and_stale_ones_back_up_a_failed_lookup() {
+        cached("fresh.rt-ping.invalid:2002", Duration::ZERO, &mixed());
+        assert_eq!(resolve("fresh.rt-ping.invalid:2002").unwrap(), mixed());
+
+        // .invalid never resolves, so the expired entry is all there is
+        cached("stale.rt-ping.invalid:2002", DNS_TTL * 2, &mixed()[..1]);
+        assert_eq!(
+            resolve("stale.rt-ping.invalid:2002").unwrap(),
+            &mixed()[..1]
+        );
+    }
+
+    #[test]
+    fn candidates_follow_the_preference_and_the_bind_family() {
+        let host = "dual.rt-ping.invalid:2002";
+        cached(host, Duration::ZERO, &mixed());
+        let opts = |ip, bind: Option<&str>| ProbeOptions {
+            ip,
+            bind: bind.map(addr),
+            ..Default::default()
+        };
+
+        let got = candidates(host, &opts(IpPreference::Auto, None)).unwrap();
+        assert_eq!(got, order(&mixed(), IpPreference::Auto));
+
+        let got = candidates(host, &opts(IpPreference::Auto, Some("0.0.0.0:0"))).unwrap();
+        assert!(got.iter().all(SocketAddr::is_ipv4) && got.len() == 3);
+
+        let got = candidates(host, &opts(IpPreference::PreferV4, Some("[::]:0"))).unwrap();
+        assert_eq!(got, [addr("[2001:db8::1]:2002")]);
+
+        let err = candidates(host, &opts(IpPreference::V6Only, Some("0.0.0.0:0"))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        assert!(err.to_string().contains(host), "{err}");
+    }
+
+    #[test]
+    fn an_earlier_attempt_that_answers_is_seen() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let socks = [&first, &second];
+        assert_eq!(
+            wait_readable(&socks, Duration::from_millis(20)).unwrap(),
+            Ready::None
+        );
+
+        peer.send_to(b"late", first.local_addr().unwrap()).unwrap();
+        assert_eq!(
+            wait_readable(&socks, Duration::from_secs(1)).unwrap(),
+            Ready::Readable(0)
+        );
+    }
+}
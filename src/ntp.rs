@@ -0,0 +1,481 @@
+//! Minimal NTPv4 (RFC 5905) packet codec, an SNTP client for cross-check
+//! beacons, and an SNTP server that hands out Roughtime-corrected time to
+//! hosts that cannot speak Roughtime.
+
+use crate::{
+    client::TimestampClient, corrected::CorrectedClock, log, source::Evidence, BeaconKind,
+    BeaconMeta, TimestampError, TimingSource,
+};
+use std::{
+    io,
+    net::UdpSocket,
+    time::{Duration, SystemTime},
+};
+
+pub const PACKET_LEN: usize = 48;
+/// Seconds between the NTP era (1900) and the Unix epoch.
+const NTP_UNIX_DELTA: u64 = 2_208_988_800;
+
+pub const MODE_CLIENT: u8 = 3;
+pub const MODE_SERVER: u8 = 4;
+pub const LEAP_NONE: u8 = 0;
+pub const LEAP_ALARM: u8 = 3;
+pub const STRATUM_UNSYNC: u8 = 16;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NtpPacket {
+    pub leap: u8,
+    pub version: u8,
+    pub mode: u8,
+    pub stratum: u8,
+    pub poll: i8,
+    pub precision: i8,
+    pub root_delay: u32,      // NTP short format (16.16 s)
+    pub root_dispersion: u32, // NTP short format (16.16 s)
+    pub ref_id: [u8; 4],
+    pub ref_ts: u64,          // NTP timestamp format (32.32 s since 1900)
+    pub orig_ts: u64,
+    pub rx_ts: u64,
+    pub tx_ts: u64,
+}
+
+impl NtpPacket {
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < PACKET_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "short NTP packet"));
+        }
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+        Ok(Self {
+            leap: buf[0] >> 6,
+            version: (buf[0] >> 3) & 0x7,
+            mode: buf[0] & 0x7,
+            stratum: buf[1],
+            poll: buf[2] as i8,
+            precision: buf[3] as i8,
+            root_delay: u32_at(4),
+            root_dispersion: u32_at(8),
+            ref_id: buf[12..16].try_into().unwrap(),
+            ref_ts: u64_at(16),
+            orig_ts: u64_at(24),
+            rx_ts: u64_at(32),
+            tx_ts: u64_at(40),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; PACKET_LEN] {
+        let mut b = [0u8; PACKET_LEN];
+        b[0] = (self.leap << 6) | ((self.version & 0x7) << 3) | (self.mode & 0x7);
+        b[1] = self.stratum;
+        b[2] = self.poll as u8;
+        b[3] = self.precision as u8;
+        b[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        b[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        b[12..16].copy_from_slice(&self.ref_id);
+        b[16..24].copy_from_slice(&self.ref_ts.to_be_bytes());
+        b[24..32].copy_from_slice(&self.orig_ts.to_be_bytes());
+        b[32..40].copy_from_slice(&self.rx_ts.to_be_bytes());
+        b[40..48].copy_from_slice(&self.tx_ts.to_be_bytes());
+        b
+    }
+}
+
+pub fn to_ntp_ts(t: SystemTime) -> u64 {
+    let d = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs() + NTP_UNIX_DELTA;
+    let frac = ((d.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (secs << 32) | frac
+}
+
+pub fn from_ntp_ts(ts: u64) -> SystemTime {
+    let secs = (ts >> 32).saturating_sub(NTP_UNIX_DELTA);
+    let nanos = ((ts & 0xffff_ffff) * 1_000_000_000) >> 32;
+    SystemTime::UNIX_EPOCH + Duration::new(secs, nanos as u32)
+}
+
+pub fn us_to_short(us: u64) -> u32 {
+    ((us << 16) / 1_000_000).min(u32::MAX as u64) as u32
+}
+
+pub fn short_to_us(v: u32) -> u64 {
+    ((v as u64) * 1_000_000) >> 16
+}
+
+fn ntp_to_us(ts: u64) -> i128 {
+    let secs = (ts >> 32) as i128 - NTP_UNIX_DELTA as i128;
+    let frac_us = (((ts & 0xffff_ffff) * 1_000_000) >> 32) as i128;
+    secs * 1_000_000 + frac_us
+}
+
+// -------------------------------------------------------------------------
+// Client
+
+/// Turn an SNTP reply into the same `BeaconMeta` shape as a Roughtime probe.
+///
+/// With T1 = `t_send_wall`, T4 = T1 + `rtt`, T2/T3 = server rx/tx:
+/// offset = ((T2 − T1) + (T3 − T4)) / 2, delay = (T4 − T1) − (T3 − T2).
+/// A stratum-0 reply is a kiss-o'-death and comes back as `KissOfDeath`.
+pub(crate) fn beacon_from_reply(
+    host: &str,
+    req: &NtpPacket,
+    buf: &[u8],
+    t_send_wall: SystemTime,
+    rtt: Duration,
+    timing: TimingSource,
+) -> Result<BeaconMeta, TimestampError> {
+    let resp = NtpPacket::decode(buf)?;
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{host}: {msg}"));
+    if resp.mode != MODE_SERVER || resp.orig_ts != req.tx_ts {
+        return Err(invalid("reply does not match request").into());
+    }
+    if resp.stratum == 0 {
+        let code = String::from_utf8_lossy(&resp.ref_id).trim_end_matches('\0').to_string();
+        return Err(TimestampError::KissOfDeath { server: host.to_string(), code });
+    }
+    if resp.leap == LEAP_ALARM || resp.stratum >= STRATUM_UNSYNC {
+        return Err(invalid("server not synchronised").into());
+    }
+
+    let t1 = t_send_wall
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i128;
+    let t4 = t1 + rtt.as_micros() as i128;
+    let (t2, t3) = (ntp_to_us(resp.rx_ts), ntp_to_us(resp.tx_ts));
+
+    let offset_us = ((t2 - t1) + (t3 - t4)) / 2;
+    let delay_us = ((t4 - t1) - (t3 - t2)).max(0);
+    let radius_us = short_to_us(resp.root_dispersion) + short_to_us(resp.root_delay) / 2;
+    let half_rtt = Duration::from_micros((rtt.as_micros() / 2) as u64);
+
+    Ok(BeaconMeta {
+        host: host.to_string(),
+        rtt_ms: rtt.as_secs_f64() * 1e3,
+        true_time: t_send_wall + half_rtt,
+        midpoint_us: ((t2 + t3) / 2).max(0) as u64,
+        offset_us,
+        uncert_us: radius_us as i128 + delay_us / 2,
+        radius_us: radius_us.min(u32::MAX as u64) as u32,
+        timing,
+        kind: BeaconKind::Sntp,
+        authenticated: false,
+        evidence: Evidence::None,
+        addr: None,
+        asymmetry_us: None,
+        paths: Vec::new(),
+    })
+}
+
+// -------------------------------------------------------------------------
+// Server
+
+#[derive(Debug, Clone)]
+pub struct NtpServerConfig {
+    pub bind: String,
+    /// Beacon refresh interval.
+    pub poll: Duration,
+    /// Beyond this age the estimate is stale and we answer stratum 16.
+    pub max_age: Duration,
+    /// Stratum claimed while synchronised (beacons count as stratum 1).
+    pub stratum: u8,
+    pub ref_id: [u8; 4],
+}
+
+impl Default for NtpServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:123".into(),
+            poll: Duration::from_secs(64),
+            max_age: Duration::from_secs(1024),
+            stratum: 2,
+            ref_id: *b"RTIM",
+        }
+    }
+}
+
+/// Build the reply to `req`, received at local time `rx_local`.
+pub fn respond(
+    req: &NtpPacket,
+    rx_local: SystemTime,
+    clock: &CorrectedClock,
+    cfg: &NtpServerConfig,
+) -> NtpPacket {
+    let mut resp = NtpPacket {
+        version: req.version.clamp(1, 4),
+        mode: MODE_SERVER,
+        poll: req.poll,
+        precision: -20, // ~1 µs
+        orig_ts: req.tx_ts,
+        ..Default::default()
+    };
+    // one estimate for every field, even if the refresher updates meanwhile
+    let snapshot = clock
+        .estimate()
+        .map(|est| (clock.reading(&est, rx_local), clock.reading(&est, SystemTime::now())));
+    match snapshot {
+        Some((rx, tx)) if !tx.stale => {
+            let updated = tx.time - tx.age;
+            resp.leap = LEAP_NONE;
+            resp.stratum = cfg.stratum;
+            resp.ref_id = cfg.ref_id;
+            resp.root_delay = us_to_short(tx.min_rtt_us);
+            resp.root_dispersion = us_to_short(tx.uncert_us);
+            resp.ref_ts = to_ntp_ts(updated);
+            resp.rx_ts = to_ntp_ts(rx.time);
+            resp.tx_ts = to_ntp_ts(tx.time);
+        }
+        _ => {
+            // no (fresh) beacon data: stop claiming sync
+            let now = SystemTime::now();
+            resp.leap = LEAP_ALARM;
+            resp.stratum = STRATUM_UNSYNC;
+            resp.ref_id = *b"INIT";
+            resp.rx_ts = to_ntp_ts(rx_local);
+            resp.tx_ts = to_ntp_ts(now);
+        }
+    }
+    resp
+}
+
+/// Serve NTP on `cfg.bind` forever, refreshing from `client`'s beacons in
+/// the background.
+pub fn serve(cfg: &NtpServerConfig, client: TimestampClient) -> Result<(), TimestampError> {
+    let clock = CorrectedClock::new(cfg.max_age);
+    clock.spawn_refresher(client, cfg.poll);
+    serve_with(cfg, &clock)
+}
+
+/// Serve from an existing clock (shared with other server modes).
+pub fn serve_with(cfg: &NtpServerConfig, clock: &CorrectedClock) -> Result<(), TimestampError> {
+    let sock = UdpSocket::bind(&cfg.bind)?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, peer) = sock.recv_from(&mut buf)?;
+        let rx_local = SystemTime::now();
+        let req = match NtpPacket::decode(&buf[..len]) {
+            Ok(p) if p.mode == MODE_CLIENT => p,
+            _ => continue, // ignore anything that is not a client request
+        };
+        let resp = respond(&req, rx_local, clock, cfg);
+        if let Err(e) = sock.send_to(&resp.encode(), peer) {
+            log::error!("ntp         : reply to {peer} failed: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::{Clock, SimClock},
+        corrected::Estimate,
+        source::SntpSource,
+        ProbeOptions,
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    const OUT: Duration = Duration::from_millis(3);
+    const PROCESSING: Duration = Duration::from_millis(1);
+    const BACK: Duration = Duration::from_millis(5);
+
+    type Shape = fn(&mut NtpPacket);
+
+    /// Stand-in NTPv4 server on 127.0.0.1, `ahead_us` in front of `clock`
+    /// and advancing it by the path delays; `shape` edits each reply.
+    fn responder(
+        clock: Arc<SimClock>,
+        ahead_us: i128,
+        shape: impl Fn(&mut NtpPacket) + Send + 'static,
+    ) -> (String, Arc<AtomicUsize>) {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = sock.local_addr().unwrap().to_string();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = seen.clone();
+        let server_time = move |clock: &SimClock| {
+            let local = clock.wall().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+            let us = local.as_micros() as i128 + ahead_us;
+            to_ntp_ts(SystemTime::UNIX_EPOCH + Duration::from_micros(us as u64))
+        };
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((len, peer)) = sock.recv_from(&mut buf) {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let Ok(req) = NtpPacket::decode(&buf[..len]) else { continue };
+                clock.advance(OUT);
+                let rx_ts = server_time(&clock);
+                clock.advance(PROCESSING);
+                let mut resp = NtpPacket {
+                    version: 4,
+                    mode: MODE_SERVER,
+                    stratum: 1,
+                    root_delay: us_to_short(10_000),
+                    root_dispersion: us_to_short(2_000),
+                    ref_id: *b"GPS\0",
+                    orig_ts: req.tx_ts,
+                    rx_ts,
+                    tx_ts: server_time(&clock),
+                    ..Default::default()
+                };
+                shape(&mut resp);
+                clock.advance(BACK);
+                let _ = sock.send_to(&resp.encode(), peer);
+            }
+        });
+        (addr, seen)
+    }
+
+    fn sim_clock() -> Arc<SimClock> {
+        Arc::new(SimClock::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)))
+    }
+
+    fn opts(clock: &Arc<SimClock>) -> ProbeOptions {
+        let clock: Arc<dyn Clock> = clock.clone();
+        ProbeOptions {
+            clock: Some(clock),
+            timeout: Duration::from_millis(200),
+            rate_limit: None,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn offset_and_delay_follow_the_four_timestamps() {
+        let clock = sim_clock();
+        let (host, _) = responder(clock.clone(), 2_500_000, |_| {});
+        let b = crate::query_sntp(&host, &opts(&clock)).unwrap();
+
+        // ((T2 − T1) + (T3 − T4)) / 2 = ahead + (OUT − BACK − PROCESSING) / 2
+        assert!((b.offset_us - (2_500_000 - 1_000)).abs() <= 2, "offset {}", b.offset_us);
+        // delay = RTT − server time = 8 ms, half of it on top of the radius
+        let radius = short_to_us(us_to_short(2_000)) + short_to_us(us_to_short(10_000)) / 2;
+        assert_eq!(b.radius_us as u64, radius);
+        assert!((b.uncert_us - (radius as i128 + 4_000)).abs() <= 1, "uncert {}", b.uncert_us);
+        assert_eq!(b.rtt_ms, 9.0);
+        assert_eq!(b.kind, BeaconKind::Sntp);
+        assert!(!b.authenticated);
+    }
+
+    #[test]
+    fn bad_mode_and_unsynchronised_servers_are_refused() {
+        let shapes: [(Shape, &str); 3] = [
+            (|p| p.mode = MODE_CLIENT, "does not match"),
+            (|p| p.stratum = STRATUM_UNSYNC, "not synchronised"),
+            (|p| p.leap = LEAP_ALARM, "not synchronised"),
+        ];
+        for (shape, want) in shapes {
+            let clock = sim_clock();
+            let (host, _) = responder(clock.clone(), 0, shape);
+            let err = crate::query_sntp(&host, &opts(&clock)).unwrap_err();
+            assert!(err.to_string().contains(want), "{err}");
+        }
+    }
+
+    #[test]
+    fn kiss_o_death_is_reported_and_not_retried() {
+        let clock = sim_clock();
+        let (host, seen) = responder(clock.clone(), 0, |p| {
+            p.stratum = 0;
+            p.ref_id = *b"RATE";
+        });
+        let o = ProbeOptions {
+            retries: 3,
+            ..opts(&clock)
+        };
+        let err = crate::run_query(&SntpSource::new(&host), &[0; 32], &o).unwrap_err();
+        assert!(
+            matches!(&err, TimestampError::KissOfDeath { code, .. } if code == "RATE"),
+            "{err}"
+        );
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+    }
+
+    fn request() -> NtpPacket {
+        NtpPacket {
+            version: 4,
+            mode: MODE_CLIENT,
+            poll: 6,
+            tx_ts: 0x1234_5678_9abc_def0,
+            ..Default::default()
+        }
+    }
+
+    fn estimate(offset_us: i128, age: Duration) -> Estimate {
+        Estimate {
+            offset_us,
+            uncert_us: 700,
+            min_rtt_us: 9_000,
+            at: Instant::now() - age,
+            freq_ppm: 0.0,
+            skew_ppm: 0.0,
+        }
+    }
+
+    fn assert_unsynchronised(resp: &NtpPacket, rx_local: SystemTime) {
+        assert_eq!(resp.leap, LEAP_ALARM);
+        assert_eq!(resp.stratum, STRATUM_UNSYNC);
+        assert_eq!(&resp.ref_id, b"INIT");
+        assert_eq!(resp.rx_ts, to_ntp_ts(rx_local));
+        assert_eq!(resp.ref_ts, 0);
+    }
+
+    #[test]
+    fn server_without_an_estimate_answers_unsynchronised() {
+        let cfg = NtpServerConfig::default();
+        let clock = CorrectedClock::new(cfg.max_age);
+        let rx_local = SystemTime::now();
+        let resp = respond(&request(), rx_local, &clock, &cfg);
+        assert_unsynchronised(&resp, rx_local);
+        assert_eq!(resp.mode, MODE_SERVER);
+        assert_eq!(resp.orig_ts, request().tx_ts);
+    }
+
+    #[test]
+    fn server_with_a_stale_estimate_answers_unsynchronised() {
+        let cfg = NtpServerConfig {
+            max_age: Duration::from_secs(1),
+            ..Default::default()
+        };
+        let clock = CorrectedClock::new(cfg.max_age);
+        clock.update(estimate(1_500_000, Duration::from_secs(2)));
+        let rx_local = SystemTime::now();
+        let resp = respond(&request(), rx_local, &clock, &cfg);
+        assert_unsynchronised(&resp, rx_local);
+    }
+
+    #[test]
+    fn server_with_a_fresh_estimate_answers_corrected_time() {
+        let cfg = NtpServerConfig {
+            stratum: 3,
+            ref_id: *b"TEST",
+            ..Default::default()
+        };
+        let clock = CorrectedClock::new(cfg.max_age);
+        clock.update(estimate(1_500_000, Duration::ZERO));
+        let rx_local = SystemTime::now();
+        let resp = respond(&request(), rx_local, &clock, &cfg);
+
+        assert_eq!(resp.leap, LEAP_NONE);
+        assert_eq!(resp.version, 4);
+        assert_eq!(resp.mode, MODE_SERVER);
+        assert_eq!(resp.stratum, 3);
+        assert_eq!(&resp.ref_id, b"TEST");
+        assert_eq!(resp.poll, 6);
+        assert_eq!(resp.orig_ts, request().tx_ts);
+        assert_eq!(resp.root_delay, us_to_short(9_000));
+        assert!(resp.root_dispersion >= us_to_short(700));
+
+        let rx = from_ntp_ts(resp.rx_ts);
+        let tx = from_ntp_ts(resp.tx_ts);
+        let expected = rx_local + Duration::from_micros(1_500_000);
+        let skew = rx.duration_since(expected).unwrap_or_else(|e| e.duration());
+        assert!(skew < Duration::from_micros(2), "rx off by {skew:?}");
+        assert!(tx >= rx && tx.duration_since(rx).unwrap() < Duration::from_secs(1));
+        assert!(from_ntp_ts(resp.ref_ts) <= tx);
+    }
+}
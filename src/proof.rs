@@ -0,0 +1,117 @@
+//! Portable proofs: the signed replies behind a timestamp, checkable later
+//! and elsewhere.
+//!
+//! A `Proof` is the stamped hash plus, per authenticated beacon, the nonce
+//! we sent and the reply verbatim (`Evidence::Roughtime`).  `verify`
+//! re-derives what the live query checked: the nonce starts with the hash,
+//! the nonce is in the signed Merkle tree, and the signature chain holds
+//! under the beacon's pinned long-term key.  A beacon without a pinned key
+//! fails the proof: inclusion alone proves nothing about who signed.
+
+use crate::{
+    source::Evidence,
+    wire::{self, Version},
+    TimestampError, TimestampResponse,
+};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Proof {
+    /// Hex of the 32-byte hash.
+    pub hash: String,
+    pub beacons: Vec<ProofBeacon>,
+}
+
+/// Unknown fields are ignored, so `Metadata::beacons` entries parse as is.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ProofBeacon {
+    pub host: String,
+    pub evidence: Evidence,
+}
+
+/// What one beacon's evidence establishes.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct VerifiedBeacon {
+    pub host: String,
+    pub version: Version,
+    pub midpoint_us: u64,
+    pub radius_us: u64,
+}
+
+impl Proof {
+    /// The authenticated beacons of `resp` that carry signed replies.
+    pub fn from_response(resp: &TimestampResponse) -> Self {
+        Self {
+            hash: resp.input_hash.clone(),
+            beacons: resp
+                .metadata
+                .beacons
+                .iter()
+                .filter(|b| matches!(b.evidence, Evidence::Roughtime { .. }))
+                .map(|b| ProofBeacon {
+                    host: b.host.clone(),
+                    evidence: b.evidence.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Check every beacon; `keys` maps host to long-term public key and
+    /// must hold one for each of them.
+    pub fn verify(
+        &self,
+        keys: &HashMap<String, Vec<u8>>,
+    ) -> Result<Vec<VerifiedBeacon>, TimestampError> {
+        let hash = hex::decode(&self.hash)
+            .ok()
+            .filter(|h| h.len() == 32)
+            .ok_or_else(|| TimestampError::BadProof("hash must be 32 bytes of hex".into()))?;
+        if self.beacons.is_empty() {
+            return Err(TimestampError::BadProof("no beacons".into()));
+        }
+        self.beacons
+            .iter()
+            .map(|b| {
+                keys.get(&b.host)
+                    .ok_or_else(|| "no pinned key for this host".to_string())
+                    .and_then(|pk| verify_beacon(&hash, b, pk))
+                    .map_err(|e| TimestampError::BadProof(format!("{}: {e}", b.host)))
+            })
+            .collect()
+    }
+}
+
+fn verify_beacon(
+    hash: &[u8],
+    b: &ProofBeacon,
+    public_key: &[u8],
+) -> Result<VerifiedBeacon, String> {
+    let Evidence::Roughtime {
+        version,
+        nonce,
+        reply,
+    } = &b.evidence
+    else {
+        return Err("no signed reply".into());
+    };
+    let nonce = hex::decode(nonce).map_err(|e| format!("nonce: {e}"))?;
+    let reply = hex::decode(reply).map_err(|e| format!("reply: {e}"))?;
+    if nonce.len() != version.nonce_len() || !nonce.starts_with(hash) {
+        return Err("nonce does not commit to the hash".into());
+    }
+    let parsed = wire::parse_reply(&reply).map_err(|e| e.to_string())?;
+    if parsed.version != *version {
+        return Err(format!(
+            "reply is {:?}, evidence says {version:?}",
+            parsed.version
+        ));
+    }
+    wire::verify_inclusion(&parsed, &nonce).map_err(|e| e.to_string())?;
+    wire::verify_reply(&parsed, public_key).map_err(|e| e.to_string())?;
+    Ok(VerifiedBeacon {
+        host: b.host.clone(),
+        version: *version,
+        midpoint_us: parsed.midpoint_us,
+        radius_us: parsed.radius_us,
+    })
+}
@@ -0,0 +1,501 @@
+//! Per-server token buckets, so a bug or a traffic spike on our side
+//! cannot turn into a flood against public beacons.
+//!
+//! Every query attempt (`run_query`, retries included) takes a token
+//! from its server's bucket per request it sends (`TimeSource::requests`:
+//! one per path for `multipath`).  A bucket holds up to `burst` tokens and
+//! refills at `per_sec`.  With the bucket empty, `OverLimit` decides:
+//! wait for the next token, out of the query's own timeout; leave the
+//! server out like an ejected one so the others and the backups answer
+//! (also when it runs dry between being admitted and sending); or fail
+//! the whole call before anything is sent.
+//!
+//! `ProbeOptions::default()` installs the process-wide `shared()` limiter
+//! (`Rate::default()`: burst 8, then 1 query/s per server, `Wait`), so
+//! every caller is metered unless it brings its own limiter or opts out.
+//! That includes the servers in `http`, `ipc` and `grpc`, which stamp
+//! through their `TimestampClient`: give it a limiter sized for the
+//! service (`TimestampClient::builder().rate_limit`, or `[rate_limit]` in
+//! `config` for the binary).  Their stamps are batched, so one token per
+//! server covers a whole batch.  Sources that never leave the host
+//! (`TimeSource::remote`) are not metered.
+
+use crate::{clock::Clock, clock::SystemClock, TimestampError};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+/// Bucket size and refill.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub burst: u32,
+    /// Tokens added per second.
+    pub per_sec: f64,
+}
+
+impl Rate {
+    pub fn per_minute(n: u32, burst: u32) -> Self {
+        Self {
+            burst,
+            per_sec: n as f64 / 60.0,
+        }
+    }
+}
+
+impl Default for Rate {
+    /// A handful of queries at once, one a second after that.
+    fn default() -> Self {
+        Self {
+            burst: 8,
+            per_sec: 1.0,
+        }
+    }
+}
+
+/// What a query over the limit does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverLimit {
+    /// Sleep until a token is due, if that is within the query timeout.
+    #[default]
+    Wait,
+    /// Skip the server; the others (and backups) make the quorum.
+    Failover,
+    /// Fail the call with `RateLimited` before sending anything.
+    FailFast,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    tokens: f64,
+    refilled: Instant,
+    allowed: u64,
+    denied: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let dt = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + dt * self.rate.per_sec).min(self.rate.burst as f64);
+        self.refilled = now;
+    }
+
+    /// Time until a whole token is there (zero if it is).
+    fn due(&self) -> Duration {
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else if self.rate.per_sec <= 0.0 {
+            Duration::MAX
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.rate.per_sec)
+        }
+    }
+}
+
+/// Per-server counters.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct RateReport {
+    pub server: String,
+    pub tokens: f64,
+    pub allowed: u64,
+    pub denied: u64,
+}
+
+/// The limiter `ProbeOptions::default()` installs: `Rate::default()` for
+/// every server, `OverLimit::Wait`, shared by the whole process.
+pub fn shared() -> Arc<RateLimiter> {
+    static SHARED: OnceLock<Arc<RateLimiter>> = OnceLock::new();
+    SHARED
+        .get_or_init(|| Arc::new(RateLimiter::new(Rate::default(), OverLimit::Wait)))
+        .clone()
+}
+
+#[derive(Debug)]
+pub struct RateLimiter {
+    default: Rate,
+    overrides: HashMap<String, Rate>,
+    policy: OverLimit,
+    clock: Arc<dyn Clock>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(default: Rate, policy: OverLimit) -> Self {
+        Self::with_clock(default, policy, Arc::new(SystemClock))
+    }
+
+    /// Refill, and wait under `OverLimit::Wait`, on `clock`.
+    pub fn with_clock(default: Rate, policy: OverLimit, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            default,
+            overrides: HashMap::new(),
+            policy,
+            clock,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A different rate for `server` (a source name).
+    pub fn server(mut self, server: impl Into<String>, rate: Rate) -> Self {
+        self.overrides.insert(server.into(), rate);
+        self
+    }
+
+    pub fn policy(&self) -> OverLimit {
+        self.policy
+    }
+
+    /// Take a token for one request to `server`.  Under
+    /// `OverLimit::Wait` this waits for it if it is due within `max_wait`,
+    /// and returns how long it waited (less than `max_wait`, barring
+    /// oversleeping, so some of a query's timeout is left).
+    pub fn acquire(&self, server: &str, max_wait: Duration) -> Result<Duration, TimestampError> {
+        let start = self.clock.mono();
+        let until = start + max_wait;
+        loop {
+            let due = match self.try_take(server) {
+                Ok(()) => return Ok(self.clock.mono().saturating_duration_since(start)),
+                Err(due) => due,
+            };
+            let fits = self
+                .clock
+                .mono()
+                .checked_add(due)
+                .is_some_and(|at| at < until);
+            if self.policy != OverLimit::Wait || !fits {
+                self.with_bucket(server, |b| b.denied += 1);
+                return Err(TimestampError::RateLimited {
+                    server: server.to_string(),
+                    retry_after: due,
+                });
+            }
+            self.clock.sleep(due);
+        }
+    }
+
+    /// Whether a query to `server` would go out now.
+    pub fn available(&self, server: &str) -> bool {
+        self.with_bucket(server, |b| b.tokens >= 1.0)
+    }
+
+    /// Under `FailFast`, the first of `servers` without a token.
+    pub fn check_all<'a>(
+        &self,
+        servers: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), TimestampError> {
+        if self.policy != OverLimit::FailFast {
+            return Ok(());
+        }
+        for s in servers {
+            let due = self.with_bucket(s, |b| b.due());
+            if !due.is_zero() {
+                self.with_bucket(s, |b| b.denied += 1);
+                return Err(TimestampError::RateLimited {
+                    server: s.to_string(),
+                    retry_after: due,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn report(&self) -> Vec<RateReport> {
+        let now = self.clock.mono();
+        let mut buckets = self.buckets.lock().unwrap();
+        let mut out: Vec<_> = buckets
+            .iter_mut()
+            .map(|(server, b)| {
+                b.refill(now);
+                RateReport {
+                    server: server.clone(),
+                    tokens: b.tokens,
+                    allowed: b.allowed,
+                    denied: b.denied,
+                }
+            })
+            .collect();
+        out.sort_by(|a, b| a.server.cmp(&b.server));
+        out
+    }
+
+    fn try_take(&self, server: &str) -> Result<(), Duration> {
+        self.with_bucket(server, |b| {
+            let due = b.due();
+            if due.is_zero() {
+                b.tokens -= 1.0;
+                b.allowed += 1;
+                Ok(())
+            } else {
+                Err(due)
+            }
+        })
+    }
+
+    /// `f` on `server`'s bucket, refilled to now; new buckets start full.
+    fn with_bucket<T>(&self, server: &str, f: impl FnOnce(&mut Bucket) -> T) -> T {
+        let now = self.clock.mono();
+        let mut buckets = self.buckets.lock().unwrap();
+        let b = buckets.entry(server.to_string()).or_insert_with(|| {
+            let rate = self.overrides.get(server).copied().unwrap_or(self.default);
+            Bucket {
+                rate,
+                tokens: rate.burst as f64,
+                refilled: now,
+                allowed: 0,
+                denied: 0,
+            }
+        });
+        b.refill(now);
+        f(b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        clock::SimClock,
+        get_timestamp_from,
+        multipath::{MultiPathSource, PathSpec},
+        source::{MockSource, TimeSource},
+        BeaconMeta, ProbeOptions,
+    };
+    use std::time::UNIX_EPOCH;
+
+    fn limiter(rate: Rate, policy: OverLimit) -> (RateLimiter, Arc<SimClock>) {
+        let clock = Arc::new(SimClock::new(UNIX_EPOCH));
+        (RateLimiter::with_clock(rate, policy, clock.clone()), clock)
+    }
+
+    fn rate(burst: u32, per_sec: f64) -> Rate {
+        Rate { burst, per_sec }
+    }
+
+    fn retry_after(r: Result<Duration, TimestampError>) -> Duration {
+        match r {
+            Err(TimestampError::RateLimited { retry_after, .. }) => retry_after,
+            other => panic!("expected RateLimited, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn a_burst_then_one_token_per_refill_interval() {
+        let (l, clock) = limiter(rate(3, 2.0), OverLimit::FailFast);
+        for _ in 0..3 {
+            assert_eq!(l.acquire("a", Duration::ZERO).unwrap(), Duration::ZERO);
+        }
+        assert_eq!(
+            retry_after(l.acquire("a", Duration::ZERO)),
+            Duration::from_millis(500)
+        );
+        clock.advance(Duration::from_millis(250));
+        assert_eq!(
+            retry_after(l.acquire("a", Duration::ZERO)),
+            Duration::from_millis(250)
+        );
+        clock.advance(Duration::from_millis(250));
+        l.acquire("a", Duration::ZERO).unwrap();
+
+        // an idle bucket fills up to the burst and no further
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(l.report()[0].tokens, 3.0);
+        let r = &l.report()[0];
+        assert_eq!((r.server.as_str(), r.allowed, r.denied), ("a", 4, 2));
+    }
+
+    #[test]
+    fn servers_have_their_own_buckets_and_rates() {
+        let (l, _) = limiter(rate(1, 1.0), OverLimit::FailFast);
+        let l = l.server("slow", rate(2, 0.0));
+        l.acquire("a", Duration::ZERO).unwrap();
+        l.acquire("b", Duration::ZERO).unwrap();
+        l.acquire("slow", Duration::ZERO).unwrap();
+        l.acquire("slow", Duration::ZERO).unwrap();
+        assert_eq!(
+            retry_after(l.acquire("slow", Duration::ZERO)),
+            Duration::MAX
+        );
+        assert!(l.available("c") && !l.available("a"));
+    }
+
+    #[test]
+    fn wait_sleeps_for_a_token_due_within_the_limit() {
+        let (l, clock) = limiter(rate(1, 4.0), OverLimit::Wait);
+        l.acquire("a", Duration::from_secs(1)).unwrap();
+        let waited = l.acquire("a", Duration::from_secs(1)).unwrap();
+        assert_eq!(waited, Duration::from_millis(250));
+        assert_eq!(clock.elapsed(), Duration::from_millis(250));
+
+        // due no sooner than the limit: refused without sleeping
+        assert_eq!(
+            retry_after(l.acquire("a", Duration::from_millis(250))),
+            Duration::from_millis(250)
+        );
+        assert_eq!(clock.elapsed(), Duration::from_millis(250));
+        assert_eq!(l.report()[0].denied, 1);
+    }
+
+    #[test]
+    fn failover_and_fail_fast_refuse_at_once() {
+        for policy in [OverLimit::Failover, OverLimit::FailFast] {
+            let (l, clock) = limiter(rate(1, 1.0), policy);
+            l.acquire("a", Duration::ZERO).unwrap();
+            assert_eq!(
+                retry_after(l.acquire("a", Duration::from_secs(60))),
+                Duration::from_secs(1)
+            );
+            assert_eq!(clock.elapsed(), Duration::ZERO, "{policy:?} slept");
+        }
+    }
+
+    #[test]
+    fn only_fail_fast_checks_every_server_up_front() {
+        for policy in [OverLimit::Wait, OverLimit::Failover, OverLimit::FailFast] {
+            let (l, _) = limiter(rate(1, 1.0), policy);
+            l.acquire("b", Duration::ZERO).unwrap();
+            let checked = l.check_all(["a", "b"]);
+            if policy == OverLimit::FailFast {
+                let Err(TimestampError::RateLimited { server, .. }) = checked else {
+                    panic!("{checked:?}")
+                };
+                assert_eq!(server, "b");
+            } else {
+                assert!(checked.is_ok(), "{policy:?}");
+            }
+        }
+    }
+
+    /// A mock that counts as a network source, and so is metered.
+    struct Remote {
+        mock: MockSource,
+        /// Fail this many queries with an I/O error first.
+        failures: Mutex<u32>,
+        /// The timeout of every query.
+        timeouts: Mutex<Vec<Duration>>,
+    }
+
+    impl Remote {
+        fn new(name: &str, failures: u32) -> Arc<Self> {
+            let mock = MockSource {
+                rtt: Duration::ZERO,
+                ..MockSource::new(name, 1_000)
+            };
+            Arc::new(Self {
+                mock,
+                failures: Mutex::new(failures),
+                timeouts: Mutex::new(Vec::new()),
+            })
+        }
+    }
+
+    impl TimeSource for Remote {
+        fn name(&self) -> &str {
+            self.mock.name()
+        }
+
+        fn authenticated(&self) -> bool {
+            true
+        }
+
+        fn query(
+            &self,
+            hash: &[u8; 32],
+            opts: &ProbeOptions,
+        ) -> Result<BeaconMeta, TimestampError> {
+            self.timeouts.lock().unwrap().push(opts.timeout);
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(std::io::Error::other("lost").into());
+            }
+            self.mock.query(hash, opts)
+        }
+    }
+
+    fn metered(limiter: RateLimiter) -> ProbeOptions {
+        ProbeOptions {
+            rate_limit: Some(Arc::new(limiter)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn a_multipath_query_takes_a_token_per_path() {
+        let (l, _) = limiter(rate(8, 0.0), OverLimit::FailFast);
+        let opts = metered(l);
+        let paths = vec![PathSpec::default(); 3];
+        let source: Arc<dyn TimeSource> =
+            Arc::new(MultiPathSource::new(Remote::new("a", 0), paths));
+        get_timestamp_from([1; 32], &[source], &opts).unwrap();
+        assert_eq!(opts.rate_limit.unwrap().report()[0].allowed, 3);
+    }
+
+    #[test]
+    fn waiting_for_a_token_comes_off_the_query_timeout() {
+        // the second path's token is due in 2 s: not within a 2 s timeout
+        let (l, clock) = limiter(rate(1, 0.5), OverLimit::Wait);
+        let opts = ProbeOptions {
+            timeout: Duration::from_secs(2),
+            ..metered(l)
+        };
+        let remote = Remote::new("a", 0);
+        let paths = vec![PathSpec::default(); 2];
+        let sources: [Arc<dyn TimeSource>; 1] =
+            [Arc::new(MultiPathSource::new(remote.clone(), paths))];
+        let err = get_timestamp_from([1; 32], &sources, &opts).unwrap_err();
+        assert!(matches!(err, TimestampError::RateLimited { .. }), "{err}");
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+        assert!(remote.timeouts.lock().unwrap().is_empty(), "nothing sent");
+
+        // with 3 s, 2 s go to the wait and 1 s is left for the query
+        let opts = ProbeOptions {
+            timeout: Duration::from_secs(3),
+            ..opts
+        };
+        clock.advance(Duration::from_secs(2));
+        get_timestamp_from([1; 32], &sources, &opts).unwrap();
+        assert_eq!(clock.elapsed(), Duration::from_secs(4));
+        assert_eq!(
+            *remote.timeouts.lock().unwrap(),
+            [Duration::from_secs(1); 2]
+        );
+    }
+
+    #[test]
+    fn under_failover_a_source_that_runs_dry_after_admission_sits_out() {
+        // admitted with one token, then a retry needs a second one: the
+        // same position as losing the token to a concurrent call
+        let (l, _) = limiter(rate(1, 0.0), OverLimit::Failover);
+        let opts = ProbeOptions {
+            retries: 1,
+            ..metered(l)
+        };
+        let sources: Vec<Arc<dyn TimeSource>> = vec![Remote::new("a", 0), Remote::new("b", 1)];
+        assert!(opts.admits("a") && opts.admits("b"));
+        let resp = get_timestamp_from([1; 32], &sources, &opts).unwrap();
+        let hosts: Vec<_> = resp
+            .metadata
+            .beacons
+            .iter()
+            .map(|b| b.host.as_str())
+            .collect();
+        assert_eq!(hosts, ["a"]);
+
+        // with nobody left the call reports the limit, not "no probes"
+        let err = get_timestamp_from([1; 32], &sources, &opts).unwrap_err();
+        assert!(matches!(err, TimestampError::RateLimited { .. }), "{err}");
+
+        // other policies fail the call as before
+        let (l, _) = limiter(rate(1, 0.0), OverLimit::Wait);
+        let opts = ProbeOptions {
+            retries: 1,
+            ..metered(l)
+        };
+        let sources: Vec<Arc<dyn TimeSource>> = vec![Remote::new("a", 0), Remote::new("b", 1)];
+        let err = get_timestamp_from([1; 32], &sources, &opts).unwrap_err();
+        assert!(matches!(err, TimestampError::RateLimited { .. }), "{err}");
+    }
+}
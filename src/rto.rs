@@ -0,0 +1,305 @@
+//! Adaptive per-server timeouts, computed like TCP's RTO (RFC 6298).
+//!
+//! Each successful query feeds its RTT into a smoothed RTT and RTT
+//! variance; the timeout for the next query is `SRTT + 4·RTTVAR`, clamped
+//! to `[floor, ceiling]`, and doubles on every retry.  Servers without
+//! history get `ProbeOptions::timeout`.  The table can be kept in a file
+//! (`<server> <srtt_ms> <rttvar_ms> <samples>` per line) so a fresh process
+//! starts from what the last one learned.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// RFC 6298 gains.
+const ALPHA: f64 = 1.0 / 8.0;
+const BETA: f64 = 1.0 / 4.0;
+const K: f64 = 4.0;
+/// Clock granularity term `G`.
+const GRANULARITY_MS: f64 = 1.0;
+/// Minimum gap between automatic saves.
+const SAVE_EVERY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct RtoConfig {
+    pub floor: Duration,
+    pub ceiling: Duration,
+}
+
+impl Default for RtoConfig {
+    fn default() -> Self {
+        Self {
+            floor: Duration::from_millis(100),
+            ceiling: Duration::from_secs(3),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct RttStats {
+    pub srtt_ms: f64,
+    pub rttvar_ms: f64,
+    pub samples: u64,
+}
+
+#[derive(Debug)]
+pub struct RtoEstimator {
+    cfg: RtoConfig,
+    servers: Mutex<HashMap<String, RttStats>>,
+    path: Option<PathBuf>,
+    last_save: Mutex<Instant>,
+}
+
+impl RtoEstimator {
+    pub fn new(cfg: RtoConfig) -> Self {
+        Self {
+            cfg,
+            servers: Mutex::new(HashMap::new()),
+            path: None,
+            last_save: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Bind to a history file, loading it when it exists.
+    pub fn open(path: impl AsRef<Path>, cfg: RtoConfig) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut est = Self::new(cfg);
+        match fs::read_to_string(&path) {
+            Ok(text) => *est.servers.get_mut().unwrap() = parse_history(&text)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        est.path = Some(path);
+        Ok(est)
+    }
+
+    /// Feed one measured RTT for `server`.
+    pub fn observe(&self, server: &str, rtt: Duration) {
+        let r = rtt.as_secs_f64() * 1e3;
+        {
+            let mut map = self.servers.lock().unwrap();
+            match map.get_mut(server) {
+                Some(s) => {
+                    s.rttvar_ms = (1.0 - BETA) * s.rttvar_ms + BETA * (s.srtt_ms - r).abs();
+                    s.srtt_ms = (1.0 - ALPHA) * s.srtt_ms + ALPHA * r;
+                    s.samples += 1;
+                }
+                None => {
+                    map.insert(
+                        server.to_string(),
+                        RttStats {
+                            srtt_ms: r,
+                            rttvar_ms: r / 2.0,
+                            samples: 1,
+                        },
+                    );
+                }
+            }
+        }
+        let due = {
+            let mut last = self.last_save.lock().unwrap();
+            let due = last.elapsed() >= SAVE_EVERY;
+            if due {
+                *last = Instant::now();
+            }
+            due
+        };
+        if due {
+            let _ = self.save(); // best effort; `save` reports errors to callers
+        }
+    }
+
+    /// `SRTT + max(G, 4·RTTVAR)` clamped; `None` without history.
+    pub fn timeout_for(&self, server: &str) -> Option<Duration> {
+        let s = *self.servers.lock().unwrap().get(server)?;
+        let rto_ms = s.srtt_ms + (K * s.rttvar_ms).max(GRANULARITY_MS);
+        Some(self.clamp(Duration::from_secs_f64(rto_ms / 1e3)))
+    }
+
+    /// Timeout for retry `attempt` (0 = first try) of a query whose base
+    /// timeout is `base`: doubled per retry, clamped.
+    pub fn backoff(&self, base: Duration, attempt: u32) -> Duration {
+        self.clamp(base.saturating_mul(1 << attempt.min(16)))
+    }
+
+    pub fn stats(&self, server: &str) -> Option<RttStats> {
+        self.servers.lock().unwrap().get(server).copied()
+    }
+
+    /// Write the table to the history file (temp file + rename).
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut lines: Vec<String> = self
+            .servers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, s)| format!("{name} {:.3} {:.3} {}\n", s.srtt_ms, s.rttvar_ms, s.samples))
+            .collect();
+        lines.sort();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, lines.concat())?;
+        fs::rename(tmp, path)
+    }
+
+    fn clamp(&self, d: Duration) -> Duration {
+        d.clamp(self.cfg.floor, self.cfg.ceiling.max(self.cfg.floor))
+    }
+}
+
+impl Drop for RtoEstimator {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+fn parse_history(text: &str) -> io::Result<HashMap<String, RttStats>> {
+    let mut map = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let bad = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad RTT history line {}: {line:?}", n + 1),
+            )
+        };
+        // numbers from the right, so names may contain spaces
+        let mut it = line.trim_end().rsplitn(4, ' ');
+        let mut num = || {
+            it.next()
+                .and_then(|v| v.parse::<f64>().ok())
+                .ok_or_else(bad)
+        };
+        let (samples, rttvar_ms, srtt_ms) = (num()? as u64, num()?, num()?);
+        let name = it.next().filter(|n| !n.is_empty()).ok_or_else(bad)?;
+        map.insert(
+            name.to_string(),
+            RttStats {
+                srtt_ms,
+                rttvar_ms,
+                samples,
+            },
+        );
+    }
+    Ok(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(v: u64) -> Duration {
+        Duration::from_millis(v)
+    }
+
+    fn wide() -> RtoConfig {
+        RtoConfig {
+            floor: ms(1),
+            ceiling: ms(60_000),
+        }
+    }
+
+    #[test]
+    fn first_sample_then_rfc6298_updates() {
+        let rto = RtoEstimator::new(wide());
+        assert_eq!(rto.timeout_for("a"), None);
+
+        // R: SRTT = R, RTTVAR = R/2
+        rto.observe("a", ms(80));
+        let s = rto.stats("a").unwrap();
+        assert_eq!((s.srtt_ms, s.rttvar_ms, s.samples), (80.0, 40.0, 1));
+        assert_eq!(rto.timeout_for("a"), Some(ms(80 + 4 * 40)));
+
+        // R': RTTVAR = ¾·40 + ¼·|80 − 160| = 50, SRTT = ⅞·80 + ⅛·160 = 90
+        rto.observe("a", ms(160));
+        let s = rto.stats("a").unwrap();
+        assert_eq!((s.srtt_ms, s.rttvar_ms, s.samples), (90.0, 50.0, 2));
+        assert_eq!(rto.timeout_for("a"), Some(ms(90 + 4 * 50)));
+        assert_eq!(rto.stats("b"), None);
+    }
+
+    #[test]
+    fn a_steady_rtt_converges_to_the_granularity_floor() {
+        let rto = RtoEstimator::new(wide());
+        for _ in 0..200 {
+            rto.observe("a", ms(20));
+        }
+        // RTTVAR decays towards 0, so max(G, 4·RTTVAR) ends at G = 1 ms
+        assert_eq!(rto.timeout_for("a"), Some(ms(21)));
+    }
+
+    #[test]
+    fn timeouts_are_clamped() {
+        let rto = RtoEstimator::new(RtoConfig {
+            floor: ms(100),
+            ceiling: ms(1_000),
+        });
+        rto.observe("near", ms(4));
+        assert_eq!(rto.timeout_for("near"), Some(ms(100)));
+        rto.observe("far", ms(900));
+        assert_eq!(rto.timeout_for("far"), Some(ms(1_000)));
+
+        // a ceiling below the floor is read as the floor
+        let odd = RtoEstimator::new(RtoConfig {
+            floor: ms(500),
+            ceiling: ms(200),
+        });
+        odd.observe("a", ms(10));
+        assert_eq!(odd.timeout_for("a"), Some(ms(500)));
+    }
+
+    #[test]
+    fn backoff_doubles_per_retry_up_to_the_ceiling() {
+        let rto = RtoEstimator::new(RtoConfig {
+            floor: ms(100),
+            ceiling: ms(1_000),
+        });
+        let tries: Vec<_> = (0..5).map(|n| rto.backoff(ms(150), n)).collect();
+        assert_eq!(tries, [ms(150), ms(300), ms(600), ms(1_000), ms(1_000)]);
+        assert_eq!(rto.backoff(ms(10), 0), ms(100));
+        assert_eq!(rto.backoff(ms(150), u32::MAX), ms(1_000));
+    }
+
+    #[test]
+    fn history_is_saved_on_drop_and_reloaded() {
+        let path = std::env::temp_dir().join(format!("rt_ping-rtt-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        {
+            let rto = RtoEstimator::open(&path, wide()).unwrap();
+            rto.observe("roughtime.example:2002", ms(80));
+            rto.observe("roughtime.example:2002", ms(160));
+            rto.observe("my server:2002", ms(7));
+            assert!(!path.exists(), "saved at most once a minute while running");
+        }
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text,
+            "my server:2002 7.000 3.500 1\nroughtime.example:2002 90.000 50.000 2\n"
+        );
+
+        let again = RtoEstimator::open(&path, wide()).unwrap();
+        assert_eq!(again.stats("roughtime.example:2002").unwrap().samples, 2);
+        assert_eq!(again.timeout_for("roughtime.example:2002"), Some(ms(290)));
+        assert_eq!(again.stats("my server:2002").unwrap().srtt_ms, 7.0);
+        drop(again);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn history_parse_errors_name_the_line() {
+        assert!(parse_history("\n\n").unwrap().is_empty());
+        for (text, line) in [("a 1 2 3\nb 1 x 3\n", 2), ("a 1 2\n", 1), (" 1 2 3\n", 1)] {
+            let err = parse_history(text).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(&format!("line {line}")), "{err}");
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Roughtime server anchored to the upstream beacons.
+//!
+//! `MIDP` is local time corrected by the beacon offsets (`CorrectedClock`),
+//! `RADI` is the combined upstream uncertainty.  Requests are drained from
+//! the socket in batches; each batch's nonces become one Merkle tree per wire
+//! version and a single `SREP` signature covers them all.
+
+use crate::{
+    client::TimestampClient,
+    corrected::{CorrectedClock, Reading},
+    fresh_nonce, log, rt_to_io, sys_to_us,
+    wire::{self, Version, RESPONSE_CONTEXT},
+    TimestampError,
+};
+use roughenough::{sign::Signer, RtMessage, Tag};
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind: String,
+    /// Hex seed of the long-term key; generated on first start.
+    pub key_file: PathBuf,
+    pub batch_size: usize,
+    /// Lifetime of each delegated online key.
+    pub delegation: Duration,
+    /// Beacon refresh interval and staleness limit for `MIDP`.
+    pub poll: Duration,
+    pub max_age: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "0.0.0.0:2002".into(),
+            key_file: "rt_ping.key".into(),
+            batch_size: 64,
+            delegation: Duration::from_secs(24 * 3600),
+            poll: Duration::from_secs(64),
+            max_age: Duration::from_secs(1024),
+        }
+    }
+}
+
+// -------------------------------------------------------------------------
+// Keys
+
+/// The long-term identity; only ever signs delegations.
+pub struct LongTermKey {
+    signer: Signer,
+}
+
+impl LongTermKey {
+    pub fn from_seed(seed: &[u8]) -> Self {
+        Self {
+            signer: Signer::from_seed(seed),
+        }
+    }
+
+    /// Read the hex seed from `path`, or create one (mode 0600) if absent.
+    pub fn load_or_generate(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let seed = match fs::read_to_string(path) {
+            Ok(text) => hex::decode(text.trim())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let seed = fresh_nonce()?.to_vec();
+                write_private(path, hex::encode(&seed).as_bytes())?;
+                seed
+            }
+            Err(e) => return Err(e),
+        };
+        if seed.len() != 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "key seed must be 32 bytes",
+            ));
+        }
+        Ok(Self::from_seed(&seed))
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.signer.public_key_bytes().to_vec()
+    }
+
+    /// `CERT { SIG, DELE { PUBK, MINT, MAXT } }` for `online` in `version`.
+    fn certify(&mut self, online: &OnlineKey, version: Version) -> io::Result<Vec<u8>> {
+        let mut dele = RtMessage::with_capacity(3);
+        dele.add_field(Tag::PUBK, &online.public_key())
+            .map_err(rt_to_io)?;
+        dele.add_field(
+            Tag::MINT,
+            &version.encode_time(online.mint_us).to_le_bytes(),
+        )
+        .map_err(rt_to_io)?;
+        dele.add_field(
+            Tag::MAXT,
+            &version.encode_time(online.maxt_us).to_le_bytes(),
+        )
+        .map_err(rt_to_io)?;
+        let dele = dele.encode().map_err(rt_to_io)?;
+
+        self.signer.update(version.delegation_context());
+        self.signer.update(&dele);
+        let sig = self.signer.sign();
+
+        let mut cert = RtMessage::with_capacity(2);
+        cert.add_field(Tag::SIG, &sig).map_err(rt_to_io)?;
+        cert.add_field(Tag::DELE, &dele).map_err(rt_to_io)?;
+        cert.encode().map_err(rt_to_io)
+    }
+}
+
+/// Short-lived key that signs responses, delegated by the long-term key.
+struct OnlineKey {
+    signer: Signer,
+    mint_us: u64,
+    maxt_us: u64,
+    certs: HashMap<Version, Vec<u8>>,
+}
+
+impl OnlineKey {
+    fn delegate(long_term: &mut LongTermKey, lifetime: Duration) -> io::Result<Self> {
+        let now = sys_to_us(SystemTime::now());
+        let mut key = Self {
+            signer: Signer::from_seed(&fresh_nonce()?),
+            mint_us: now.saturating_sub(3_600_000_000), // tolerate skewed clients
+            maxt_us: now + lifetime.as_micros() as u64,
+            certs: HashMap::new(),
+        };
+        for v in [Version::Classic, Version::Ietf] {
+            let cert = long_term.certify(&key, v)?;
+            key.certs.insert(v, cert);
+        }
+        Ok(key)
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.signer.public_key_bytes().to_vec()
+    }
+
+    fn expires_within(&self, margin: Duration) -> bool {
+        sys_to_us(SystemTime::now()) + margin.as_micros() as u64 >= self.maxt_us
+    }
+}
+
+// -------------------------------------------------------------------------
+// Responder
+
+pub struct RoughtimeServer {
+    cfg: ServerConfig,
+    long_term: LongTermKey,
+    online: OnlineKey,
+    clock: CorrectedClock,
+}
+
+impl RoughtimeServer {
+    pub fn new(cfg: ServerConfig, clock: CorrectedClock) -> io::Result<Self> {
+        let long_term = LongTermKey::load_or_generate(&cfg.key_file)?;
+        Self::with_key(cfg, long_term, clock)
+    }
+
+    /// As `new`, with the long-term key supplied (`cfg.key_file` unused).
+    pub fn with_key(
+        cfg: ServerConfig,
+        mut long_term: LongTermKey,
+        clock: CorrectedClock,
+    ) -> io::Result<Self> {
+        let online = OnlineKey::delegate(&mut long_term, cfg.delegation)?;
+        Ok(Self {
+            cfg,
+            long_term,
+            online,
+            clock,
+        })
+    }
+
+    pub fn public_key(&self) -> Vec<u8> {
+        self.long_term.public_key()
+    }
+
+    /// Answer requests on `cfg.bind` forever.
+    pub fn run(&mut self) -> Result<(), TimestampError> {
+        let sock = UdpSocket::bind(&self.cfg.bind)?;
+        let mut buf = [0u8; 2048];
+        let mut dropped = 0usize; // requests left unanswered while stale
+        loop {
+            // block for the first request, then drain whatever else is queued
+            sock.set_nonblocking(false)?;
+            let mut batch = Vec::with_capacity(self.cfg.batch_size);
+            let (len, peer) = sock.recv_from(&mut buf)?;
+            push_request(&mut batch, &buf[..len], peer);
+            sock.set_nonblocking(true)?;
+            while batch.len() < self.cfg.batch_size {
+                match sock.recv_from(&mut buf) {
+                    Ok((len, peer)) => push_request(&mut batch, &buf[..len], peer),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            if self.online.expires_within(self.cfg.delegation / 10) {
+                self.online = OnlineKey::delegate(&mut self.long_term, self.cfg.delegation)?;
+            }
+            let reading = match self.clock.now() {
+                Some(r) if !r.stale => r,
+                _ => {
+                    // no fresh upstream time: stay silent rather than sign it
+                    if dropped == 0 {
+                        log::error!("roughtime   : no fresh beacon estimate, not answering");
+                    }
+                    dropped += batch.len();
+                    continue;
+                }
+            };
+            if dropped > 0 {
+                log::info!(
+                    "roughtime   : answering again ({dropped} requests dropped while stale)"
+                );
+                dropped = 0;
+            }
+            for (peer, reply) in self.respond(&batch, &reading)? {
+                if let Err(e) = sock.send_to(&reply, peer) {
+                    log::error!("roughtime   : reply to {peer} failed: {e}");
+                }
+            }
+        }
+    }
+
+    /// Signed replies for one batch of `(version, nonce, peer)` requests.
+    pub(crate) fn respond(
+        &mut self,
+        batch: &[(Version, Vec<u8>, SocketAddr)],
+        reading: &Reading,
+    ) -> io::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        let mut out = Vec::with_capacity(batch.len());
+        for version in [Version::Classic, Version::Ietf] {
+            let group: Vec<_> = batch.iter().filter(|(v, ..)| *v == version).collect();
+            if group.is_empty() {
+                continue;
+            }
+            let mut tree = version.merkle();
+            for (_, nonce, _) in &group {
+                tree.push_leaf(nonce);
+            }
+            let root = tree.compute_root();
+
+            let mut srep = RtMessage::with_capacity(4);
+            if version == Version::Ietf {
+                srep.add_field(Tag::VER, &wire::IETF_VERSION.to_le_bytes())
+                    .map_err(rt_to_io)?;
+            }
+            srep.add_field(
+                Tag::RADI,
+                &version.encode_radius(reading.uncert_us).to_le_bytes(),
+            )
+            .map_err(rt_to_io)?;
+            srep.add_field(
+                Tag::MIDP,
+                &version.encode_time(sys_to_us(reading.time)).to_le_bytes(),
+            )
+            .map_err(rt_to_io)?;
+            srep.add_field(Tag::ROOT, &root).map_err(rt_to_io)?;
+            let srep = srep.encode().map_err(rt_to_io)?;
+
+            self.online.signer.update(RESPONSE_CONTEXT);
+            self.online.signer.update(&srep);
+            let sig = self.online.signer.sign();
+            let cert = &self.online.certs[&version];
+
+            for (idx, (_, nonce, peer)) in group.iter().enumerate() {
+                let mut resp = RtMessage::with_capacity(7);
+                resp.add_field(Tag::SIG, &sig).map_err(rt_to_io)?;
+                if version == Version::Ietf {
+                    resp.add_field(Tag::VER, &wire::IETF_VERSION.to_le_bytes())
+                        .map_err(rt_to_io)?;
+                    resp.add_field(Tag::NONC, nonce).map_err(rt_to_io)?;
+                }
+                resp.add_field(Tag::PATH, &tree.get_paths(idx))
+                    .map_err(rt_to_io)?;
+                resp.add_field(Tag::SREP, &srep).map_err(rt_to_io)?;
+                resp.add_field(Tag::CERT, cert).map_err(rt_to_io)?;
+                resp.add_field(Tag::INDX, &(idx as u32).to_le_bytes())
+                    .map_err(rt_to_io)?;
+                out.push((*peer, version.frame(resp.encode().map_err(rt_to_io)?)));
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn push_request(batch: &mut Vec<(Version, Vec<u8>, SocketAddr)>, buf: &[u8], peer: SocketAddr) {
+    match wire::parse_request(buf) {
+        Ok((version, nonce)) => batch.push((version, nonce, peer)),
+        Err(e) => log::error!("roughtime   : dropped request from {peer}: {e}"),
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(data)
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, data: &[u8]) -> io::Result<()> {
+    fs::write(path, data)
+}
+
+/// Run a Roughtime server fed by `client`'s beacons.
+pub fn serve(cfg: ServerConfig, client: TimestampClient) -> Result<(), TimestampError> {
+    let clock = CorrectedClock::new(cfg.max_age);
+    clock.spawn_refresher(client, cfg.poll);
+    let mut server = RoughtimeServer::new(cfg, clock)?;
+    log::info!(
+        "roughtime   : public key {}",
+        hex::encode(server.public_key())
+    );
+    server.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> RoughtimeServer {
+        RoughtimeServer::with_key(
+            ServerConfig::default(),
+            LongTermKey::from_seed(&[7; 32]),
+            CorrectedClock::new(Duration::MAX),
+        )
+        .unwrap()
+    }
+
+    fn reading(time: SystemTime, uncert_us: u64) -> Reading {
+        Reading {
+            time,
+            uncert_us,
+            age: Duration::ZERO,
+            stale: false,
+            min_rtt_us: 0,
+        }
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn replies_verify_for_both_wire_formats() {
+        let mut server = server();
+        let now = SystemTime::now();
+        let time = SystemTime::UNIX_EPOCH + Duration::from_micros(sys_to_us(now));
+        let requests = [
+            (Version::Classic, vec![1u8; 64]),
+            (Version::Ietf, vec![2u8; 32]),
+            (Version::Classic, vec![3u8; 64]),
+            (Version::Ietf, vec![4u8; 32]),
+        ];
+        let mut batch = Vec::new();
+        for (i, (version, nonce)) in requests.iter().enumerate() {
+            let datagram = wire::build_request(*version, nonce).unwrap();
+            push_request(&mut batch, &datagram, peer(1000 + i as u16));
+        }
+        assert_eq!(batch.len(), requests.len());
+
+        let replies = server.respond(&batch, &reading(time, 2_500)).unwrap();
+        assert_eq!(replies.len(), requests.len());
+        for (addr, datagram) in &replies {
+            let i = (addr.port() - 1000) as usize;
+            let (version, nonce) = &requests[i];
+            let reply = wire::parse_reply(datagram).unwrap();
+            assert_eq!(reply.version, *version);
+            wire::verify_reply(&reply, &server.public_key()).unwrap();
+            wire::verify_inclusion(&reply, nonce).unwrap();
+            assert!(wire::verify_inclusion(&reply, &requests[(i + 2) % 4].1).is_err());
+
+            let (_, body) = wire::unframe(datagram).unwrap();
+            let msg = RtMessage::from_bytes(body).unwrap();
+            match version {
+                Version::Classic => {
+                    assert_eq!(reply.midpoint_us, sys_to_us(time));
+                    assert_eq!(reply.radius_us, 2_500);
+                    assert!(msg.get_field(Tag::NONC).is_none());
+                    assert!(msg.get_field(Tag::VER).is_none());
+                }
+                Version::Ietf => {
+                    assert_eq!(reply.midpoint_us, sys_to_us(time) / 1_000_000 * 1_000_000);
+                    assert_eq!(reply.radius_us, 1_000_000);
+                    assert_eq!(msg.get_field(Tag::NONC), Some(&nonce[..]));
+                    let ver = msg.get_field(Tag::VER).unwrap();
+                    assert_eq!(ver, &wire::IETF_VERSION.to_le_bytes()[..]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replies_from_another_key_do_not_verify() {
+        let mut server = server();
+        let other = LongTermKey::from_seed(&[8; 32]).public_key();
+        let mut batch = Vec::new();
+        let datagram = wire::build_request(Version::Ietf, &[9; 32]).unwrap();
+        push_request(&mut batch, &datagram, peer(1));
+        let replies = server
+            .respond(&batch, &reading(SystemTime::now(), 10))
+            .unwrap();
+        let reply = wire::parse_reply(&replies[0].1).unwrap();
+        assert!(wire::verify_reply(&reply, &other).is_err());
+    }
+
+    #[test]
+    fn malformed_requests_are_dropped() {
+        let mut batch = Vec::new();
+        push_request(&mut batch, b"not roughtime", peer(1));
+        assert!(batch.is_empty());
+    }
+}
@@ -0,0 +1,492 @@
+//! Corrected clock in shared memory, for other processes on the host.
+//!
+//! One daemon (`run`) keeps the beacon rounds going and publishes offset,
+//! frequency and bound into a small file-backed segment (`/dev/shm` by
+//! default).  Readers map it read-only and correct their own clock
+//! readings with `ShmReader::now`: a clock read plus a few loads, no
+//! syscalls, no beacon traffic.
+//!
+//! The segment is a seqlock: the writer makes `seq` odd, stores the
+//! fields, then makes it even again; a reader retries until it sees the
+//! same even `seq` before and after its loads.  Every field is an
+//! `AtomicU64` so torn reads are detected rather than undefined.
+
+use crate::{
+    client::TimestampClient,
+    corrected::{CorrectedClock, Estimate, Reading, PHI_PPM},
+    drift::DriftEstimator,
+    fresh_nonce, log, TimestampError, TimestampResponse,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    hint, io, mem,
+    os::unix::{
+        fs::{OpenOptionsExt, PermissionsExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    ptr,
+    sync::atomic::{fence, AtomicU64, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_PATH: &str = "/dev/shm/rt_ping";
+/// World-readable: any process on the host may correct its clock.
+pub const DEFAULT_MODE: u32 = 0o644;
+const MAGIC: u64 = 0x5254_5f50_494e_4731; // "RT_PING1"
+/// Bumped on any layout change.
+const LAYOUT_VERSION: u64 = 1;
+/// Reader retries before assuming the writer died mid-update.
+const MAX_SPINS: u32 = 100_000;
+
+/// Shared layout; native endianness, 64-bit fields only.
+#[repr(C)]
+struct Segment {
+    magic: AtomicU64,
+    version: AtomicU64,
+    /// Odd while an update is in progress; 0 = never published.
+    seq: AtomicU64,
+    /// Local wall time the parameters refer to, µs since epoch.
+    ref_us: AtomicU64,
+    /// True − local at `ref_us`, ns (i64 bits).
+    offset_ns: AtomicU64,
+    /// Offset gained per second of local time, ppm (f64 bits).
+    freq_ppm: AtomicU64,
+    /// Bound at `ref_us`, ns.
+    uncert_ns: AtomicU64,
+    /// Bound growth per second after `ref_us`, ppm (f64 bits).
+    growth_ppm: AtomicU64,
+    /// Readers flag the data stale after this long, µs.
+    max_age_us: AtomicU64,
+    min_rtt_us: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShmConfig {
+    pub path: PathBuf,
+    /// Segment file mode; readers only need read access.
+    pub mode: u32,
+    /// Time between beacon rounds.
+    pub every: Duration,
+    /// Readers flag the data stale after this long without a round.
+    pub max_age: Duration,
+}
+
+impl Default for ShmConfig {
+    fn default() -> Self {
+        Self {
+            path: DEFAULT_PATH.into(),
+            mode: DEFAULT_MODE,
+            every: Duration::from_secs(16),
+            max_age: Duration::from_secs(64),
+        }
+    }
+}
+
+/// What gets published.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct ClockParams {
+    pub ref_time: SystemTime,
+    pub offset_ns: i64,
+    pub freq_ppm: f64,
+    pub uncert_ns: u64,
+    pub growth_ppm: f64,
+    pub max_age: Duration,
+    pub min_rtt_us: u64,
+}
+
+impl ClockParams {
+    /// Parameters after one beacon round, with `drift` already fed it.
+    pub fn from_response(
+        resp: &TimestampResponse,
+        drift: &DriftEstimator,
+        max_age: Duration,
+    ) -> Self {
+        let est = Estimate::from_response(resp).with_drift(drift);
+        Self::from_estimate(&est, local_time(resp), max_age)
+    }
+
+    /// Parameters for an estimate taken at local wall time `ref_time`.
+    pub fn from_estimate(est: &Estimate, ref_time: SystemTime, max_age: Duration) -> Self {
+        Self {
+            ref_time,
+            offset_ns: (est.offset_us * 1_000) as i64,
+            freq_ppm: est.freq_ppm,
+            uncert_ns: est.uncert_us * 1_000,
+            growth_ppm: PHI_PPM as f64 + est.skew_ppm,
+            max_age,
+            min_rtt_us: est.min_rtt_us,
+        }
+    }
+
+    /// Corrected reading of `local`.
+    pub fn at(&self, local: SystemTime) -> Reading {
+        let dt = signed_secs(local, self.ref_time);
+        let offset_ns = self.offset_ns as f64 + self.freq_ppm * 1e3 * dt;
+        let mag = Duration::from_nanos(offset_ns.abs() as u64);
+        let time = if offset_ns >= 0.0 {
+            local + mag
+        } else {
+            local - mag
+        };
+        let age = Duration::from_secs_f64(dt.max(0.0));
+        let uncert_ns = self.uncert_ns as f64 + self.growth_ppm * 1e3 * dt.abs();
+        Reading {
+            time,
+            uncert_us: (uncert_ns / 1e3).ceil() as u64,
+            age,
+            stale: age > self.max_age,
+            min_rtt_us: self.min_rtt_us,
+        }
+    }
+}
+
+fn local_time(resp: &TimestampResponse) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(resp.local_timestamp)
+}
+
+fn signed_secs(t: SystemTime, since: SystemTime) -> f64 {
+    match t.duration_since(since) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+// -------------------------------------------------------------------------
+// Mapping
+
+struct Mapping {
+    ptr: *mut libc::c_void,
+    _file: File,
+}
+
+// SAFETY: the mapping is only accessed through `Segment`'s atomics.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: File, writable: bool) -> io::Result<Self> {
+        let len = mem::size_of::<Segment>();
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { ptr, _file: file })
+    }
+
+    fn segment(&self) -> &Segment {
+        // SAFETY: page-aligned, at least `size_of::<Segment>()` bytes, and
+        // alive as long as `self`.
+        unsafe { &*(self.ptr as *const Segment) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, mem::size_of::<Segment>());
+        }
+    }
+}
+
+// -------------------------------------------------------------------------
+// Writer / reader
+
+/// The daemon's side.  Only one writer per segment.
+pub struct ShmPublisher {
+    map: Mapping,
+}
+
+impl ShmPublisher {
+    /// Create (or take over) the segment at `path` with file mode `mode`
+    /// (`DEFAULT_MODE` for world-readable).  A segment taken over gets
+    /// `mode` too, whatever it had before.
+    pub fn create(path: impl AsRef<Path>, mode: u32) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(mode)
+            .open(&path)?;
+        // `mode` above is filtered by the umask and ignored for an
+        // existing file
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        file.set_len(mem::size_of::<Segment>() as u64)?;
+        let map = Mapping::new(file, true)?;
+        let seg = map.segment();
+        if seg.magic.load(Ordering::Relaxed) != MAGIC
+            || seg.version.load(Ordering::Relaxed) != LAYOUT_VERSION
+        {
+            seg.seq.store(0, Ordering::Relaxed);
+            seg.version.store(LAYOUT_VERSION, Ordering::Relaxed);
+            seg.magic.store(MAGIC, Ordering::Release);
+        } else if seg.seq.load(Ordering::Relaxed) % 2 == 1 {
+            // previous writer died mid-update; its data is suspect
+            seg.seq.store(0, Ordering::Release);
+        }
+        Ok(Self { map })
+    }
+
+    pub fn publish(&self, p: &ClockParams) {
+        let seg = self.map.segment();
+        let seq = seg.seq.load(Ordering::Relaxed);
+        seg.seq.store(seq | 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let ref_us = p
+            .ref_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros();
+        seg.ref_us.store(ref_us as u64, Ordering::Relaxed);
+        seg.offset_ns.store(p.offset_ns as u64, Ordering::Relaxed);
+        seg.freq_ppm.store(p.freq_ppm.to_bits(), Ordering::Relaxed);
+        seg.uncert_ns.store(p.uncert_ns, Ordering::Relaxed);
+        seg.growth_ppm
+            .store(p.growth_ppm.to_bits(), Ordering::Relaxed);
+        seg.max_age_us
+            .store(p.max_age.as_micros() as u64, Ordering::Relaxed);
+        seg.min_rtt_us.store(p.min_rtt_us, Ordering::Relaxed);
+        seg.seq.store((seq | 1) + 1, Ordering::Release);
+    }
+}
+
+/// Any process's side: read-only mapping.
+pub struct ShmReader {
+    map: Mapping,
+}
+
+impl ShmReader {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        if file.metadata()?.len() < mem::size_of::<Segment>() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "segment too small",
+            ));
+        }
+        let map = Mapping::new(file, false)?;
+        let seg = map.segment();
+        if seg.magic.load(Ordering::Acquire) != MAGIC
+            || seg.version.load(Ordering::Relaxed) != LAYOUT_VERSION
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an rt_ping segment (or a different layout version)",
+            ));
+        }
+        Ok(Self { map })
+    }
+
+    /// Consistent copy of the published parameters; `None` before the
+    /// first publication or while the writer appears stuck mid-update.
+    pub fn params(&self) -> Option<ClockParams> {
+        let seg = self.map.segment();
+        for _ in 0..MAX_SPINS {
+            let s1 = seg.seq.load(Ordering::Acquire);
+            if s1 == 0 {
+                return None;
+            }
+            if s1 % 2 == 1 {
+                hint::spin_loop();
+                continue;
+            }
+            let p = ClockParams {
+                ref_time: UNIX_EPOCH + Duration::from_micros(seg.ref_us.load(Ordering::Relaxed)),
+                offset_ns: seg.offset_ns.load(Ordering::Relaxed) as i64,
+                freq_ppm: f64::from_bits(seg.freq_ppm.load(Ordering::Relaxed)),
+                uncert_ns: seg.uncert_ns.load(Ordering::Relaxed),
+                growth_ppm: f64::from_bits(seg.growth_ppm.load(Ordering::Relaxed)),
+                max_age: Duration::from_micros(seg.max_age_us.load(Ordering::Relaxed)),
+                min_rtt_us: seg.min_rtt_us.load(Ordering::Relaxed),
+            };
+            fence(Ordering::Acquire);
+            if seg.seq.load(Ordering::Relaxed) == s1 {
+                return Some(p);
+            }
+        }
+        None
+    }
+
+    /// Corrected time with its bound.
+    pub fn now(&self) -> Option<Reading> {
+        let local = SystemTime::now();
+        Some(self.params()?.at(local))
+    }
+}
+
+/// Publish a fresh estimate after every beacon round, forever.  Failed
+/// rounds are reported and skipped; readers see the bound grow and,
+/// after `max_age`, `stale`.
+pub fn run(cfg: &ShmConfig, client: &TimestampClient) -> Result<(), TimestampError> {
+    let out = ShmPublisher::create(&cfg.path, cfg.mode)?;
+    let clock = CorrectedClock::new(cfg.max_age);
+    loop {
+        let round = fresh_nonce()
+            .map_err(TimestampError::from)
+            .and_then(|n| client.timestamp(n));
+        match round {
+            Ok(resp) => {
+                if let Err(e) = clock.refresh(&resp, client) {
+                    log::error!("shm         : drift file not saved: {e}");
+                }
+                let est = clock.estimate().expect("refresh sets an estimate");
+                let p = ClockParams::from_estimate(&est, local_time(&resp), cfg.max_age);
+                out.publish(&p);
+                log::info!(
+                    "shm         : offset {:+} ns  freq {:+.3} ppm  ±{} ns",
+                    p.offset_ns,
+                    p.freq_ppm,
+                    p.uncert_ns
+                );
+            }
+            Err(e) => log::error!("shm         : beacon round failed: {e}"),
+        }
+        thread::sleep(cfg.every);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    fn segment_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rt_ping-shm-{name}-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn params(i: u64) -> ClockParams {
+        ClockParams {
+            ref_time: UNIX_EPOCH + Duration::from_secs(1_700_000_000 + i),
+            offset_ns: i as i64 * 1_000,
+            freq_ppm: i as f64 / 4.0,
+            uncert_ns: i * 7,
+            growth_ppm: 15.0 + i as f64,
+            max_age: Duration::from_secs(64),
+            min_rtt_us: i,
+        }
+    }
+
+    #[test]
+    fn readers_see_each_publication_whole() {
+        let path = segment_path("seqlock");
+        let out = ShmPublisher::create(&path, DEFAULT_MODE).unwrap();
+        let reader = ShmReader::open(&path).unwrap();
+        assert_eq!(reader.params(), None, "nothing published yet");
+        out.publish(&params(1));
+        assert_eq!(reader.params(), Some(params(1)));
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut i = 2;
+                // paced, or a reader could spin past `MAX_SPINS` and give up
+                while !stop.load(Ordering::Relaxed) {
+                    out.publish(&params(i));
+                    i += 1;
+                    thread::sleep(Duration::from_micros(20));
+                }
+                i
+            })
+        };
+        let mut newest = 1;
+        while newest < 500 {
+            let p = reader.params().expect("writer is not stuck");
+            assert_eq!(p, params(p.min_rtt_us), "torn read");
+            assert!(p.min_rtt_us >= newest, "went back to an older publication");
+            newest = p.min_rtt_us;
+        }
+        stop.store(true, Ordering::Relaxed);
+        assert!(writer.join().unwrap() > 500);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_takeover_drops_a_half_written_update() {
+        let path = segment_path("takeover");
+        let out = ShmPublisher::create(&path, DEFAULT_MODE).unwrap();
+        out.publish(&params(3));
+        // the writer dies between making `seq` odd and even again
+        out.map.segment().seq.fetch_add(1, Ordering::Relaxed);
+        drop(out);
+        let reader = ShmReader::open(&path).unwrap();
+        assert_eq!(reader.params(), None);
+        let out = ShmPublisher::create(&path, DEFAULT_MODE).unwrap();
+        assert_eq!(reader.params(), None);
+        out.publish(&params(4));
+        assert_eq!(reader.params(), Some(params(4)));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_segment_gets_the_configured_mode() {
+        let path = segment_path("mode");
+        let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+        ShmPublisher::create(&path, 0o600).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        // an existing segment is opened up (or down) as asked
+        ShmPublisher::create(&path, DEFAULT_MODE).unwrap();
+        assert_eq!(mode(&path), 0o644);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn readings_extrapolate_offset_and_bound() {
+        let p = ClockParams {
+            ref_time: UNIX_EPOCH + Duration::from_secs(1_000),
+            offset_ns: 2_000_000,
+            freq_ppm: 10.0,
+            uncert_ns: 500_000,
+            growth_ppm: 20.0,
+            max_age: Duration::from_secs(60),
+            min_rtt_us: 900,
+        };
+        let at_ref = p.at(p.ref_time);
+        assert_eq!(at_ref.time, p.ref_time + Duration::from_millis(2));
+        assert_eq!((at_ref.uncert_us, at_ref.age), (500, Duration::ZERO));
+        assert_eq!(at_ref.min_rtt_us, 900);
+
+        // 10 s later: +100 µs of offset, +200 µs of bound
+        let later = p.ref_time + Duration::from_secs(10);
+        let r = p.at(later);
+        assert_eq!(r.time, later + Duration::from_micros(2_100));
+        assert_eq!(r.uncert_us, 700);
+        assert_eq!(r.age, Duration::from_secs(10));
+        assert!(!r.stale);
+
+        // a reading from before the reference still widens the bound
+        let before = p.ref_time - Duration::from_secs(10);
+        let r = p.at(before);
+        assert_eq!(r.time, before + Duration::from_micros(1_900));
+        assert_eq!((r.uncert_us, r.age), (700, Duration::ZERO));
+
+        let negative = ClockParams {
+            offset_ns: -3_000_000,
+            freq_ppm: 0.0,
+            ..p
+        };
+        assert_eq!(negative.at(later).time, later - Duration::from_millis(3));
+        assert!(p.at(p.ref_time + Duration::from_secs(61)).stale);
+    }
+}
@@ -0,0 +1,48 @@
+//! Stopping the daemons: a flag each server's accept loop polls, and the
+//! CLI's SIGINT / SIGTERM hook that raises it.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How often the signal thread looks for a delivered signal.
+const SIGNAL_POLL: Duration = Duration::from_millis(25);
+
+/// Stops a running `http`, `ipc` or `grpc` server.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle(Arc<AtomicBool>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// SIGINT / SIGTERM trigger `handle` (for the CLI).
+#[cfg(unix)]
+pub fn on_signals(handle: ShutdownHandle) {
+    static SIGNALLED: AtomicBool = AtomicBool::new(false);
+    extern "C" fn on_signal(_: libc::c_int) {
+        SIGNALLED.store(true, Ordering::SeqCst);
+    }
+    let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+    thread::spawn(move || {
+        while !SIGNALLED.load(Ordering::SeqCst) {
+            thread::sleep(SIGNAL_POLL);
+        }
+        handle.shutdown();
+    });
+}
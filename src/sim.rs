@@ -0,0 +1,445 @@
+//! Deterministic network simulation for the offset / uncertainty math.
+//!
+//! There is one true timeline.  Our local clock (a `SimClock`) runs
+//! `local_error_us` off it, each `SimServer` runs `clock_error_us` off it
+//! and sits behind configurable one-way delays and loss.  Every server is a
+//! responder on a loopback socket and queries take the ordinary probe path
+//! (`query_roughtime` / `query_sntp`), so exchange timing and reply parsing
+//! are the production code.  The responder moves the shared clock by the
+//! outbound delay before it stamps and by the return delay before it
+//! answers; the network lets one query through at a time, so results are
+//! identical however the probe threads interleave.  A lost query is dropped
+//! at the responder (the client sees a real timeout); loss draws from a
+//! per-server seeded generator.
+//!
+//! Roughtime delegations are minted on the real clock, so start the
+//! `SimClock` near `SystemTime::now()` for authenticated servers.
+
+use crate::{
+    clock::{Clock, SimClock},
+    corrected::{CorrectedClock, Reading},
+    log,
+    ntp::{self, NtpPacket},
+    query_roughtime, query_sntp,
+    server::{LongTermKey, RoughtimeServer, ServerConfig},
+    source::TimeSource,
+    sys_to_us,
+    wire::{self, Version},
+    BeaconMeta, ProbeOptions, TimestampError,
+};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// How often an idle responder looks for `SimServer` having been dropped.
+const IDLE_POLL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct SimNetwork {
+    pub clock: Arc<SimClock>,
+    /// Local clock − true time, µs.
+    pub local_error_us: i128,
+    /// Held for the whole of one query: one exchange on the wire at a time.
+    gate: Arc<Mutex<()>>,
+}
+
+impl SimNetwork {
+    pub fn new(clock: Arc<SimClock>, local_error_us: i128) -> Self {
+        Self {
+            clock,
+            local_error_us,
+            gate: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// What a perfect `offset_us` (true − local) would be.
+    pub fn true_offset_us(&self) -> i128 {
+        -self.local_error_us
+    }
+
+    /// Start a responder for `cfg` on 127.0.0.1.
+    pub fn server(&self, cfg: SimServerConfig) -> io::Result<SimServer> {
+        let sock = UdpSocket::bind("127.0.0.1:0")?;
+        sock.set_read_timeout(Some(IDLE_POLL))?;
+        let addr = sock.local_addr()?;
+
+        let roughtime = if cfg.authenticated {
+            let mut seed = [0x5a; 32];
+            seed[..8].copy_from_slice(&cfg.seed.to_le_bytes());
+            let server = RoughtimeServer::with_key(
+                ServerConfig::default(),
+                LongTermKey::from_seed(&seed),
+                CorrectedClock::new(Duration::MAX),
+            )?;
+            Some(server)
+        } else {
+            None
+        };
+        let public_key = roughtime.as_ref().map(RoughtimeServer::public_key);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder = Responder {
+            sock,
+            net: self.clone(),
+            cfg: cfg.clone(),
+            rng: cfg.seed.max(1),
+            roughtime,
+            stop: stop.clone(),
+        };
+        thread::spawn(move || responder.run());
+        Ok(SimServer {
+            net: self.clone(),
+            cfg,
+            addr,
+            public_key,
+            stop,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimServerConfig {
+    pub name: String,
+    /// Server clock − true time, µs (honest servers keep this within radius).
+    pub clock_error_us: i128,
+    pub radius_us: u32,
+    /// Client → server delay.
+    pub delay_out: Duration,
+    /// Server → client delay.
+    pub delay_back: Duration,
+    /// Probability in [0, 1] that a query is lost.
+    pub loss: f64,
+    pub seed: u64,
+    /// Signed Roughtime replies; otherwise the server answers SNTP.
+    pub authenticated: bool,
+}
+
+impl SimServerConfig {
+    /// Symmetric, lossless, honest server with `rtt`.
+    pub fn symmetric(name: impl Into<String>, rtt: Duration) -> Self {
+        Self {
+            name: name.into(),
+            clock_error_us: 0,
+            radius_us: 1_000,
+            delay_out: rtt / 2,
+            delay_back: rtt / 2,
+            loss: 0.0,
+            seed: 1,
+            authenticated: true,
+        }
+    }
+}
+
+/// Client side of a simulated server; the responder stops when it drops.
+#[derive(Debug)]
+pub struct SimServer {
+    net: SimNetwork,
+    cfg: SimServerConfig,
+    addr: SocketAddr,
+    public_key: Option<Vec<u8>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl SimServer {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Long-term key of an authenticated server.
+    pub fn public_key(&self) -> Option<&[u8]> {
+        self.public_key.as_deref()
+    }
+}
+
+impl Drop for SimServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+impl TimeSource for SimServer {
+    fn name(&self) -> &str {
+        &self.cfg.name
+    }
+
+    fn authenticated(&self) -> bool {
+        self.cfg.authenticated
+    }
+
+    fn remote(&self) -> bool {
+        false
+    }
+
+    fn query(&self, hash: &[u8; 32], opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError> {
+        let _turn = self.net.gate.lock().unwrap_or_else(PoisonError::into_inner);
+        let clock: Arc<dyn Clock> = self.net.clock.clone();
+        let opts = ProbeOptions {
+            clock: Some(clock),
+            kernel_timestamps: false,
+            bind: None,
+            interface: None,
+            ..opts.clone()
+        };
+        let host = self.addr.to_string();
+        let mut meta = match &self.public_key {
+            Some(pk) => query_roughtime(&host, Version::Classic, Some(pk), hash, &opts)?,
+            None => query_sntp(&host, &opts)?,
+        };
+        meta.host = self.cfg.name.clone();
+        Ok(meta)
+    }
+}
+
+// -------------------------------------------------------------------------
+// Responder
+
+struct Responder {
+    sock: UdpSocket,
+    net: SimNetwork,
+    cfg: SimServerConfig,
+    rng: u64,
+    roughtime: Option<RoughtimeServer>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Responder {
+    fn run(mut self) {
+        let mut buf = [0u8; 2048];
+        while !self.stop.load(Ordering::Relaxed) {
+            let (len, peer) = match self.sock.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    log::error!("sim         : {}: {e}", self.cfg.name);
+                    return;
+                }
+            };
+            if self.lost() {
+                continue;
+            }
+            self.net.clock.advance(self.cfg.delay_out);
+            let reply = self.reply(&buf[..len], peer);
+            self.net.clock.advance(self.cfg.delay_back);
+            match reply {
+                Ok(reply) => {
+                    let _ = self.sock.send_to(&reply, peer);
+                }
+                Err(e) => log::error!("sim         : {}: dropped request: {e}", self.cfg.name),
+            }
+        }
+    }
+
+    /// xorshift64: reproducible loss decisions.
+    fn lost(&mut self) -> bool {
+        if self.cfg.loss <= 0.0 {
+            return false;
+        }
+        let s = &mut self.rng;
+        *s ^= *s << 13;
+        *s ^= *s >> 7;
+        *s ^= *s << 17;
+        (*s as f64 / u64::MAX as f64) < self.cfg.loss
+    }
+
+    /// The server's own clock: local reading − local error + server error.
+    fn server_time(&self) -> SystemTime {
+        let local_us = sys_to_us(self.net.clock.wall()) as i128;
+        let us = local_us - self.net.local_error_us + self.cfg.clock_error_us;
+        SystemTime::UNIX_EPOCH + Duration::from_micros(us.max(0) as u64)
+    }
+
+    fn reply(&mut self, req: &[u8], peer: SocketAddr) -> io::Result<Vec<u8>> {
+        let time = self.server_time();
+        let radius_us = self.cfg.radius_us as u64;
+        match &mut self.roughtime {
+            Some(server) => {
+                let (version, nonce) = wire::parse_request(req)?;
+                let reading = Reading {
+                    time,
+                    uncert_us: radius_us,
+                    age: Duration::ZERO,
+                    stale: false,
+                    min_rtt_us: 0,
+                };
+                let mut out = server.respond(&[(version, nonce, peer)], &reading)?;
+                out.pop()
+                    .map(|(_, reply)| reply)
+                    .ok_or_else(|| io::Error::other("no reply built"))
+            }
+            None => {
+                let req = NtpPacket::decode(req)?;
+                let ts = ntp::to_ntp_ts(time);
+                let resp = NtpPacket {
+                    leap: ntp::LEAP_NONE,
+                    version: req.version,
+                    mode: ntp::MODE_SERVER,
+                    stratum: 1,
+                    root_dispersion: ntp::us_to_short(radius_us),
+                    ref_id: *b"SIM\0",
+                    ref_ts: ts,
+                    orig_ts: req.tx_ts,
+                    rx_ts: ts,
+                    tx_ts: ts,
+                    ..Default::default()
+                };
+                Ok(resp.encode().to_vec())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::get_timestamp_from;
+
+    const MS: i128 = 1_000;
+
+    fn network(local_error_us: i128) -> SimNetwork {
+        // whole microseconds, so no reading is truncated on the wire
+        let start = SystemTime::UNIX_EPOCH + Duration::from_micros(sys_to_us(SystemTime::now()));
+        SimNetwork::new(Arc::new(SimClock::new(start)), local_error_us)
+    }
+
+    fn opts(net: &SimNetwork) -> ProbeOptions {
+        let clock: Arc<dyn Clock> = net.clock.clone();
+        ProbeOptions {
+            clock: Some(clock),
+            timeout: Duration::from_millis(300),
+            ..Default::default()
+        }
+    }
+
+    fn asymmetric(name: &str, error_us: i128, out_ms: u64, back_ms: u64) -> SimServerConfig {
+        SimServerConfig {
+            clock_error_us: error_us,
+            delay_out: Duration::from_millis(out_ms),
+            delay_back: Duration::from_millis(back_ms),
+            ..SimServerConfig::symmetric(name, Duration::ZERO)
+        }
+    }
+
+    #[test]
+    fn asymmetric_delay_biases_the_offset_by_half_the_difference() {
+        let net = network(2 * MS);
+        let server = net.server(asymmetric("a", 500, 30, 10)).unwrap();
+        let b = server.query(&[7; 32], &opts(&net)).unwrap();
+
+        // server error + (out − back) / 2 on top of the true offset
+        assert_eq!(b.offset_us, net.true_offset_us() + 500 + 10 * MS);
+        // radius + RTT / 2
+        assert_eq!(b.uncert_us, 1_000 + 20 * MS);
+        assert_eq!(b.rtt_ms, 40.0);
+        assert!((b.offset_us - net.true_offset_us()).abs() <= b.uncert_us);
+        assert_eq!(net.clock.elapsed(), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn worst_case_asymmetry_stays_inside_the_bound() {
+        let net = network(-7 * MS);
+        // all of the RTT on one leg, server at the edge of its radius
+        for (out, back, err) in [(40, 0, 1_000), (0, 40, -1_000)] {
+            let server = net.server(asymmetric("edge", err, out, back)).unwrap();
+            let b = server.query(&[1; 32], &opts(&net)).unwrap();
+            let miss = (b.offset_us - net.true_offset_us()).abs();
+            assert_eq!(miss, 1_000 + 20 * MS);
+            assert_eq!(miss, b.uncert_us);
+        }
+    }
+
+    #[test]
+    fn sntp_replies_take_the_same_path() {
+        let net = network(3 * MS);
+        let cfg = SimServerConfig {
+            authenticated: false,
+            ..asymmetric("ntp", -200, 12, 4)
+        };
+        let server = net.server(cfg).unwrap();
+        let b = server.query(&[0; 32], &opts(&net)).unwrap();
+        let want = net.true_offset_us() - 200 + 4 * MS;
+        // NTP timestamps carry 2^-32 s, the short format 2^-16 s
+        assert!((b.offset_us - want).abs() <= 1, "{} vs {want}", b.offset_us);
+        assert!(
+            (b.uncert_us - (1_000 + 8 * MS)).abs() <= 16,
+            "{}",
+            b.uncert_us
+        );
+        assert!(!b.authenticated);
+    }
+
+    #[test]
+    fn aggregate_is_bounded_by_its_beacons() {
+        let net = network(5 * MS);
+        let sources: Vec<Arc<dyn TimeSource>> = vec![
+            Arc::new(net.server(asymmetric("a", 300, 8, 2)).unwrap()),
+            Arc::new(net.server(asymmetric("b", -900, 1, 25)).unwrap()),
+            Arc::new(net.server(asymmetric("c", 0, 15, 15)).unwrap()),
+        ];
+        let resp = get_timestamp_from([3; 32], &sources, &opts(&net)).unwrap();
+
+        let mut offsets: Vec<i128> = resp.metadata.beacons.iter().map(|b| b.offset_us).collect();
+        offsets.sort();
+        let truth = net.true_offset_us();
+        assert_eq!(
+            offsets,
+            [truth - 900 - 12 * MS, truth, truth + 300 + 3 * MS]
+        );
+
+        // the median of honest beacons lands within the widest of their bounds
+        let combined = resp.offset_us();
+        let widest = resp
+            .metadata
+            .beacons
+            .iter()
+            .map(|b| b.uncert_us)
+            .max()
+            .unwrap();
+        assert_eq!(combined, truth);
+        assert!((combined - truth).abs() <= widest);
+        for b in &resp.metadata.beacons {
+            assert!((b.offset_us - truth).abs() <= b.uncert_us, "{}", b.host);
+        }
+    }
+
+    #[test]
+    fn lost_queries_time_out_and_retry() {
+        let net = network(0);
+        let lossy = net
+            .server(SimServerConfig {
+                loss: 1.0,
+                ..SimServerConfig::symmetric("gone", Duration::from_millis(10))
+            })
+            .unwrap();
+        let o = ProbeOptions {
+            timeout: Duration::from_millis(100),
+            retries: 2,
+            ..opts(&net)
+        };
+        let started = std::time::Instant::now();
+        let err = crate::run_query(&lossy, &[0; 32], &o).unwrap_err();
+        assert!(
+            started.elapsed() >= Duration::from_millis(300),
+            "gave up before the retries"
+        );
+        assert!(
+            matches!(&err, TimestampError::Io(e) if e.kind() == io::ErrorKind::TimedOut),
+            "{err}"
+        );
+        // nothing was answered, so the network never moved
+        assert_eq!(net.clock.elapsed(), Duration::ZERO);
+    }
+}
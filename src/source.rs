@@ -0,0 +1,345 @@
+//! `TimeSource`: one beacon of any kind behind a single query operation.
+//!
+//! Aggregation (`get_timestamp_from`), proofs and the CLI only see
+//! `BeaconMeta`s, so Roughtime, SNTP, the local clock, test mocks and
+//! out-of-tree sources are interchangeable.
+
+use crate::{
+    query_roughtime, query_sntp, wire::Version, BeaconKind, BeaconMeta, ProbeOptions,
+    TimestampError, TimingSource,
+};
+use std::{
+    io,
+    sync::Arc,
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// What backs a measurement beyond the numbers in `BeaconMeta`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Evidence {
+    /// Nothing verifiable (SNTP, local clock, mocks).
+    None,
+    /// The signed reply, verbatim, and the nonce it answers (both hex).
+    Roughtime {
+        version: Version,
+        nonce: String,
+        reply: String,
+    },
+    /// Opaque bytes from a custom source (hex).
+    Custom(String),
+}
+
+pub trait TimeSource: Send + Sync {
+    /// Host or label reported in `BeaconMeta::host`.
+    fn name(&self) -> &str;
+
+    /// Whether the source's answers are authenticated and may form the
+    /// quorum under `QuorumPolicy::AuthenticatedOnly`.
+    fn authenticated(&self) -> bool;
+
+    /// Blocking query bound to `hash` as nonce; the returned meta carries
+    /// the measurement and its `evidence`.
+    fn query(&self, hash: &[u8; 32], opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError>;
+
+    /// Whether queries go over the network and so count against
+    /// `ProbeOptions::rate_limit`.
+    fn remote(&self) -> bool {
+        true
+    }
+
+    /// Requests one `query` sends to the server, each taking a token
+    /// when `remote`.
+    fn requests(&self) -> u32 {
+        1
+    }
+}
+
+// -------------------------------------------------------------------------
+// Roughtime / SNTP
+
+#[derive(Debug, Clone)]
+pub struct RoughtimeSource {
+    pub host: String,
+    pub version: Version,
+    /// Long-term public key; when set, reply signatures are verified.
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl RoughtimeSource {
+    pub fn classic(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            version: Version::Classic,
+            public_key: None,
+        }
+    }
+
+    pub fn ietf(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            version: Version::Ietf,
+            public_key: None,
+        }
+    }
+}
+
+impl TimeSource for RoughtimeSource {
+    fn name(&self) -> &str {
+        &self.host
+    }
+
+    fn authenticated(&self) -> bool {
+        true
+    }
+
+    fn query(&self, hash: &[u8; 32], opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError> {
+        query_roughtime(&self.host, self.version, self.public_key.as_deref(), hash, opts)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SntpSource {
+    pub host: String,
+}
+
+impl SntpSource {
+    pub fn new(host: impl Into<String>) -> Self {
+        Self { host: host.into() }
+    }
+}
+
+impl TimeSource for SntpSource {
+    fn name(&self) -> &str {
+        &self.host
+    }
+
+    fn authenticated(&self) -> bool {
+        false
+    }
+
+    fn query(&self, _hash: &[u8; 32], opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError> {
+        query_sntp(&self.host, opts)
+    }
+}
+
+// -------------------------------------------------------------------------
+// Local clock / mock
+
+/// Our own clock, taken at face value with an assumed bound.
+#[derive(Debug, Clone)]
+pub struct LocalClockSource {
+    pub uncert_us: u64,
+}
+
+impl TimeSource for LocalClockSource {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    fn authenticated(&self) -> bool {
+        false
+    }
+
+    fn remote(&self) -> bool {
+        false
+    }
+
+    fn query(&self, _hash: &[u8; 32], _opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError> {
+        let now = SystemTime::now();
+        Ok(synthetic(
+            self.name(),
+            BeaconKind::LocalClock,
+            now,
+            Duration::ZERO,
+            0,
+            self.uncert_us,
+            false,
+        ))
+    }
+}
+
+/// Canned answers: `offset_us` ahead of the local clock after `rtt`.
+#[derive(Debug, Clone)]
+pub struct MockSource {
+    pub name: String,
+    pub offset_us: i128,
+    pub uncert_us: u64,
+    pub rtt: Duration,
+    pub authenticated: bool,
+    /// Answer with an I/O error instead (after `rtt`).
+    pub fail: bool,
+}
+
+impl MockSource {
+    pub fn new(name: impl Into<String>, offset_us: i128) -> Self {
+        Self {
+            name: name.into(),
+            offset_us,
+            uncert_us: 1_000,
+            rtt: Duration::from_millis(1),
+            authenticated: true,
+            fail: false,
+        }
+    }
+}
+
+impl TimeSource for MockSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    fn remote(&self) -> bool {
+        false
+    }
+
+    fn query(&self, _hash: &[u8; 32], _opts: &ProbeOptions) -> Result<BeaconMeta, TimestampError> {
+        let sent = SystemTime::now();
+        thread::sleep(self.rtt);
+        if self.fail {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{}: mock failure", self.name),
+            )
+            .into());
+        }
+        Ok(synthetic(
+            &self.name,
+            BeaconKind::Mock,
+            sent,
+            self.rtt,
+            self.offset_us,
+            self.uncert_us,
+            self.authenticated,
+        ))
+    }
+}
+
+fn synthetic(
+    name: &str,
+    kind: BeaconKind,
+    sent: SystemTime,
+    rtt: Duration,
+    offset_us: i128,
+    uncert_us: u64,
+    authenticated: bool,
+) -> BeaconMeta {
+    let true_time = sent + rtt / 2;
+    let local_us = true_time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i128;
+    BeaconMeta {
+        host: name.to_string(),
+        rtt_ms: rtt.as_secs_f64() * 1e3,
+        true_time,
+        midpoint_us: (local_us + offset_us).max(0) as u64,
+        offset_us,
+        uncert_us: uncert_us as i128,
+        radius_us: uncert_us.min(u32::MAX as u64) as u32,
+        timing: TimingSource::UserSpace,
+        kind,
+        authenticated,
+        evidence: Evidence::None,
+        addr: None,
+        asymmetry_us: None,
+        paths: Vec::new(),
+    }
+}
+
+// -------------------------------------------------------------------------
+// Spec strings
+
+/// Parse `roughtime://host:port`, `roughtime-ietf://host:port`,
+/// `sntp://host:port` or `local`; a bare `host:port` means classic Roughtime.
+/// The port is required: there is no default that suits every server.
+pub fn from_spec(spec: &str) -> io::Result<Arc<dyn TimeSource>> {
+    let src: Arc<dyn TimeSource> = match spec.split_once("://") {
+        Some(("roughtime", host)) => Arc::new(RoughtimeSource::classic(host_port(host)?)),
+        Some(("roughtime-ietf", host)) => Arc::new(RoughtimeSource::ietf(host_port(host)?)),
+        Some(("sntp", host)) | Some(("ntp", host)) => Arc::new(SntpSource::new(host_port(host)?)),
+        Some((scheme, _)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown time source scheme {scheme:?}"),
+            ))
+        }
+        None if spec == "local" => Arc::new(LocalClockSource {
+            uncert_us: 1_000_000,
+        }),
+        None => Arc::new(RoughtimeSource::classic(host_port(spec)?)),
+    };
+    Ok(src)
+}
+
+/// `host` itself, once checked to end in a numeric `:port`.
+fn host_port(host: &str) -> io::Result<&str> {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() && port.parse::<u16>().is_ok() => Ok(host),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("expected host:port, got {host:?}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(spec: &str) -> Arc<dyn TimeSource> {
+        from_spec(spec).unwrap_or_else(|e| panic!("{spec}: {e}"))
+    }
+
+    #[test]
+    fn schemes_pick_the_source_kind() {
+        let classic = parse("roughtime://roughtime.example:2002");
+        assert_eq!(classic.name(), "roughtime.example:2002");
+        assert!(classic.authenticated() && classic.remote());
+
+        let bare = parse("roughtime.example:2002");
+        assert_eq!(bare.name(), "roughtime.example:2002");
+        assert!(bare.authenticated());
+
+        let ietf = parse("roughtime-ietf://[::1]:2002");
+        assert_eq!(ietf.name(), "[::1]:2002");
+        assert!(ietf.authenticated());
+
+        for spec in ["sntp://pool.ntp.example:123", "ntp://pool.ntp.example:123"] {
+            let sntp = parse(spec);
+            assert_eq!(sntp.name(), "pool.ntp.example:123");
+            assert!(!sntp.authenticated() && sntp.remote());
+        }
+
+        let local = parse("local");
+        assert_eq!(local.name(), "local");
+        assert!(!local.authenticated() && !local.remote());
+    }
+
+    #[test]
+    fn unknown_schemes_are_rejected() {
+        let err = from_spec("gps://receiver:1").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(err.to_string().contains("\"gps\""), "{err}");
+    }
+
+    #[test]
+    fn a_missing_or_bad_port_is_rejected() {
+        for spec in [
+            "roughtime://roughtime.example",
+            "roughtime-ietf://roughtime.example:",
+            "sntp://pool.ntp.example:ntp",
+            "roughtime.example:99999",
+            "roughtime://:2002",
+            "localhost",
+        ] {
+            let err = from_spec(spec).err().unwrap_or_else(|| panic!("{spec} accepted"));
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(err.to_string().contains("host:port"), "{spec}: {err}");
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Kernel software timestamps for probe sockets (Linux `SO_TIMESTAMPING`).
+//!
+//! The kernel stamps the datagram when it leaves the socket layer (TX, read
+//! back from the error queue) and when it arrives (RX, delivered as a control
+//! message).  Both are `CLOCK_REALTIME`, so they slot straight into the
+//! `SystemTime` math in `parse_reply` without syscall/scheduler latency.
+
+use std::{net::UdpSocket, time::SystemTime};
+
+/// Raw reading of one exchange – any missing side falls back to user space.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct KernelStamps {
+    pub tx: Option<SystemTime>,
+    pub rx: Option<SystemTime>,
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::KernelStamps;
+    use std::{
+        io, mem,
+        net::UdpSocket,
+        os::unix::io::AsRawFd,
+        ptr,
+        time::{Duration, SystemTime},
+    };
+
+    const FLAGS: libc::c_uint = libc::SOF_TIMESTAMPING_SOFTWARE
+        | libc::SOF_TIMESTAMPING_TX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+        | libc::SOF_TIMESTAMPING_OPT_TSONLY;
+
+    pub(crate) fn enable(sock: &UdpSocket) -> io::Result<()> {
+        let flags = FLAGS;
+        let rc = unsafe {
+            libc::setsockopt(
+                sock.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_TIMESTAMPING,
+                &flags as *const _ as *const libc::c_void,
+                mem::size_of_val(&flags) as libc::socklen_t,
+            )
+        };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// `recv` that also returns the kernel RX timestamp (if one was attached).
+    pub(crate) fn recv(
+        sock: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, Option<SystemTime>)> {
+        recvmsg(sock, buf, 0)
+    }
+
+    /// Drain the error queue for the TX timestamp of the last datagram sent.
+    pub(crate) fn tx_stamp(sock: &UdpSocket) -> Option<SystemTime> {
+        let mut scratch = [0u8; 64];
+        // a few tries: the queue may also hold IP_RECVERR notifications
+        for _ in 0..4 {
+            match recvmsg(sock, &mut scratch, libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT) {
+                Ok((_, Some(ts))) => return Some(ts),
+                Ok((_, None)) => continue,
+                Err(_) => return None,
+            }
+        }
+        None
+    }
+
+    fn recvmsg(
+        sock: &UdpSocket,
+        buf: &mut [u8],
+        flags: libc::c_int,
+    ) -> io::Result<(usize, Option<SystemTime>)> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let mut control = [0u64; 64]; // u64 for cmsg alignment
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = ptr::null_mut();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, flags) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok((n as usize, software_stamp(&msg)))
+    }
+
+    fn software_stamp(msg: &libc::msghdr) -> Option<SystemTime> {
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+            while !cmsg.is_null() {
+                let hdr = &*cmsg;
+                if hdr.cmsg_level == libc::SOL_SOCKET && hdr.cmsg_type == libc::SCM_TIMESTAMPING {
+                    // struct scm_timestamping { struct timespec ts[3]; } – ts[0] is software
+                    let ts = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::timespec);
+                    if ts.tv_sec == 0 && ts.tv_nsec == 0 {
+                        return None;
+                    }
+                    return Some(
+                        SystemTime::UNIX_EPOCH + Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32),
+                    );
+                }
+                cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+            }
+        }
+        None
+    }
+
+    pub(crate) fn exchange_stamps(sock: &UdpSocket, rx: Option<SystemTime>) -> KernelStamps {
+        KernelStamps {
+            tx: tx_stamp(sock),
+            rx,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::KernelStamps;
+    use std::{io, net::UdpSocket, time::SystemTime};
+
+    pub(crate) fn enable(_sock: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_TIMESTAMPING is Linux-only",
+        ))
+    }
+
+    pub(crate) fn recv(
+        sock: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, Option<SystemTime>)> {
+        sock.recv_from(buf).map(|(n, _)| (n, None))
+    }
+
+    pub(crate) fn exchange_stamps(_sock: &UdpSocket, rx: Option<SystemTime>) -> KernelStamps {
+        KernelStamps { tx: None, rx }
+    }
+
+    pub(crate) fn tx_stamp(_sock: &UdpSocket) -> Option<SystemTime> {
+        None
+    }
+}
+
+pub(crate) use imp::{exchange_stamps, recv, tx_stamp};
+
+/// Try to switch `sock` to kernel timestamping; `false` means user-space only.
+pub(crate) fn try_enable(sock: &UdpSocket) -> bool {
+    imp::enable(sock).is_ok()
+}
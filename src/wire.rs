@@ -0,0 +1,243 @@
+//! Roughtime wire formats: Google "classic" and the IETF draft framing.
+//!
+//! | | classic | IETF draft |
+//! |---|---|---|
+//! | framing | bare message | `ROUGHTIM` + u32 LE length + message |
+//! | nonce | 64 bytes | 32 bytes |
+//! | padding tag | `PAD\xff` | `ZZZZ` |
+//! | `MIDP` / `MINT` / `MAXT` | µs since epoch | s since epoch |
+//! | `RADI` | µs | s |
+//! | Merkle hash | SHA-512 | SHA-512 truncated to 32 bytes |
+
+use crate::rt_to_io;
+use roughenough::{merkle::MerkleTree, sign::Verifier, RtMessage, Tag};
+use std::{convert::TryInto, io};
+
+pub const FRAME_MAGIC: &[u8; 8] = b"ROUGHTIM";
+/// `VER` value we speak on the IETF side (draft 13).
+pub const IETF_VERSION: u32 = 0x8000_000c;
+pub const MIN_REQUEST_LEN: usize = 1024;
+
+pub const DELEGATION_CONTEXT_CLASSIC: &[u8] = b"RoughTime v1 delegation signature--\x00";
+pub const DELEGATION_CONTEXT_IETF: &[u8] = b"RoughTime v1 delegation signature\x00";
+pub const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\x00";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum Version {
+    Classic,
+    Ietf,
+}
+
+impl Version {
+    pub fn nonce_len(self) -> usize {
+        match self {
+            Version::Classic => 64,
+            Version::Ietf => 32,
+        }
+    }
+
+    pub fn merkle(self) -> MerkleTree {
+        match self {
+            Version::Classic => MerkleTree::new_sha512_google(),
+            Version::Ietf => MerkleTree::new_sha512_ietf(),
+        }
+    }
+
+    pub fn delegation_context(self) -> &'static [u8] {
+        match self {
+            Version::Classic => DELEGATION_CONTEXT_CLASSIC,
+            Version::Ietf => DELEGATION_CONTEXT_IETF,
+        }
+    }
+
+    /// Time fields on the wire, from µs since epoch.
+    pub fn encode_time(self, us: u64) -> u64 {
+        match self {
+            Version::Classic => us,
+            Version::Ietf => us / 1_000_000,
+        }
+    }
+
+    /// Time fields on the wire, back to µs since epoch.
+    pub fn decode_time(self, v: u64) -> u64 {
+        match self {
+            Version::Classic => v,
+            Version::Ietf => v.saturating_mul(1_000_000),
+        }
+    }
+
+    /// `RADI` on the wire from µs; IETF rounds up to whole seconds.
+    pub fn encode_radius(self, us: u64) -> u32 {
+        match self {
+            Version::Classic => us.min(u32::MAX as u64) as u32,
+            Version::Ietf => us.div_ceil(1_000_000).max(1) as u32,
+        }
+    }
+
+    pub fn decode_radius(self, v: u32) -> u64 {
+        match self {
+            Version::Classic => v as u64,
+            Version::Ietf => v as u64 * 1_000_000,
+        }
+    }
+
+    pub fn frame(self, msg: Vec<u8>) -> Vec<u8> {
+        match self {
+            Version::Classic => msg,
+            Version::Ietf => {
+                let mut out = Vec::with_capacity(12 + msg.len());
+                out.extend_from_slice(FRAME_MAGIC);
+                out.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+                out.extend_from_slice(&msg);
+                out
+            }
+        }
+    }
+}
+
+/// Split a datagram into its version and the bare message bytes.
+pub fn unframe(buf: &[u8]) -> io::Result<(Version, &[u8])> {
+    if buf.len() >= 12 && &buf[..8] == FRAME_MAGIC {
+        let len = u32::from_le_bytes(buf[8..12].try_into().unwrap()) as usize;
+        let body = buf
+            .get(12..12 + len)
+            .ok_or_else(|| bad("frame length exceeds datagram"))?;
+        Ok((Version::Ietf, body))
+    } else {
+        Ok((Version::Classic, buf))
+    }
+}
+
+/// Request for `nonce` (already `version.nonce_len()` bytes), padded so the
+/// encoded datagram is at least `MIN_REQUEST_LEN`.
+pub fn build_request(version: Version, nonce: &[u8]) -> io::Result<Vec<u8>> {
+    let fields = |pad: &[u8]| -> io::Result<RtMessage> {
+        let mut req = RtMessage::with_capacity(3);
+        match version {
+            Version::Classic => {
+                req.add_field(Tag::NONC, nonce).map_err(rt_to_io)?;
+                req.add_field(Tag::PAD, pad).map_err(rt_to_io)?;
+            }
+            Version::Ietf => {
+                req.add_field(Tag::VER, &IETF_VERSION.to_le_bytes())
+                    .map_err(rt_to_io)?;
+                req.add_field(Tag::NONC, nonce).map_err(rt_to_io)?;
+                req.add_field(Tag::ZZZZ, pad).map_err(rt_to_io)?;
+            }
+        }
+        Ok(req)
+    };
+    let unpadded = version
+        .frame(fields(&[])?.encode().map_err(rt_to_io)?)
+        .len();
+    let pad = vec![0u8; MIN_REQUEST_LEN.saturating_sub(unpadded)];
+    Ok(version.frame(fields(&pad)?.encode().map_err(rt_to_io)?))
+}
+
+/// Server side: version and nonce of an incoming request.
+pub fn parse_request(buf: &[u8]) -> io::Result<(Version, Vec<u8>)> {
+    if buf.len() < MIN_REQUEST_LEN {
+        return Err(bad("request below minimum size"));
+    }
+    let (version, body) = unframe(buf)?;
+    let msg = RtMessage::from_bytes(body).map_err(rt_to_io)?;
+    let nonce = msg
+        .get_field(Tag::NONC)
+        .ok_or_else(|| bad("request without NONC"))?;
+    if nonce.len() != version.nonce_len() {
+        return Err(bad("nonce length does not match version"));
+    }
+    Ok((version, nonce.to_vec()))
+}
+
+/// Fields of a signed response, unverified.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub version: Version,
+    pub midpoint_us: u64,
+    pub radius_us: u64,
+    pub root: Vec<u8>,
+    pub index: u32,
+    pub path: Vec<u8>,
+    pub srep: Vec<u8>,
+    pub sig: Vec<u8>,
+    pub cert: Vec<u8>,
+}
+
+/// Client side: pull the fields out of a response datagram.
+pub fn parse_reply(buf: &[u8]) -> io::Result<Reply> {
+    let (version, body) = unframe(buf)?;
+    let resp = RtMessage::from_bytes(body).map_err(rt_to_io)?;
+    let srep_bytes = field(&resp, Tag::SREP)?;
+    let srep = RtMessage::from_bytes(&srep_bytes).map_err(rt_to_io)?;
+    Ok(Reply {
+        version,
+        midpoint_us: version.decode_time(le_u64(&field(&srep, Tag::MIDP)?)?),
+        radius_us: version.decode_radius(le_u32(&field(&srep, Tag::RADI)?)?),
+        root: field(&srep, Tag::ROOT)?,
+        index: le_u32(&field(&resp, Tag::INDX)?)?,
+        path: field(&resp, Tag::PATH)?,
+        srep: srep_bytes,
+        sig: field(&resp, Tag::SIG)?,
+        cert: field(&resp, Tag::CERT)?,
+    })
+}
+
+/// Check that `nonce` is a leaf of the tree whose root the server signed.
+pub fn verify_inclusion(reply: &Reply, nonce: &[u8]) -> io::Result<()> {
+    let root = reply.version.merkle().root_from_paths(reply.index as usize, nonce, &reply.path);
+    if root != reply.root {
+        return Err(bad("Merkle path invalid"));
+    }
+    Ok(())
+}
+
+/// Check the signature chain of `reply` against the server's long-term
+/// `public_key`: CERT/DELE by the long-term key, SREP by the delegated
+/// key, and `MIDP` inside the delegation window.
+pub fn verify_reply(reply: &Reply, public_key: &[u8]) -> io::Result<()> {
+    let cert = RtMessage::from_bytes(&reply.cert).map_err(rt_to_io)?;
+    let dele_bytes = field(&cert, Tag::DELE)?;
+
+    let mut v = Verifier::new(public_key);
+    v.update(reply.version.delegation_context());
+    v.update(&dele_bytes);
+    if !v.verify(&field(&cert, Tag::SIG)?) {
+        return Err(bad("delegation signature invalid"));
+    }
+
+    let dele = RtMessage::from_bytes(&dele_bytes).map_err(rt_to_io)?;
+    let mut v = Verifier::new(&field(&dele, Tag::PUBK)?);
+    v.update(RESPONSE_CONTEXT);
+    v.update(&reply.srep);
+    if !v.verify(&reply.sig) {
+        return Err(bad("response signature invalid"));
+    }
+
+    let mint = reply.version.decode_time(le_u64(&field(&dele, Tag::MINT)?)?);
+    let maxt = reply.version.decode_time(le_u64(&field(&dele, Tag::MAXT)?)?);
+    if reply.midpoint_us < mint || reply.midpoint_us > maxt {
+        return Err(bad("midpoint outside delegation window"));
+    }
+    Ok(())
+}
+
+fn field(msg: &RtMessage, tag: Tag) -> io::Result<Vec<u8>> {
+    msg.get_field(tag)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| bad(&format!("message without {tag:?}")))
+}
+
+fn le_u32(b: &[u8]) -> io::Result<u32> {
+    let b = b.get(..4).ok_or_else(|| bad("short u32 field"))?;
+    Ok(u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn le_u64(b: &[u8]) -> io::Result<u64> {
+    let b = b.get(..8).ok_or_else(|| bad("short u64 field"))?;
+    Ok(u64::from_le_bytes(b.try_into().unwrap()))
+}
+
+fn bad(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}